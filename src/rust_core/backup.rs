@@ -0,0 +1,666 @@
+//! Paper backups of symmetric device keys
+//!
+//! A device key and its metadata are packed into a short binary record,
+//! followed by a checksum, and written out as words from the BIP39 English
+//! list (11 bits per word) or as a hex string that fits a QR code's
+//! alphanumeric mode. Restoring checks the checksum, so a mistyped or
+//! swapped word is reported instead of producing a wrong key.
+//!
+//! ```text
+//! length         u8    (length of the body)
+//! body:
+//!   kind         u8    (1 = key backup, 2 = key share; see crate::shares)
+//!   algorithm    u8    (1 = AES-256, 2 = AES-128, 3 = ChaCha20)
+//!   key version  u32
+//!   created      i64   (Unix seconds)
+//!   rotated      i64   (Unix seconds, 0 = never rotated)
+//!   key          16 or 32 bytes, as the algorithm requires
+//!   device id    UTF-8, the rest of the body
+//! checksum       first 4 bytes of SHA-256 over the length and body
+//! ```
+//!
+//! All integers are big endian. Key pairs are not supported; back up their
+//! PKCS#8 private key instead.
+
+use crate::fingerprint::Fingerprint;
+use crate::keystore::{EncryptionKey, KeyMetadata, KeyRole, KeyStore, KeyStoreError};
+use bip39::Language;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Prefix of the QR payload string
+pub const PAYLOAD_PREFIX: &str = "AGSK1:";
+
+/// Record kind of a key backup
+const KEY_RECORD: u8 = 1;
+
+/// Record kind of a key share
+pub(crate) const SHARE_RECORD: u8 = 2;
+
+/// Length of the checksum after the record
+const CHECKSUM_LEN: usize = 4;
+
+/// Record bytes before the key: kind, algorithm, version, timestamps
+const FIXED_LEN: usize = 1 + 1 + 4 + 8 + 8;
+
+/// Bits encoded by each word
+const BITS_PER_WORD: usize = 11;
+
+/// Words per row on a printed sheet
+const SHEET_COLUMNS: usize = 4;
+
+/// Key backup error types
+#[derive(Debug, Error)]
+pub enum BackupError {
+    /// Only symmetric keys can be backed up as words
+    #[error("{0} keys cannot be backed up as a mnemonic")]
+    UnsupportedAlgorithm(String),
+
+    /// Input word is not in the word list
+    #[error("Word {position} ({word:?}) is not a backup word")]
+    UnknownWord {
+        /// 1-based position of the word
+        position: usize,
+        /// Word as given
+        word: String,
+    },
+
+    /// Checksum does not match, usually a mistyped or swapped word
+    #[error("Backup checksum does not match; check the words for typos or swapped order")]
+    ChecksumMismatch,
+
+    /// Record is truncated or malformed
+    #[error("Invalid key backup: {0}")]
+    InvalidFormat(String),
+
+    /// Shares are missing, repeated or from different splits
+    #[error("Cannot combine key shares: {0}")]
+    InvalidShares(String),
+
+    /// Shares combined into a key that does not match the key ID they carry
+    #[error("Key shares do not rebuild the key they belong to; at least one share is wrong or corrupted")]
+    WrongShares,
+
+    /// System random number generator failed
+    #[error("Failed to generate random data")]
+    RandomFailure,
+
+    /// A different key is already stored for the same device and version
+    #[error("A different key is already stored for {device_id} version {version}")]
+    Conflict {
+        /// Device the key belongs to
+        device_id: String,
+        /// Key version
+        version: u32,
+    },
+
+    /// Key store failure while restoring
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+}
+
+/// Where [`restore_key`] put a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restored {
+    /// The key became the device's current key
+    Current,
+    /// The key was stored as an archived version behind a newer key
+    Archived,
+    /// The same key was already stored
+    Unchanged,
+}
+
+/// Backup words for a key, separated by spaces
+pub fn to_mnemonic(key: &EncryptionKey) -> Result<Zeroizing<String>, BackupError> {
+    Ok(record_words(&encode(key)?))
+}
+
+/// Words for a framed record, 11 bits each
+pub(crate) fn record_words(record: &[u8]) -> Zeroizing<String> {
+    let words = Language::English.word_list();
+
+    let mut out = Zeroizing::new(String::new());
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &byte in record.iter() {
+        acc = (acc << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= BITS_PER_WORD {
+            bits -= BITS_PER_WORD;
+            push_word(&mut out, words[((acc >> bits) & 0x7ff) as usize]);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        // Pad the last word with zero bits
+        push_word(
+            &mut out,
+            words[((acc << (BITS_PER_WORD - bits)) & 0x7ff) as usize],
+        );
+    }
+    out
+}
+
+/// QR-ready payload string for a key: [`PAYLOAD_PREFIX`] and uppercase hex
+pub fn to_payload(key: &EncryptionKey) -> Result<Zeroizing<String>, BackupError> {
+    let record = encode(key)?;
+    Ok(Zeroizing::new(format!(
+        "{PAYLOAD_PREFIX}{}",
+        hex::encode_upper(&*record)
+    )))
+}
+
+/// Printable backup sheet with the words, key details and QR payload
+pub fn backup_sheet(key: &EncryptionKey) -> Result<Zeroizing<String>, BackupError> {
+    let mnemonic = to_mnemonic(key)?;
+    let payload = to_payload(key)?;
+    let fingerprint = Fingerprint::of_secret_key(&key.key_material);
+    let metadata = &key.metadata;
+
+    let mut sheet = Zeroizing::new(String::new());
+    // Writing to a String cannot fail
+    let _ = writeln!(sheet, "AirGapSync key backup");
+    let _ = writeln!(sheet, "=====================");
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "Device:             {}", metadata.device_id);
+    let _ = writeln!(sheet, "Algorithm:          {}", metadata.algorithm);
+    let _ = writeln!(sheet, "Key version:        {}", metadata.version);
+    let _ = writeln!(
+        sheet,
+        "Created:            {}",
+        metadata.created_at.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    let _ = writeln!(sheet, "Key ID:             {}", fingerprint.key_id_hex());
+    let _ = writeln!(
+        sheet,
+        "Verification code:  {}",
+        fingerprint.verification_code()
+    );
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "Recovery words:");
+    let words: Vec<&str> = mnemonic.split(' ').collect();
+    for (row, chunk) in words.chunks(SHEET_COLUMNS).enumerate() {
+        let mut line = Zeroizing::new(String::new());
+        for (column, word) in chunk.iter().enumerate() {
+            let number = row * SHEET_COLUMNS + column + 1;
+            let _ = write!(line, "{number:>4}. {word:<10}");
+        }
+        let _ = writeln!(sheet, "{}", line.trim_end());
+    }
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "QR payload:");
+    let _ = writeln!(sheet, "{}", payload.as_str());
+    let _ = writeln!(sheet);
+    let _ = writeln!(
+        sheet,
+        "Restore with `airgapsync keys restore`, entering the words or the QR payload."
+    );
+    let _ = writeln!(
+        sheet,
+        "Anyone holding this sheet can decrypt the device's data; store it accordingly."
+    );
+    Ok(sheet)
+}
+
+/// Recover a key from backup words
+///
+/// Words are matched case-insensitively, and the first four letters of a
+/// word are enough. Position numbers such as `12.` are ignored, so words
+/// can be copied straight from a printed sheet.
+pub fn from_mnemonic(mnemonic: &str) -> Result<EncryptionKey, BackupError> {
+    decode(&words_record(mnemonic)?)
+}
+
+/// Framed record from words written by [`record_words`]
+pub(crate) fn words_record(mnemonic: &str) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    // Sized up front so the key is never left behind by a reallocation
+    let mut record = Zeroizing::new(Vec::with_capacity(u8::MAX as usize + CHECKSUM_LEN + 2));
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let tokens = mnemonic.split_whitespace().filter(|token| {
+        !token
+            .trim_end_matches(['.', ')'])
+            .chars()
+            .all(|c| c.is_ascii_digit())
+    });
+    for (index, token) in tokens.enumerate() {
+        let word = find_word(token).ok_or_else(|| BackupError::UnknownWord {
+            position: index + 1,
+            word: token.to_string(),
+        })?;
+        acc = (acc << BITS_PER_WORD) | u32::from(word);
+        bits += BITS_PER_WORD;
+        while bits >= 8 {
+            bits -= 8;
+            record.push((acc >> bits) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if acc != 0 {
+        return Err(BackupError::ChecksumMismatch);
+    }
+
+    // The zero padding of the last word can add a whole spare byte
+    let len =
+        1 + usize::from(
+            *record
+                .first()
+                .ok_or_else(|| BackupError::InvalidFormat("no backup words given".to_string()))?,
+        ) + CHECKSUM_LEN;
+    if record.len() < len {
+        return Err(BackupError::InvalidFormat("too few words".to_string()));
+    }
+    if record.len() > len + 1 || record[len..].iter().any(|&b| b != 0) {
+        return Err(BackupError::ChecksumMismatch);
+    }
+    record.truncate(len);
+    Ok(record)
+}
+
+/// Recover a key from a QR payload string
+pub fn from_payload(payload: &str) -> Result<EncryptionKey, BackupError> {
+    decode(&payload_record(payload, PAYLOAD_PREFIX)?)
+}
+
+/// Framed record from a payload string starting with `prefix`
+pub(crate) fn payload_record(
+    payload: &str,
+    prefix: &str,
+) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    let hex = payload
+        .trim()
+        .strip_prefix(prefix)
+        .ok_or_else(|| BackupError::InvalidFormat(format!("payload must start with {prefix}")))?;
+    Ok(Zeroizing::new(
+        hex::decode(hex).map_err(|e| BackupError::InvalidFormat(e.to_string()))?,
+    ))
+}
+
+/// First token of `input` that is a payload starting with `prefix`
+pub(crate) fn find_payload<'a>(input: &'a str, prefix: &str) -> Option<&'a str> {
+    input
+        .split_whitespace()
+        .find(|token| token.starts_with(prefix))
+}
+
+/// Recover a key from either backup words or a QR payload string
+///
+/// Input holding a payload anywhere, such as a whole backup sheet, is
+/// restored from the payload.
+pub fn parse(input: &str) -> Result<EncryptionKey, BackupError> {
+    match find_payload(input, PAYLOAD_PREFIX) {
+        Some(payload) => from_payload(payload),
+        None => from_mnemonic(input),
+    }
+}
+
+/// Store a restored key
+///
+/// The key becomes the device's current key unless a newer version is
+/// stored, in which case it is kept as an archived version so files
+/// written before the rotation can be opened again.
+pub fn restore_key(store: &dyn KeyStore, key: &EncryptionKey) -> Result<Restored, BackupError> {
+    let device_id = &key.metadata.device_id;
+    let version = key.metadata.version;
+    match store.get_key_version(device_id, version) {
+        Ok(existing) if existing.key_material == key.key_material => {
+            return Ok(Restored::Unchanged)
+        }
+        Ok(_) => {
+            return Err(BackupError::Conflict {
+                device_id: device_id.clone(),
+                version,
+            })
+        }
+        Err(KeyStoreError::KeyNotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    match store.get_key(device_id) {
+        Ok(current) if current.metadata.version > version => {
+            store.archive_key(key)?;
+            Ok(Restored::Archived)
+        }
+        Ok(current) => {
+            store.archive_key(&current)?;
+            store.store_key(device_id, key)?;
+            Ok(Restored::Current)
+        }
+        Err(KeyStoreError::KeyNotFound) => {
+            store.store_key(device_id, key)?;
+            Ok(Restored::Current)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Framed record for a key
+fn encode(key: &EncryptionKey) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    seal(&key_body(key)?)
+}
+
+/// Key from a framed record produced by `encode`
+fn decode(record: &[u8]) -> Result<EncryptionKey, BackupError> {
+    key_from_body(open(record)?)
+}
+
+/// Record body holding a key and its metadata
+pub(crate) fn key_body(key: &EncryptionKey) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    let metadata = &key.metadata;
+    let (algorithm, key_len) = algorithm_id(&metadata.algorithm)
+        .ok_or_else(|| BackupError::UnsupportedAlgorithm(metadata.algorithm.clone()))?;
+    if key.key_material.len() != key_len {
+        return Err(BackupError::InvalidFormat(format!(
+            "{} key must be {key_len} bytes",
+            metadata.algorithm
+        )));
+    }
+    if metadata.device_id.is_empty() {
+        return Err(BackupError::InvalidFormat("empty device ID".to_string()));
+    }
+
+    let mut body = Zeroizing::new(Vec::with_capacity(
+        FIXED_LEN + key_len + metadata.device_id.len(),
+    ));
+    body.push(KEY_RECORD);
+    body.push(algorithm);
+    body.extend_from_slice(&metadata.version.to_be_bytes());
+    body.extend_from_slice(&metadata.created_at.timestamp().to_be_bytes());
+    let rotated = metadata.rotated_at.map_or(0, |at| at.timestamp());
+    body.extend_from_slice(&rotated.to_be_bytes());
+    body.extend_from_slice(&key.key_material);
+    body.extend_from_slice(metadata.device_id.as_bytes());
+    Ok(body)
+}
+
+/// Key and metadata from a body produced by [`key_body`]
+pub(crate) fn key_from_body(body: &[u8]) -> Result<EncryptionKey, BackupError> {
+    match body.first() {
+        Some(&KEY_RECORD) => {}
+        Some(&SHARE_RECORD) => {
+            return Err(BackupError::InvalidFormat(
+                "this is a key share; combine it with the other shares".to_string(),
+            ))
+        }
+        _ => {
+            return Err(BackupError::InvalidFormat(
+                "unknown record kind".to_string(),
+            ))
+        }
+    }
+    if body.len() < FIXED_LEN {
+        return Err(BackupError::InvalidFormat("truncated backup".to_string()));
+    }
+
+    let (algorithm, key_len) = algorithm_from_id(body[1])?;
+    let version = u32::from_be_bytes(body[2..6].try_into().expect("4 bytes"));
+    let created_at = timestamp(&body[6..14])?
+        .ok_or_else(|| BackupError::InvalidFormat("missing creation time".to_string()))?;
+    let rotated_at = timestamp(&body[14..22])?;
+
+    let rest = &body[FIXED_LEN..];
+    if rest.len() <= key_len {
+        return Err(BackupError::InvalidFormat("truncated backup".to_string()));
+    }
+    let (key_material, device_id) = rest.split_at(key_len);
+    let device_id = String::from_utf8(device_id.to_vec())
+        .map_err(|_| BackupError::InvalidFormat("device ID is not UTF-8".to_string()))?;
+
+    Ok(EncryptionKey {
+        key_material: key_material.to_vec(),
+        metadata: KeyMetadata {
+            algorithm: algorithm.to_string(),
+            created_at,
+            rotated_at,
+            version,
+            device_id,
+            role: KeyRole::Encryption,
+        },
+    })
+}
+
+/// Frame a record body with its length and checksum
+pub(crate) fn seal(body: &[u8]) -> Result<Zeroizing<Vec<u8>>, BackupError> {
+    if body.is_empty() || body.len() > u8::MAX as usize {
+        return Err(BackupError::InvalidFormat(
+            "record too long; use a shorter device ID".to_string(),
+        ));
+    }
+    let mut record = Zeroizing::new(Vec::with_capacity(1 + body.len() + CHECKSUM_LEN));
+    record.push(body.len() as u8);
+    record.extend_from_slice(body);
+    let checksum = Sha256::digest(&record[..]);
+    record.extend_from_slice(&checksum[..CHECKSUM_LEN]);
+    Ok(record)
+}
+
+/// Body of a framed record, after checking its length and checksum
+pub(crate) fn open(record: &[u8]) -> Result<&[u8], BackupError> {
+    if record.len() < 1 + CHECKSUM_LEN {
+        return Err(BackupError::InvalidFormat("truncated record".to_string()));
+    }
+    let (framed, checksum) = record.split_at(record.len() - CHECKSUM_LEN);
+    if Sha256::digest(framed)[..CHECKSUM_LEN] != *checksum {
+        return Err(BackupError::ChecksumMismatch);
+    }
+    if usize::from(framed[0]) != framed.len() - 1 {
+        return Err(BackupError::InvalidFormat("truncated record".to_string()));
+    }
+    Ok(&framed[1..])
+}
+
+/// Timestamp from 8 big-endian bytes, with 0 meaning none
+fn timestamp(bytes: &[u8]) -> Result<Option<DateTime<Utc>>, BackupError> {
+    match i64::from_be_bytes(bytes.try_into().expect("8 bytes")) {
+        0 => Ok(None),
+        seconds => DateTime::from_timestamp(seconds, 0)
+            .map(Some)
+            .ok_or_else(|| BackupError::InvalidFormat("invalid timestamp".to_string())),
+    }
+}
+
+/// Record ID and key length of a symmetric algorithm
+fn algorithm_id(algorithm: &str) -> Option<(u8, usize)> {
+    match algorithm {
+        "AES-256" => Some((1, 32)),
+        "AES-128" => Some((2, 16)),
+        "ChaCha20" => Some((3, 32)),
+        _ => None,
+    }
+}
+
+fn algorithm_from_id(id: u8) -> Result<(&'static str, usize), BackupError> {
+    match id {
+        1 => Ok(("AES-256", 32)),
+        2 => Ok(("AES-128", 16)),
+        3 => Ok(("ChaCha20", 32)),
+        other => Err(BackupError::InvalidFormat(format!(
+            "unknown algorithm {other}"
+        ))),
+    }
+}
+
+/// Index of a word, also accepting its unique first four letters
+fn find_word(token: &str) -> Option<u16> {
+    let word = token.to_ascii_lowercase();
+    let language = Language::English;
+    if let Some(index) = language.find_word(&word) {
+        return Some(index);
+    }
+    match language.words_by_prefix(word.get(..4)?) {
+        [only] if only.starts_with(&word) => language.find_word(only),
+        _ => None,
+    }
+}
+
+fn push_word(out: &mut String, word: &str) {
+    if !out.is_empty() {
+        out.push(' ');
+    }
+    out.push_str(word);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::{generate_key, rotate_key, MemoryKeyStore};
+
+    fn fixed_key() -> EncryptionKey {
+        let mut key = generate_key("AES-256", "USB001").unwrap();
+        key.key_material = vec![0x42; 32];
+        key.metadata.version = 3;
+        key.metadata.created_at = DateTime::from_timestamp(1_704_067_200, 0).unwrap();
+        key
+    }
+
+    #[test]
+    fn test_known_encoding() {
+        // Record, checksum and words computed independently in Python from
+        // the BIP39 English list
+        let key = fixed_key();
+        assert_eq!(
+            to_payload(&key).unwrap().as_str(),
+            "AGSK1:3C01010000000300000000659200800000000000000000424242424242424242424242\
+             4242424242424242424242424242424242424242555342303031378FF0DF"
+        );
+        let mnemonic = to_mnemonic(&key).unwrap();
+        assert_eq!(mnemonic.split(' ').count(), 48);
+        assert!(mnemonic.starts_with("despair amount divorce abandon ability length"));
+        assert!(mnemonic.ends_with("fetch old metal gather beach side sell theme"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for algorithm in ["AES-256", "AES-128", "ChaCha20"] {
+            let store = MemoryKeyStore::new();
+            store
+                .store_key("USB001", &generate_key(algorithm, "USB001").unwrap())
+                .unwrap();
+            let key = rotate_key(&store, "USB001").unwrap();
+
+            for restored in [
+                from_mnemonic(&to_mnemonic(&key).unwrap()).unwrap(),
+                parse(&to_mnemonic(&key).unwrap()).unwrap(),
+                parse(&to_payload(&key).unwrap()).unwrap(),
+            ] {
+                assert_eq!(restored.key_material, key.key_material);
+                assert_eq!(restored.metadata.algorithm, algorithm);
+                assert_eq!(restored.metadata.device_id, "USB001");
+                assert_eq!(restored.metadata.version, 2);
+                assert_eq!(restored.metadata.role, KeyRole::Encryption);
+                assert_eq!(
+                    restored.metadata.created_at.timestamp(),
+                    key.metadata.created_at.timestamp()
+                );
+                assert_eq!(
+                    restored.metadata.rotated_at.map(|at| at.timestamp()),
+                    key.metadata.rotated_at.map(|at| at.timestamp())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_lenient_input() {
+        let key = fixed_key();
+        let mnemonic = to_mnemonic(&key).unwrap();
+
+        // Numbered, upper case and shortened to four letters, as copied
+        // from a sheet
+        let copied: Vec<String> = mnemonic
+            .split(' ')
+            .enumerate()
+            .map(|(i, word)| format!("{}. {}", i + 1, word[..word.len().min(4)].to_uppercase()))
+            .collect();
+        let restored = from_mnemonic(&copied.join("\n")).unwrap();
+        assert_eq!(restored.key_material, key.key_material);
+
+        let sheet = backup_sheet(&key).unwrap();
+        assert!(sheet.contains("  48. theme\n"));
+        assert_eq!(parse(&sheet).unwrap().key_material, key.key_material);
+        assert!(sheet.contains(to_payload(&key).unwrap().as_str()));
+        assert!(sheet.contains(&Fingerprint::of_secret_key(&key.key_material).verification_code()));
+    }
+
+    #[test]
+    fn test_transcription_errors() {
+        let mnemonic = to_mnemonic(&fixed_key()).unwrap();
+        let mut words: Vec<&str> = mnemonic.split(' ').collect();
+
+        let mut typo = words.clone();
+        typo[5] = "lenght";
+        assert!(matches!(
+            from_mnemonic(&typo.join(" ")),
+            Err(BackupError::UnknownWord { position: 6, .. })
+        ));
+
+        let mut wrong = words.clone();
+        wrong[20] = "zoo";
+        assert!(matches!(
+            from_mnemonic(&wrong.join(" ")),
+            Err(BackupError::ChecksumMismatch)
+        ));
+
+        words.swap(1, 2);
+        assert!(matches!(
+            from_mnemonic(&words.join(" ")),
+            Err(BackupError::ChecksumMismatch)
+        ));
+        words.swap(1, 2);
+
+        words.pop();
+        assert!(from_mnemonic(&words.join(" ")).is_err());
+        assert!(from_mnemonic("").is_err());
+
+        let payload = to_payload(&fixed_key()).unwrap();
+        let tampered = payload.replace("4242", "4243");
+        assert!(matches!(
+            from_payload(&tampered),
+            Err(BackupError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_key_pairs() {
+        let key = generate_key("Ed25519", "signer").unwrap();
+        assert!(matches!(
+            to_mnemonic(&key),
+            Err(BackupError::UnsupportedAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_key() {
+        let store = MemoryKeyStore::new();
+        let original = generate_key("AES-256", "USB001").unwrap();
+        let backup = to_mnemonic(&original).unwrap();
+
+        let key = from_mnemonic(&backup).unwrap();
+        assert_eq!(restore_key(&store, &key).unwrap(), Restored::Current);
+        assert_eq!(restore_key(&store, &key).unwrap(), Restored::Unchanged);
+
+        // An old backup restored behind a newer key is archived
+        let rotated = rotate_key(&store, "USB001").unwrap();
+        store.retire_key_version("USB001", 1).unwrap();
+        assert_eq!(restore_key(&store, &key).unwrap(), Restored::Archived);
+        assert_eq!(
+            store.get_key("USB001").unwrap().key_material,
+            rotated.key_material
+        );
+        assert_eq!(
+            store.get_key_version("USB001", 1).unwrap().key_material,
+            original.key_material
+        );
+
+        // A backup of another key with the same version is refused
+        let mut other = key.clone();
+        other.key_material = vec![7; 32];
+        assert!(matches!(
+            restore_key(&store, &other),
+            Err(BackupError::Conflict { version: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,241 @@
+//! Compression before encryption
+//!
+//! Object payloads (whole files, or chunks with deduplication on) are
+//! compressed with zstd before they are encrypted. `policy.compression_level`
+//! 0 turns compression off; levels 1 to 9 are spread over zstd's levels 1 to
+//! 19 (see [`Compression::from_policy_level`]).
+//!
+//! Compressing data that is already compressed (archives, media, encrypted
+//! files) costs time and saves nothing, so each object is first tried on a
+//! sample of up to [`TRIAL_LEN`] bytes and stored uncompressed unless the
+//! sample shrinks by at least 5%.
+//!
+//! The codec and level used are recorded in each object's
+//! [`FileHeader`](crate::header::FileHeader), so objects are read back
+//! correctly whatever the configuration says at restore time.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Bytes of an object compressed to decide whether compressing pays off
+pub const TRIAL_LEN: usize = 64 * 1024;
+
+/// Highest zstd level used, for `compression_level` 9
+pub const MAX_ZSTD_LEVEL: u8 = 19;
+
+/// How an object's payload is compressed before encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Stored as is
+    #[default]
+    None,
+    /// Compressed with zstd
+    Zstd {
+        /// zstd compression level (1 to 19)
+        level: u8,
+    },
+}
+
+impl Compression {
+    /// Compression for a `policy.compression_level` from 0 to 9
+    ///
+    /// 0 means no compression. Levels 1 to 9 map linearly onto zstd levels
+    /// 1 to 19; higher policy levels are treated as 9.
+    pub fn from_policy_level(level: u8) -> Self {
+        match level {
+            0 => Compression::None,
+            level => {
+                let level = level.min(9);
+                Compression::Zstd {
+                    level: 1 + (level - 1) * (MAX_ZSTD_LEVEL - 1) / 8,
+                }
+            }
+        }
+    }
+
+    /// Compression to use for an object starting with `sample`
+    ///
+    /// Compresses the first [`TRIAL_LEN`] bytes of `sample` and returns
+    /// `self` if that saves at least 5%, otherwise [`Compression::None`].
+    pub fn for_sample(self, sample: &[u8]) -> Self {
+        let Compression::Zstd { level } = self else {
+            return self;
+        };
+        let sample = &sample[..sample.len().min(TRIAL_LEN)];
+        if sample.is_empty() {
+            return Compression::None;
+        }
+
+        match zstd::bulk::compress(sample, level.into()) {
+            Ok(compressed) if compressed.len() * 20 <= sample.len() * 19 => self,
+            _ => Compression::None,
+        }
+    }
+
+    /// Compression to use for a stream, decided on its first bytes
+    ///
+    /// Reads up to [`TRIAL_LEN`] bytes from `reader` for
+    /// [`Compression::for_sample`] and returns a reader over the whole
+    /// stream, those bytes included.
+    pub fn for_stream<R: Read>(self, mut reader: R) -> io::Result<(Self, impl Read)> {
+        let mut sample = Vec::new();
+        if self != Compression::None {
+            (&mut reader)
+                .take(TRIAL_LEN as u64)
+                .read_to_end(&mut sample)?;
+        }
+        Ok((
+            self.for_sample(&sample),
+            io::Cursor::new(sample).chain(reader),
+        ))
+    }
+
+    /// Reader yielding the compressed form of `reader`
+    pub fn compress<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Zstd { level } => {
+                Box::new(zstd::stream::read::Encoder::new(reader, level.into())?)
+            }
+        })
+    }
+
+    /// Writer decompressing everything written to it into `writer`
+    ///
+    /// The returned writer must be flushed once all data is written.
+    pub fn decompress<'a, W: Write + 'a>(self, writer: W) -> io::Result<Box<dyn Write + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(writer),
+            Compression::Zstd { .. } => Box::new(zstd::stream::write::Decoder::new(writer)?),
+        })
+    }
+
+    /// Reader decompressing `reader`
+    pub fn decompress_reader<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Zstd { .. } => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Zstd { level } => write!(f, "zstd level {level}"),
+        }
+    }
+}
+
+/// Reader or writer that counts the bytes passing through it
+pub(crate) struct Counted<T> {
+    inner: T,
+    count: u64,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Bytes read or written so far
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_levels() {
+        assert_eq!(Compression::from_policy_level(0), Compression::None);
+        let levels: Vec<u8> = (1..=9)
+            .map(|level| match Compression::from_policy_level(level) {
+                Compression::Zstd { level } => level,
+                Compression::None => 0,
+            })
+            .collect();
+        assert_eq!(levels, [1, 3, 5, 7, 10, 12, 14, 16, 19]);
+        assert_eq!(
+            Compression::from_policy_level(12),
+            Compression::from_policy_level(9)
+        );
+    }
+
+    #[test]
+    fn test_trial_skips_incompressible_data() {
+        let zstd = Compression::from_policy_level(3);
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(100);
+        assert_eq!(zstd.for_sample(&text), zstd);
+
+        // Data that looks random, like compressed or encrypted files, is
+        // stored as is
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let random: Vec<u8> = (0..16 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert_eq!(zstd.for_sample(&random), Compression::None);
+        assert_eq!(zstd.for_sample(b""), Compression::None);
+        assert_eq!(Compression::None.for_sample(&text), Compression::None);
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let text = b"compressible ".repeat(20_000);
+        let (compression, reader) = Compression::from_policy_level(9)
+            .for_stream(&text[..])
+            .unwrap();
+        assert_eq!(compression, Compression::Zstd { level: 19 });
+
+        let mut compressed = Vec::new();
+        compression
+            .compress(reader)
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert!(compressed.len() < text.len() / 10);
+
+        let mut out = Vec::new();
+        let mut writer = compression.decompress(&mut out).unwrap();
+        writer.write_all(&compressed).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(out, text);
+
+        let mut out = Vec::new();
+        compression
+            .decompress_reader(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, text);
+    }
+}
@@ -3,10 +3,13 @@
 //! This module defines the TOML configuration schema and provides
 //! serialization/deserialization support with validation.
 
+use crate::crypto::KdfParams;
+use crate::envelope::Recipient;
+use crate::keys::AsymmetricKey;
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 /// Configuration error types
@@ -27,6 +30,10 @@ pub enum ConfigError {
     /// Failed to serialize configuration to TOML
     #[error("Failed to serialize configuration: {0}")]
     SerializationError(#[from] toml::ser::Error),
+
+    /// Failed to update a configuration file in place
+    #[error("Failed to update configuration file: {0}")]
+    UpdateError(#[from] toml_edit::TomlError),
 }
 
 /// Main configuration structure for AirGapSync
@@ -113,6 +120,90 @@ pub struct DeviceConfig {
     /// Device-specific encryption settings
     #[serde(default)]
     pub encryption: EncryptionConfig,
+
+    /// Public keys that can also decrypt this device's data
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<RecipientConfig>,
+}
+
+impl DeviceConfig {
+    /// Read and parse every recipient's public keys
+    ///
+    /// Recipients must be hybrid (have an ML-KEM key) exactly when the
+    /// device sets `encryption.post_quantum`.
+    pub fn load_recipients(&self) -> Result<Vec<Recipient>, ConfigError> {
+        self.recipients
+            .iter()
+            .map(|recipient| {
+                recipient
+                    .check_post_quantum(self.encryption.post_quantum)
+                    .map_err(|e| {
+                        ConfigError::ValidationError(format!("recipient {}: {e}", recipient.name))
+                    })?;
+                recipient.load()
+            })
+            .collect()
+    }
+}
+
+/// Public-key recipient of a device's data
+///
+/// Every file synced to the device has its data key wrapped to each
+/// recipient, so the holder of the matching private key can decrypt it
+/// without the device key.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RecipientConfig {
+    /// Who holds the private key
+    pub name: String,
+
+    /// PEM file with the recipient's public key (RSA, P-256, P-384 or X25519)
+    pub public_key: PathBuf,
+
+    /// PEM file with the recipient's ML-KEM-768 public key, making this a
+    /// post-quantum hybrid recipient (`public_key` must then be X25519 or
+    /// P-256, and the device must set `encryption.post_quantum`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ml_kem_public_key: Option<PathBuf>,
+}
+
+impl RecipientConfig {
+    /// Read and parse the recipient's public key
+    pub fn load(&self) -> Result<Recipient, ConfigError> {
+        self.read_public_key()
+            .map_err(|e| ConfigError::ValidationError(format!("recipient {}: {e}", self.name)))
+    }
+
+    fn read_public_key(&self) -> Result<Recipient, String> {
+        let recipient = Recipient::from_public_key_pem(&read_pem(&self.public_key)?)
+            .map_err(|e| e.to_string())?;
+        match &self.ml_kem_public_key {
+            None => Ok(recipient),
+            Some(path) => {
+                let kem_key = AsymmetricKey::from_public_pem(&read_pem(path)?)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                recipient.with_ml_kem(&kem_key).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Check the recipient against the device's `post_quantum` setting
+    fn check_post_quantum(&self, post_quantum: bool) -> Result<(), String> {
+        match (post_quantum, &self.ml_kem_public_key) {
+            (true, None) => {
+                Err("needs an ml_kem_public_key, as encryption.post_quantum is set".to_string())
+            }
+            (false, Some(_)) => {
+                Err("has an ml_kem_public_key, but encryption.post_quantum is not set".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Read a PEM file, expanding `~`
+fn read_pem(path: &Path) -> Result<String, String> {
+    let path = PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).as_ref());
+    std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {e}", path.display()))
 }
 
 /// Encryption configuration for a device
@@ -129,6 +220,84 @@ pub struct EncryptionConfig {
     /// PBKDF2 iterations (if using PBKDF2)
     #[serde(default = "default_pbkdf2_iterations")]
     pub iterations: u32,
+
+    /// Argon2 memory cost in KiB (if using Argon2)
+    #[serde(default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+
+    /// Argon2 time cost, i.e. passes over memory (if using Argon2)
+    #[serde(default = "default_argon2_time_cost")]
+    pub argon2_time_cost: u32,
+
+    /// Argon2 degree of parallelism (if using Argon2)
+    #[serde(default = "default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+
+    /// Wrap data keys to recipients with hybrid ML-KEM-768 + ECDH, so they
+    /// stay protected against a future quantum computer (device recipients
+    /// only; each needs an `ml_kem_public_key`)
+    #[serde(default = "default_false")]
+    pub post_quantum: bool,
+}
+
+/// Largest accepted chunk size in MB
+pub const MAX_CHUNK_SIZE_MB: u32 = 1024;
+
+impl PolicyConfig {
+    /// Chunk size in bytes, used as the encryption segment size
+    pub fn chunk_size_bytes(&self) -> usize {
+        self.chunk_size_mb as usize * 1024 * 1024
+    }
+}
+
+/// Minimum accepted PBKDF2 iteration count
+pub const MIN_PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Minimum accepted Argon2 memory cost in KiB (19 MiB, OWASP baseline)
+pub const MIN_ARGON2_MEMORY_KIB: u32 = 19_456;
+
+/// Minimum accepted Argon2 time cost
+pub const MIN_ARGON2_TIME_COST: u32 = 2;
+
+/// Maximum accepted PBKDF2 iteration count
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Maximum accepted Argon2 memory cost in KiB (2 GiB)
+pub const MAX_ARGON2_MEMORY_KIB: u32 = 2 * 1024 * 1024;
+
+/// Maximum accepted Argon2 time cost
+pub const MAX_ARGON2_TIME_COST: u32 = 64;
+
+/// Maximum accepted Argon2 parallelism
+pub const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+impl EncryptionConfig {
+    /// Check that key derivation parameters meet minimum strength and stay
+    /// within [`KdfParams::check_limits`]
+    pub fn validate(&self) -> Result<(), String> {
+        match self.key_derivation {
+            KeyDerivation::Pbkdf2 => {
+                if self.iterations < MIN_PBKDF2_ITERATIONS {
+                    return Err(format!(
+                        "PBKDF2 iterations must be at least {MIN_PBKDF2_ITERATIONS}"
+                    ));
+                }
+            }
+            KeyDerivation::Argon2 => {
+                if self.argon2_memory_kib < MIN_ARGON2_MEMORY_KIB {
+                    return Err(format!(
+                        "Argon2 memory cost must be at least {MIN_ARGON2_MEMORY_KIB} KiB"
+                    ));
+                }
+                if self.argon2_time_cost < MIN_ARGON2_TIME_COST {
+                    return Err(format!(
+                        "Argon2 time cost must be at least {MIN_ARGON2_TIME_COST}"
+                    ));
+                }
+            }
+        }
+        KdfParams::from_config(self).check_limits()
+    }
 }
 
 /// Supported encryption algorithms
@@ -154,11 +323,11 @@ pub enum KeyDerivation {
 /// Retention and cleanup policies
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct PolicyConfig {
-    /// Number of snapshots to retain
+    /// Number of most recent snapshots always kept
     #[serde(default = "default_retain_snapshots")]
     pub retain_snapshots: u32,
 
-    /// Keep snapshots for N days
+    /// Keep every snapshot younger than N days
     #[serde(default = "default_retain_days")]
     pub retain_days: u32,
 
@@ -205,6 +374,42 @@ pub struct SecurityConfig {
     /// Audit log retention in days
     #[serde(default = "default_audit_retention_days")]
     pub audit_retention_days: u32,
+
+    /// Key storage backend
+    #[serde(default = "default_key_store_backend")]
+    pub key_store: KeyStoreBackend,
+
+    /// Vault file location (`~/.airgapsync/keys.vault` if unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_store_path: Option<PathBuf>,
+
+    /// Cipher and passphrase key derivation for new key vaults
+    #[serde(default)]
+    pub vault: EncryptionConfig,
+
+    /// Key store ID of a signing key pair that signs snapshot manifests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+/// Key storage backends
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStoreBackend {
+    /// macOS login Keychain
+    Keychain,
+    /// Single passphrase-protected vault file
+    Vault,
+}
+
+impl KeyStoreBackend {
+    /// Get the backend name as used in configuration files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStoreBackend::Keychain => "keychain",
+            KeyStoreBackend::Vault => "vault",
+        }
+    }
 }
 
 /// Audit logging levels
@@ -321,6 +526,21 @@ fn default_pbkdf2_iterations() -> u32 {
     100_000
 }
 
+/// Default Argon2 memory cost in KiB (64 MiB, RFC 9106 second recommendation)
+fn default_argon2_memory_kib() -> u32 {
+    65_536
+}
+
+/// Default Argon2 time cost (3 passes)
+fn default_argon2_time_cost() -> u32 {
+    3
+}
+
+/// Default Argon2 parallelism (4 lanes)
+fn default_argon2_parallelism() -> u32 {
+    4
+}
+
 /// Default number of snapshots to retain (7)
 fn default_retain_snapshots() -> u32 {
     7
@@ -371,6 +591,15 @@ fn default_audit_retention_days() -> u32 {
     365
 }
 
+/// Default key store backend (Keychain on macOS, a vault elsewhere)
+fn default_key_store_backend() -> KeyStoreBackend {
+    if cfg!(target_os = "macos") {
+        KeyStoreBackend::Keychain
+    } else {
+        KeyStoreBackend::Vault
+    }
+}
+
 /// Default snapshot format version (1)
 fn default_snapshot_version() -> u32 {
     1
@@ -389,6 +618,10 @@ impl Default for EncryptionConfig {
             algorithm: default_encryption_algorithm(),
             key_derivation: default_key_derivation(),
             iterations: default_pbkdf2_iterations(),
+            argon2_memory_kib: default_argon2_memory_kib(),
+            argon2_time_cost: default_argon2_time_cost(),
+            argon2_parallelism: default_argon2_parallelism(),
+            post_quantum: false,
         }
     }
 }
@@ -415,6 +648,10 @@ impl Default for SecurityConfig {
             require_authentication: true,
             audit_level: default_audit_level(),
             audit_retention_days: default_audit_retention_days(),
+            key_store: default_key_store_backend(),
+            key_store_path: None,
+            vault: EncryptionConfig::default(),
+            signing_key: None,
         }
     }
 }
@@ -462,6 +699,21 @@ impl Config {
         Ok(())
     }
 
+    /// Record the time of the last successful sync in a configuration file
+    ///
+    /// Only `advanced.last_sync` is rewritten; comments and formatting in the
+    /// rest of the file are kept. The new contents are written to a temporary
+    /// file next to `path` and renamed over it, so an interrupted write never
+    /// leaves a truncated configuration.
+    pub fn record_last_sync(path: &Path, time: DateTime<Utc>) -> Result<(), ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut document: toml_edit::DocumentMut = contents.parse()?;
+        let last_sync = time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        document["advanced"]["last_sync"] = toml_edit::value(last_sync);
+        replace_file(path, document.to_string().as_bytes())?;
+        Ok(())
+    }
+
     /// Validate configuration
     pub fn validate(&self) -> Result<(), ConfigError> {
         // Ensure at least one device is configured
@@ -479,6 +731,10 @@ impl Config {
             )));
         }
 
+        // Exclude patterns must parse
+        crate::walker::SourceWalker::new(&self.source)
+            .map_err(|e| ConfigError::ValidationError(e.to_string()))?;
+
         // Validate device IDs are unique
         let mut device_ids = std::collections::HashSet::new();
         for device in &self.device {
@@ -490,6 +746,49 @@ impl Config {
             }
         }
 
+        // Recipients must name a readable, supported public key, with an
+        // ML-KEM key exactly when the device opts into post-quantum wrapping
+        for device in &self.device {
+            let mut names = std::collections::HashSet::new();
+            for recipient in &device.recipients {
+                if !names.insert(&recipient.name) {
+                    return Err(ConfigError::ValidationError(format!(
+                        "device {}: duplicate recipient {}",
+                        device.id, recipient.name
+                    )));
+                }
+                recipient
+                    .check_post_quantum(device.encryption.post_quantum)
+                    .and_then(|_| recipient.read_public_key())
+                    .map_err(|e| {
+                        ConfigError::ValidationError(format!(
+                            "device {}: recipient {}: {e}",
+                            device.id, recipient.name
+                        ))
+                    })?;
+            }
+        }
+
+        // Reject weak key derivation parameters
+        for device in &self.device {
+            device
+                .encryption
+                .validate()
+                .map_err(|e| ConfigError::ValidationError(format!("device {}: {e}", device.id)))?;
+        }
+        self.security
+            .vault
+            .validate()
+            .map_err(|e| ConfigError::ValidationError(format!("security.vault: {e}")))?;
+
+        if self.advanced.snapshot_version != u32::from(crate::snapshot::FORMAT_VERSION) {
+            return Err(ConfigError::ValidationError(format!(
+                "Unsupported snapshot version {} (supported: {})",
+                self.advanced.snapshot_version,
+                crate::snapshot::FORMAT_VERSION
+            )));
+        }
+
         // Validate compression level
         if self.policy.compression_level > 9 {
             return Err(ConfigError::ValidationError(
@@ -497,12 +796,24 @@ impl Config {
             ));
         }
 
+        // The Keychain backend only exists on macOS
+        if self.security.key_store == KeyStoreBackend::Keychain && !cfg!(target_os = "macos") {
+            return Err(ConfigError::ValidationError(
+                "Keychain key store is only available on macOS".to_string(),
+            ));
+        }
+
         // Validate chunk size
         if self.policy.chunk_size_mb == 0 {
             return Err(ConfigError::ValidationError(
                 "Chunk size must be greater than 0".to_string(),
             ));
         }
+        if self.policy.chunk_size_mb > MAX_CHUNK_SIZE_MB {
+            return Err(ConfigError::ValidationError(format!(
+                "Chunk size must be at most {MAX_CHUNK_SIZE_MB} MB"
+            )));
+        }
 
         Ok(())
     }
@@ -516,6 +827,43 @@ impl Config {
     }
 }
 
+/// Atomically replace `path` with `contents`, keeping its permissions
+///
+/// The temporary file gets a random name and is created exclusively, so an
+/// existing file or symlink in its place is never written through.
+fn replace_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let temp = path.with_file_name(temp_name);
+
+    let permissions = std::fs::metadata(path)?.permissions();
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+    let written = file
+        .set_permissions(permissions)
+        .and_then(|()| file.write_all(contents))
+        .and_then(|()| file.sync_all())
+        .and_then(|()| {
+            drop(file);
+            std::fs::rename(&temp, path)
+        });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -536,6 +884,7 @@ mod tests {
                 name: "Test USB".to_string(),
                 mount_point: PathBuf::from("/Volumes/USB001"),
                 encryption: EncryptionConfig::default(),
+                recipients: vec![],
             }],
             policy: PolicyConfig::default(),
             security: SecurityConfig::default(),
@@ -579,9 +928,192 @@ mod tests {
             name: "Test USB".to_string(),
             mount_point: PathBuf::from("/Volumes/USB001"),
             encryption: EncryptionConfig::default(),
+            recipients: vec![],
         });
 
         // Should still fail with nonexistent source path
         assert!(config.validate().is_err());
+
+        let source = tempfile::tempdir().unwrap();
+        config.source.path = source.path().to_path_buf();
+        assert!(config.validate().is_ok());
+
+        // Exclude patterns must parse
+        config.source.exclude = vec!["[z-a]".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_record_last_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let config = Config {
+            general: GeneralConfig::default(),
+            source: SourceConfig {
+                path: dir.path().to_path_buf(),
+                exclude: vec![],
+                follow_symlinks: false,
+                include_hidden: false,
+            },
+            device: vec![DeviceConfig {
+                id: "USB001".to_string(),
+                name: "Test USB".to_string(),
+                mount_point: PathBuf::from("/mnt/usb"),
+                encryption: EncryptionConfig::default(),
+                recipients: vec![],
+            }],
+            policy: PolicyConfig::default(),
+            security: SecurityConfig::default(),
+            schedule: None,
+            notifications: NotificationConfig::default(),
+            advanced: AdvancedConfig::default(),
+        };
+        let contents = format!("# Keep me\n{}", toml::to_string_pretty(&config).unwrap());
+        std::fs::write(&path, contents).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o640);
+            std::fs::set_permissions(&path, permissions).unwrap();
+        }
+
+        let time = DateTime::parse_from_rfc3339("2026-10-17T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        Config::record_last_sync(&path, time).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# Keep me\n"));
+        let loaded = Config::from_file(&path).unwrap();
+        assert_eq!(loaded.advanced.last_sync, Some(time));
+
+        // Written through a temporary file that keeps the file's mode
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+    }
+
+    #[test]
+    fn test_example_config() {
+        // The shipped example is valid on every platform once its source
+        // directory exists
+        let example = include_str!("../../config.example.toml");
+        let mut config: Config = toml::from_str(example).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        config.source.path = dir.path().to_path_buf();
+        config.validate().unwrap();
+        assert_eq!(config.security.key_store, default_key_store_backend());
+    }
+
+    #[test]
+    fn test_recipient_loading() {
+        use crate::keys::{AsymmetricAlgorithm, AsymmetricKey};
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP256).unwrap();
+        std::fs::write(dir.path().join("officer.pem"), key.public_key_pem()).unwrap();
+        std::fs::write(dir.path().join("junk.pem"), "not a key").unwrap();
+
+        let recipient = RecipientConfig {
+            name: "officer".to_string(),
+            public_key: dir.path().join("officer.pem"),
+            ml_kem_public_key: None,
+        };
+        assert_eq!(recipient.load().unwrap(), Recipient::from_key(&key));
+
+        for file in ["junk.pem", "missing.pem"] {
+            let recipient = RecipientConfig {
+                name: "officer".to_string(),
+                public_key: dir.path().join(file),
+                ml_kem_public_key: None,
+            };
+            assert!(matches!(
+                recipient.load(),
+                Err(ConfigError::ValidationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_post_quantum_recipients() {
+        use crate::keys::{AsymmetricAlgorithm, AsymmetricKey};
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        let kem = AsymmetricKey::generate(AsymmetricAlgorithm::MlKem768).unwrap();
+        std::fs::write(dir.path().join("officer.pem"), key.public_key_pem()).unwrap();
+        std::fs::write(dir.path().join("officer-kem.pem"), kem.public_key_pem()).unwrap();
+
+        let mut device = DeviceConfig {
+            id: "USB001".to_string(),
+            name: "Test USB".to_string(),
+            mount_point: dir.path().to_path_buf(),
+            encryption: EncryptionConfig {
+                post_quantum: true,
+                ..EncryptionConfig::default()
+            },
+            recipients: vec![RecipientConfig {
+                name: "officer".to_string(),
+                public_key: dir.path().join("officer.pem"),
+                ml_kem_public_key: Some(dir.path().join("officer-kem.pem")),
+            }],
+        };
+        let recipients = device.load_recipients().unwrap();
+        assert_eq!(
+            recipients,
+            vec![Recipient::from_key(&key).with_ml_kem(&kem).unwrap()]
+        );
+
+        // Hybrid recipients are opt-in per device
+        device.encryption.post_quantum = false;
+        assert!(device.load_recipients().is_err());
+
+        // and once opted in, classical recipients are refused
+        device.encryption.post_quantum = true;
+        device.recipients[0].ml_kem_public_key = None;
+        assert!(device.load_recipients().is_err());
+
+        // The ML-KEM file must hold an ML-KEM key
+        device.recipients[0].ml_kem_public_key = Some(dir.path().join("officer.pem"));
+        assert!(device.load_recipients().is_err());
+    }
+
+    #[test]
+    fn test_key_derivation_validation() {
+        let mut encryption = EncryptionConfig::default();
+        assert!(encryption.validate().is_ok());
+
+        encryption.iterations = 1_000;
+        assert!(encryption.validate().is_err());
+
+        encryption.key_derivation = KeyDerivation::Argon2;
+        assert!(encryption.validate().is_ok());
+
+        encryption.argon2_memory_kib = 4_096;
+        assert!(encryption.validate().is_err());
+        encryption.argon2_memory_kib = default_argon2_memory_kib();
+
+        encryption.argon2_time_cost = 1;
+        assert!(encryption.validate().is_err());
+        encryption.argon2_time_cost = default_argon2_time_cost();
+
+        encryption.argon2_parallelism = 0;
+        assert!(encryption.validate().is_err());
+        encryption.argon2_parallelism = default_argon2_parallelism();
+
+        // Costs are bounded too
+        encryption.argon2_memory_kib = MAX_ARGON2_MEMORY_KIB + 1;
+        assert!(encryption.validate().is_err());
+        encryption.argon2_memory_kib = default_argon2_memory_kib();
+        encryption.argon2_time_cost = MAX_ARGON2_TIME_COST + 1;
+        assert!(encryption.validate().is_err());
+
+        encryption.key_derivation = KeyDerivation::Pbkdf2;
+        encryption.iterations = MAX_PBKDF2_ITERATIONS + 1;
+        assert!(encryption.validate().is_err());
     }
 }
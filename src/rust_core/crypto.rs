@@ -3,11 +3,16 @@
 //! This module implements encryption, decryption, and key management
 //! using the ring cryptography library.
 
+use crate::config::{
+    EncryptionConfig, KeyDerivation, MAX_ARGON2_MEMORY_KIB, MAX_ARGON2_PARALLELISM,
+    MAX_ARGON2_TIME_COST, MAX_PBKDF2_ITERATIONS,
+};
 use ring::aead::{Aad, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey};
 use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
 use ring::error::Unspecified;
 use ring::pbkdf2;
 use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
 use thiserror::Error;
 use zeroize::Zeroize;
@@ -42,6 +47,26 @@ pub enum CryptoError {
     /// Requested algorithm is not supported
     #[error("Algorithm not supported: {0}")]
     UnsupportedAlgorithm(String),
+
+    /// Encrypted file header is malformed or unsupported
+    #[error("Invalid file header: {0}")]
+    InvalidHeader(String),
+
+    /// Key does not match the one recorded in the file header
+    #[error("Key mismatch: {0}")]
+    KeyMismatch(String),
+
+    /// Public key a data key is wrapped to is malformed or unsupported
+    #[error("Invalid recipient: {0}")]
+    InvalidRecipient(String),
+
+    /// Encrypted stream header or parameters are malformed
+    #[error("Invalid stream: {0}")]
+    InvalidStream(String),
+
+    /// I/O error while streaming data
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 /// Supported encryption algorithms
@@ -79,6 +104,97 @@ impl Algorithm {
     }
 }
 
+impl From<crate::config::EncryptionAlgorithm> for Algorithm {
+    fn from(algorithm: crate::config::EncryptionAlgorithm) -> Self {
+        match algorithm {
+            crate::config::EncryptionAlgorithm::Aes256Gcm => Algorithm::Aes256Gcm,
+            crate::config::EncryptionAlgorithm::ChaCha20Poly1305 => Algorithm::ChaCha20Poly1305,
+        }
+    }
+}
+
+/// Password-based key derivation function and its cost parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum KdfParams {
+    /// PBKDF2-HMAC-SHA256
+    Pbkdf2 {
+        /// Iteration count
+        iterations: u32,
+    },
+    /// Argon2id (RFC 9106)
+    Argon2 {
+        /// Memory cost in KiB
+        memory_kib: u32,
+        /// Number of passes over memory
+        time_cost: u32,
+        /// Degree of parallelism (lanes)
+        parallelism: u32,
+    },
+}
+
+impl KdfParams {
+    /// KDF parameters selected by an encryption configuration
+    pub fn from_config(config: &EncryptionConfig) -> Self {
+        match config.key_derivation {
+            KeyDerivation::Pbkdf2 => KdfParams::Pbkdf2 {
+                iterations: config.iterations,
+            },
+            KeyDerivation::Argon2 => KdfParams::Argon2 {
+                memory_kib: config.argon2_memory_kib,
+                time_cost: config.argon2_time_cost,
+                parallelism: config.argon2_parallelism,
+            },
+        }
+    }
+
+    /// The key derivation function these parameters belong to
+    pub fn key_derivation(&self) -> KeyDerivation {
+        match self {
+            KdfParams::Pbkdf2 { .. } => KeyDerivation::Pbkdf2,
+            KdfParams::Argon2 { .. } => KeyDerivation::Argon2,
+        }
+    }
+
+    /// Check that the costs stay within the configured maxima
+    ///
+    /// Parameters read from a file must pass this before a key is derived,
+    /// since they are not yet authenticated.
+    pub fn check_limits(&self) -> Result<(), String> {
+        match *self {
+            KdfParams::Pbkdf2 { iterations } => {
+                if iterations > MAX_PBKDF2_ITERATIONS {
+                    return Err(format!(
+                        "PBKDF2 iterations must be at most {MAX_PBKDF2_ITERATIONS}"
+                    ));
+                }
+            }
+            KdfParams::Argon2 {
+                memory_kib,
+                time_cost,
+                parallelism,
+            } => {
+                if memory_kib > MAX_ARGON2_MEMORY_KIB {
+                    return Err(format!(
+                        "Argon2 memory cost must be at most {MAX_ARGON2_MEMORY_KIB} KiB"
+                    ));
+                }
+                if time_cost > MAX_ARGON2_TIME_COST {
+                    return Err(format!(
+                        "Argon2 time cost must be at most {MAX_ARGON2_TIME_COST}"
+                    ));
+                }
+                if parallelism == 0 || parallelism > MAX_ARGON2_PARALLELISM {
+                    return Err(format!(
+                        "Argon2 parallelism must be between 1-{MAX_ARGON2_PARALLELISM}"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A cryptographic key for encryption/decryption
 pub struct CryptoKey {
     /// The raw key material (zeroed on drop)
@@ -125,14 +241,66 @@ impl CryptoKey {
         Ok(Self { key, algorithm })
     }
 
+    /// Derive a key from a password using Argon2id
+    pub fn derive_argon2id(
+        password: &[u8],
+        salt: &[u8],
+        memory_kib: u32,
+        time_cost: u32,
+        parallelism: u32,
+        algorithm: Algorithm,
+    ) -> Result<Self, CryptoError> {
+        let params = argon2::Params::new(
+            memory_kib,
+            time_cost,
+            parallelism,
+            Some(algorithm.key_size()),
+        )
+        .map_err(|_| CryptoError::KeyDerivationFailed)?;
+        let argon2 =
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+        let mut key = vec![0u8; algorithm.key_size()];
+        argon2
+            .hash_password_into(password, salt, &mut key)
+            .map_err(|_| CryptoError::KeyDerivationFailed)?;
+
+        Ok(Self { key, algorithm })
+    }
+
+    /// Derive a key from a password with the given key derivation function
+    pub fn derive(
+        password: &[u8],
+        salt: &[u8],
+        params: &KdfParams,
+        algorithm: Algorithm,
+    ) -> Result<Self, CryptoError> {
+        match *params {
+            KdfParams::Pbkdf2 { iterations } => {
+                Self::derive_from_password(password, salt, iterations, algorithm)
+            }
+            KdfParams::Argon2 {
+                memory_kib,
+                time_cost,
+                parallelism,
+            } => Self::derive_argon2id(
+                password,
+                salt,
+                memory_kib,
+                time_cost,
+                parallelism,
+                algorithm,
+            ),
+        }
+    }
+
     /// Get the algorithm for this key
     pub fn algorithm(&self) -> Algorithm {
         self.algorithm
     }
 
-    /// Get the key material for testing purposes
-    #[cfg(test)]
-    pub fn key(&self) -> &[u8] {
+    /// Raw key material for other cipher constructions in this crate
+    pub(crate) fn key(&self) -> &[u8] {
         &self.key
     }
 
@@ -321,6 +489,91 @@ mod tests {
         assert_eq!(key.key, key2.key);
     }
 
+    #[test]
+    fn test_pbkdf2_known_answer() {
+        // RFC 7914 section 11, PBKDF2-HMAC-SHA256 (first 32 bytes)
+        let key = CryptoKey::derive(
+            b"passwd",
+            b"salt",
+            &KdfParams::Pbkdf2 { iterations: 1 },
+            Algorithm::Aes256Gcm,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(key.key()),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+    }
+
+    #[test]
+    fn test_argon2id_known_answer() {
+        // Argon2 reference implementation (phc-winner-argon2) test vector:
+        // argon2id, v=0x13, m=64 MiB, t=2, p=1, "password" / "somesalt"
+        let key = CryptoKey::derive(
+            b"password",
+            b"somesalt",
+            &KdfParams::Argon2 {
+                memory_kib: 65536,
+                time_cost: 2,
+                parallelism: 1,
+            },
+            Algorithm::Aes256Gcm,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(key.key()),
+            "09316115d5cf24ed5a15a31a3ba326e5cf32edc24702987c02b6566f61913cf7"
+        );
+    }
+
+    #[test]
+    fn test_argon2id_parameters_matter() {
+        let derive = |memory_kib, time_cost, parallelism| {
+            CryptoKey::derive(
+                b"password",
+                b"somesaltsomesalt",
+                &KdfParams::Argon2 {
+                    memory_kib,
+                    time_cost,
+                    parallelism,
+                },
+                Algorithm::ChaCha20Poly1305,
+            )
+            .unwrap()
+        };
+
+        let base = derive(1024, 2, 1);
+        assert_eq!(base.key(), derive(1024, 2, 1).key());
+        assert_ne!(base.key(), derive(2048, 2, 1).key());
+        assert_ne!(base.key(), derive(1024, 3, 1).key());
+        assert_ne!(base.key(), derive(1024, 2, 2).key());
+    }
+
+    #[test]
+    fn test_argon2id_rejects_invalid_parameters() {
+        let result = CryptoKey::derive_argon2id(b"pw", b"somesalt", 8, 0, 1, Algorithm::Aes256Gcm);
+        assert!(matches!(result, Err(CryptoError::KeyDerivationFailed)));
+    }
+
+    #[test]
+    fn test_kdf_params_from_config() {
+        let mut config = EncryptionConfig::default();
+        assert_eq!(
+            KdfParams::from_config(&config),
+            KdfParams::Pbkdf2 {
+                iterations: 100_000
+            }
+        );
+
+        config.key_derivation = KeyDerivation::Argon2;
+        let params = KdfParams::from_config(&config);
+        assert_eq!(params.key_derivation(), KeyDerivation::Argon2);
+        assert_eq!(
+            serde_json::to_value(&params).unwrap()["algorithm"],
+            "argon2"
+        );
+    }
+
     #[test]
     fn test_encrypt_decrypt_aes() {
         let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
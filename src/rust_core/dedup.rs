@@ -0,0 +1,875 @@
+//! Chunk-level deduplication
+//!
+//! With `advanced.experimental_dedup` set, sync splits each file into
+//! content-defined chunks (FastCDC) instead of encrypting it whole. Chunk
+//! boundaries follow the data, so an insertion near the start of a file
+//! only changes the chunks around it. The chunker aims for chunks of
+//! `policy.chunk_size_mb`, within what FastCDC supports (see [`Chunker`]).
+//!
+//! Each chunk is named by a keyed BLAKE3 hash of its contents. The key is
+//! random per device and kept only in the encrypted chunk index, so chunk
+//! IDs say nothing about the plaintext to anyone without the device key.
+//! Identical chunks, within a file, across files or across snapshots, are
+//! stored once:
+//!
+//! ```text
+//! AirGapSync/chunks/<first 2 hex digits>/<chunk id hex>.chunk
+//! AirGapSync/chunks/chunks.index
+//! ```
+//!
+//! Chunks and the index are encrypted like data files, with a
+//! [`FileHeader`] followed by a segmented stream. A chunk's payload context
+//! is its ID, so chunks cannot be swapped for one another. The decrypted
+//! index is:
+//!
+//! ```text
+//! magic        "AGCI"
+//! version      u8
+//! id key       32 bytes (BLAKE3 key for chunk IDs)
+//! chunk count  u64
+//! each chunk, sorted by ID:
+//!   id         32 bytes
+//!   references u64
+//!   size       u64 (plaintext bytes)
+//! ```
+//!
+//! All integers are big endian. A chunk's reference count is the number of
+//! times snapshot manifests list it as an object of a file; a chunk whose
+//! count drops to zero is no longer needed by any snapshot and is deleted
+//! by [`ChunkStore::collect_garbage`].
+
+use crate::compress::Compression;
+use crate::crypto::{CryptoError, CryptoKey, NonceGenerator};
+use crate::envelope::Recipient;
+use crate::header::{self, FileHeader};
+use crate::keystore::{EncryptionKey, KeyStore, KeyStoreError};
+use crate::snapshot::{EntryKind, Manifest, ObjectId};
+use crate::sync::DEVICE_ROOT;
+use fastcdc::v2020::{StreamCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Directory under the device root that holds chunks and their index
+pub const CHUNK_DIR: &str = "chunks";
+
+/// File extension of stored chunks
+pub const CHUNK_EXTENSION: &str = "chunk";
+
+/// Name of the chunk index under the chunk directory
+pub const INDEX_FILE: &str = "chunks.index";
+
+/// File extension of the chunk index
+pub const INDEX_EXTENSION: &str = "index";
+
+/// Magic bytes at the start of the decrypted chunk index
+pub const INDEX_MAGIC: &[u8; 4] = b"AGCI";
+
+/// Current chunk index format version
+pub const INDEX_VERSION: u8 = 1;
+
+/// Length of a chunk ID in bytes
+pub const CHUNK_ID_LEN: usize = 32;
+
+/// Payload context of the encrypted chunk index
+pub const INDEX_AAD: &str = "chunk-index";
+
+/// Deduplication errors
+#[derive(Debug, Error)]
+pub enum DedupError {
+    /// The chunk index is malformed
+    #[error("Invalid chunk index: {0}")]
+    InvalidIndex(String),
+
+    /// The chunk index was written by an unsupported format version
+    #[error("Unsupported chunk index format version {0}")]
+    UnsupportedVersion(u8),
+
+    /// A chunk is not in the index
+    #[error("Unknown chunk {0}")]
+    UnknownChunk(ChunkId),
+
+    /// A chunk's contents do not match its ID
+    #[error("Chunk {0} does not match its contents")]
+    Corrupt(ChunkId),
+
+    /// Encryption or decryption failed
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+
+    /// The key version the index was sealed with could not be loaded
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Keyed hash naming a chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkId([u8; CHUNK_ID_LEN]);
+
+impl ChunkId {
+    /// Raw ID bytes
+    pub fn as_bytes(&self) -> &[u8; CHUNK_ID_LEN] {
+        &self.0
+    }
+
+    /// Chunk ID held in a manifest object ID, if it is one
+    ///
+    /// Whole encrypted files are named by shorter file IDs, so the two
+    /// kinds of object cannot be confused.
+    pub fn from_object(object: &ObjectId) -> Option<Self> {
+        object.as_bytes().try_into().ok().map(Self)
+    }
+}
+
+impl From<ChunkId> for ObjectId {
+    fn from(id: ChunkId) -> Self {
+        ObjectId::new(id.0)
+    }
+}
+
+impl fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Content-defined chunker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunker {
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+}
+
+impl Chunker {
+    /// Chunker aiming for chunks of about `target` bytes
+    ///
+    /// The target is clamped to FastCDC's supported range (256 bytes to
+    /// 4 MiB). Chunks are at least a quarter and at most four times the
+    /// target, except for a file's last chunk, which may be shorter.
+    pub fn new(target: usize) -> Self {
+        let avg_size = target.clamp(AVERAGE_MIN as usize, AVERAGE_MAX as usize) as u32;
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: (avg_size * 4).min(MAXIMUM_MAX),
+        }
+    }
+
+    /// Target chunk size in bytes
+    pub fn target_size(&self) -> usize {
+        self.avg_size as usize
+    }
+
+    /// Largest chunk this chunker produces
+    pub fn max_size(&self) -> usize {
+        self.max_size as usize
+    }
+
+    /// Split everything read from `reader` into chunks
+    ///
+    /// An empty input yields no chunks.
+    pub fn chunks<R: Read>(&self, reader: R) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+        StreamCDC::new(reader, self.min_size, self.avg_size, self.max_size)
+            .map(|chunk| chunk.map(|chunk| chunk.data).map_err(io::Error::from))
+    }
+}
+
+/// Index entry for a stored chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRecord {
+    /// Number of manifest references to the chunk
+    pub references: u64,
+    /// Plaintext size in bytes
+    pub size: u64,
+}
+
+/// Chunk IDs and reference counts for one device
+pub struct ChunkIndex {
+    id_key: Zeroizing<[u8; CHUNK_ID_LEN]>,
+    chunks: BTreeMap<ChunkId, ChunkRecord>,
+}
+
+impl ChunkIndex {
+    /// Empty index with a fresh random ID key
+    pub fn new() -> Result<Self, DedupError> {
+        let random = Zeroizing::new(NonceGenerator::new().generate(CHUNK_ID_LEN)?);
+        let mut id_key = Zeroizing::new([0u8; CHUNK_ID_LEN]);
+        id_key.copy_from_slice(&random);
+        Ok(Self {
+            id_key,
+            chunks: BTreeMap::new(),
+        })
+    }
+
+    /// ID of a chunk with these contents
+    pub fn chunk_id(&self, data: &[u8]) -> ChunkId {
+        ChunkId(*blake3::keyed_hash(&self.id_key, data).as_bytes())
+    }
+
+    /// Index entry for a chunk
+    pub fn get(&self, id: &ChunkId) -> Option<&ChunkRecord> {
+        self.chunks.get(id)
+    }
+
+    /// Whether a chunk is stored
+    pub fn contains(&self, id: &ChunkId) -> bool {
+        self.chunks.contains_key(id)
+    }
+
+    /// Number of stored chunks
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Whether no chunks are stored
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Count the chunk references of every file in `manifest`
+    pub fn add_references(&mut self, manifest: &Manifest) {
+        for id in manifest_chunks(manifest) {
+            if let Some(record) = self.chunks.get_mut(&id) {
+                record.references += 1;
+            }
+        }
+    }
+
+    /// Drop the chunk references of every file in `manifest`
+    ///
+    /// Chunks left without references are removed from the index and
+    /// returned so their files can be deleted.
+    pub fn release(&mut self, manifest: &Manifest) -> Vec<ChunkId> {
+        let mut unused = Vec::new();
+        for id in manifest_chunks(manifest) {
+            if let Some(record) = self.chunks.get_mut(&id) {
+                record.references = record.references.saturating_sub(1);
+                if record.references == 0 {
+                    self.chunks.remove(&id);
+                    unused.push(id);
+                }
+            }
+        }
+        unused
+    }
+
+    /// Record a newly stored chunk, without references
+    fn insert(&mut self, id: ChunkId, size: u64) {
+        self.chunks.insert(
+            id,
+            ChunkRecord {
+                references: 0,
+                size,
+            },
+        );
+    }
+
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(Vec::with_capacity(
+            INDEX_MAGIC.len() + 1 + CHUNK_ID_LEN + 8 + self.chunks.len() * (CHUNK_ID_LEN + 16),
+        ));
+        out.extend_from_slice(INDEX_MAGIC);
+        out.push(INDEX_VERSION);
+        out.extend_from_slice(&*self.id_key);
+        out.extend_from_slice(&(self.chunks.len() as u64).to_be_bytes());
+        for (id, record) in &self.chunks {
+            out.extend_from_slice(&id.0);
+            out.extend_from_slice(&record.references.to_be_bytes());
+            out.extend_from_slice(&record.size.to_be_bytes());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, DedupError> {
+        let invalid = |reason: &str| DedupError::InvalidIndex(reason.to_string());
+        let header_len = INDEX_MAGIC.len() + 1 + CHUNK_ID_LEN + 8;
+        if bytes.len() < header_len || &bytes[..INDEX_MAGIC.len()] != INDEX_MAGIC {
+            return Err(invalid("not a chunk index"));
+        }
+        let version = bytes[INDEX_MAGIC.len()];
+        if version != INDEX_VERSION {
+            return Err(DedupError::UnsupportedVersion(version));
+        }
+
+        let (header, body) = bytes.split_at(header_len);
+        let mut id_key = Zeroizing::new([0u8; CHUNK_ID_LEN]);
+        id_key.copy_from_slice(&header[INDEX_MAGIC.len() + 1..][..CHUNK_ID_LEN]);
+        let count = u64::from_be_bytes(header[header_len - 8..].try_into().unwrap_or_default());
+
+        const ENTRY_LEN: usize = CHUNK_ID_LEN + 16;
+        if body.len() % ENTRY_LEN != 0 || (body.len() / ENTRY_LEN) as u64 != count {
+            return Err(invalid("chunk count does not match its entries"));
+        }
+        let mut chunks = BTreeMap::new();
+        for entry in body.chunks_exact(ENTRY_LEN) {
+            let (id, numbers) = entry.split_at(CHUNK_ID_LEN);
+            let (references, size) = numbers.split_at(8);
+            let id = ChunkId(id.try_into().unwrap_or_default());
+            let record = ChunkRecord {
+                references: u64::from_be_bytes(references.try_into().unwrap_or_default()),
+                size: u64::from_be_bytes(size.try_into().unwrap_or_default()),
+            };
+            if chunks.last_key_value().is_some_and(|(last, _)| *last >= id) {
+                return Err(invalid("chunks out of order"));
+            }
+            chunks.insert(id, record);
+        }
+        Ok(Self { id_key, chunks })
+    }
+}
+
+/// A file's contents as stored chunks
+#[derive(Debug, Clone, Default)]
+pub struct StoredChunks {
+    /// Chunks holding the contents, in order
+    pub chunks: Vec<ChunkId>,
+    /// Plaintext bytes read
+    pub bytes_read: u64,
+    /// Ciphertext bytes written for chunks that were not already stored
+    pub bytes_written: u64,
+}
+
+/// Deduplicated chunk storage on a device
+pub struct ChunkStore<'a> {
+    dir: PathBuf,
+    key: &'a EncryptionKey,
+    archive: Option<&'a dyn KeyStore>,
+    recipients: &'a [Recipient],
+    chunker: Chunker,
+    compression: Compression,
+    index: ChunkIndex,
+}
+
+impl<'a> ChunkStore<'a> {
+    /// Open the chunk store on a device, creating an empty index if needed
+    ///
+    /// Chunks are encrypted to the device key and `recipients`.
+    pub fn open(
+        mount_point: &Path,
+        key: &'a EncryptionKey,
+        recipients: &'a [Recipient],
+        chunker: Chunker,
+    ) -> Result<Self, DedupError> {
+        Self::open_index(mount_point, key, None, recipients, chunker)
+    }
+
+    /// Like [`ChunkStore::open`], but the index and chunks sealed under an
+    /// older version of the device key are opened with that version from
+    /// `store`
+    ///
+    /// The index is sealed under `key` the next time it is saved.
+    pub fn open_with_archive(
+        mount_point: &Path,
+        key: &'a EncryptionKey,
+        store: &'a dyn KeyStore,
+        recipients: &'a [Recipient],
+        chunker: Chunker,
+    ) -> Result<Self, DedupError> {
+        Self::open_index(mount_point, key, Some(store), recipients, chunker)
+    }
+
+    fn open_index(
+        mount_point: &Path,
+        key: &'a EncryptionKey,
+        archive: Option<&'a dyn KeyStore>,
+        recipients: &'a [Recipient],
+        chunker: Chunker,
+    ) -> Result<Self, DedupError> {
+        let dir = chunk_dir(mount_point);
+        let index = match File::open(dir.join(INDEX_FILE)) {
+            Ok(file) => read_index(key, archive, BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ChunkIndex::new()?,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            dir,
+            key,
+            archive,
+            recipients,
+            chunker,
+            compression: Compression::None,
+            index,
+        })
+    }
+
+    /// Compress new chunks before encryption
+    ///
+    /// Chunks that would not shrink are still stored as is (see
+    /// [`Compression::for_sample`]).
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The chunk index
+    pub fn index(&self) -> &ChunkIndex {
+        &self.index
+    }
+
+    /// Split `reader` into chunks, writing each chunk not yet stored
+    pub fn store<R: Read>(&mut self, reader: R) -> Result<StoredChunks, DedupError> {
+        let mut stored = StoredChunks::default();
+        for data in self.chunker.chunks(reader) {
+            let data = Zeroizing::new(data?);
+            let id = self.index.chunk_id(&data);
+            stored.bytes_read += data.len() as u64;
+            if !self.index.contains(&id) {
+                stored.bytes_written += self.write_chunk(&id, &data)?;
+                self.index.insert(id, data.len() as u64);
+            }
+            stored.chunks.push(id);
+        }
+        Ok(stored)
+    }
+
+    /// Decrypt a stored chunk and check it against its ID
+    ///
+    /// A chunk kept from before a key rotation is opened with the key
+    /// version named in its header if the store was opened with an archive.
+    pub fn read_chunk(&self, id: &ChunkId) -> Result<Zeroizing<Vec<u8>>, DedupError> {
+        if !self.index.contains(id) {
+            return Err(DedupError::UnknownChunk(*id));
+        }
+        let mut reader = BufReader::new(File::open(self.chunk_path(id))?);
+        let file_header = FileHeader::read_from(&mut reader)?;
+        let data_key = open_data_key(&file_header, self.key, self.archive)?;
+        let mut data = Zeroizing::new(Vec::new());
+        header::decrypt_file(
+            &data_key,
+            &file_header,
+            reader,
+            &mut *data,
+            chunk_aad(id).as_bytes(),
+        )?;
+        if self.index.chunk_id(&data) != *id {
+            return Err(DedupError::Corrupt(*id));
+        }
+        Ok(data)
+    }
+
+    /// Count the references of `manifest` and save the index
+    ///
+    /// Call this before writing the manifest itself: a run interrupted in
+    /// between leaves counts too high, which only keeps chunks longer.
+    pub fn commit(&mut self, manifest: &Manifest) -> Result<(), DedupError> {
+        self.index.add_references(manifest);
+        self.save()
+    }
+
+    /// Release the chunks of deleted snapshots and delete unused chunks
+    ///
+    /// Call this after deleting the manifests of `released`: a run stopped
+    /// in between leaves counts too high, which only keeps chunks longer.
+    /// Chunks left without references, including those stored for a file
+    /// that then failed, and chunk files the index does not list, such as
+    /// those written by a run that never committed, are deleted. Returns
+    /// the number of chunk files deleted.
+    pub fn collect_garbage(&mut self, released: &[Manifest]) -> Result<u64, DedupError> {
+        let before = self.index.len();
+        for manifest in released {
+            self.index.release(manifest);
+        }
+        self.index.chunks.retain(|_, record| record.references > 0);
+        if self.index.len() != before {
+            self.save()?;
+        }
+
+        let mut deleted = 0;
+        let prefixes = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        for prefix in prefixes {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(prefix.path())? {
+                let path = entry?.path();
+                match path.extension().and_then(|e| e.to_str()) {
+                    Some(CHUNK_EXTENSION)
+                        if chunk_id_from_path(&path)
+                            .is_some_and(|id| !self.index.contains(&id)) =>
+                    {
+                        fs::remove_file(&path)?;
+                        deleted += 1;
+                    }
+                    // Left behind by an interrupted write
+                    Some("tmp") => fs::remove_file(&path)?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Encrypt the index and atomically replace the one on the device
+    pub fn save(&self) -> Result<(), DedupError> {
+        fs::create_dir_all(&self.dir)?;
+        let body = self.index.encode();
+        write_atomic(&self.dir.join(INDEX_FILE), |writer| {
+            let (file_header, data_key) = FileHeader::seal(self.key, self.recipients)?;
+            header::encrypt_file(
+                &data_key,
+                &file_header,
+                &mut &body[..],
+                writer,
+                self.chunker.max_size(),
+                INDEX_AAD.as_bytes(),
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Location of a chunk in this store
+    pub fn chunk_path(&self, id: &ChunkId) -> PathBuf {
+        chunk_file(&self.dir, id)
+    }
+
+    /// Encrypt one chunk into the store, returning the bytes written
+    fn write_chunk(&self, id: &ChunkId, data: &[u8]) -> Result<u64, DedupError> {
+        let path = self.chunk_path(id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let compression = self.compression.for_sample(data);
+        write_atomic(&path, |writer| {
+            let (file_header, data_key) =
+                FileHeader::seal_compressed(self.key, self.recipients, compression)?;
+            header::encrypt_file(
+                &data_key,
+                &file_header,
+                &mut &data[..],
+                writer,
+                self.chunker.max_size(),
+                chunk_aad(id).as_bytes(),
+            )?;
+            Ok(())
+        })?;
+        Ok(fs::metadata(&path)?.len())
+    }
+}
+
+/// Directory holding chunks and the chunk index under a device mount point
+pub fn chunk_dir(mount_point: &Path) -> PathBuf {
+    mount_point.join(DEVICE_ROOT).join(CHUNK_DIR)
+}
+
+/// Location of a chunk under the chunk directory
+pub fn chunk_file(chunk_dir: &Path, id: &ChunkId) -> PathBuf {
+    let name = id.to_string();
+    chunk_dir
+        .join(&name[..2])
+        .join(format!("{name}.{CHUNK_EXTENSION}"))
+}
+
+/// Additional authenticated data binding an encrypted chunk to its ID
+pub fn chunk_aad(id: &ChunkId) -> String {
+    format!("chunk:{id}")
+}
+
+/// Chunk ID named by a stored chunk's file name, if it is one
+pub fn chunk_id_from_path(path: &Path) -> Option<ChunkId> {
+    let stem = path.file_stem()?.to_str()?;
+    let bytes = hex::decode(stem).ok()?;
+    Some(ChunkId(bytes.try_into().ok()?))
+}
+
+/// Decrypt a chunk index read from `reader`
+fn read_index<R: Read>(
+    key: &EncryptionKey,
+    archive: Option<&dyn KeyStore>,
+    mut reader: R,
+) -> Result<ChunkIndex, DedupError> {
+    let file_header = FileHeader::read_from(&mut reader)?;
+    let data_key = open_data_key(&file_header, key, archive)?;
+    let mut body = Zeroizing::new(Vec::new());
+    header::decrypt_file(
+        &data_key,
+        &file_header,
+        reader,
+        &mut *body,
+        INDEX_AAD.as_bytes(),
+    )?;
+    ChunkIndex::decode(&body)
+}
+
+/// Unwrap the data key of an index or chunk
+///
+/// With an `archive`, a header naming another version of the device key is
+/// opened with that version.
+fn open_data_key(
+    file_header: &FileHeader,
+    key: &EncryptionKey,
+    archive: Option<&dyn KeyStore>,
+) -> Result<CryptoKey, DedupError> {
+    Ok(match (file_header.device_key(), archive) {
+        (Some((device_id, version)), Some(store))
+            if device_id == key.metadata.device_id && version != key.metadata.version =>
+        {
+            let archived = store.get_key_version(device_id, version)?;
+            file_header.open_with_device_key(&archived)?
+        }
+        _ => file_header.open_with_device_key(key)?,
+    })
+}
+
+/// Chunk objects of every file in a manifest, with repeats
+fn manifest_chunks(manifest: &Manifest) -> impl Iterator<Item = ChunkId> + '_ {
+    manifest
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::File)
+        .flat_map(|entry| entry.objects.iter().filter_map(ChunkId::from_object))
+}
+
+/// Write `path` through a temporary file renamed into place on success
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), DedupError>,
+) -> Result<(), DedupError> {
+    let temp = path.with_extension("tmp");
+    let result = (|| -> Result<(), DedupError> {
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::generate_key;
+    use crate::snapshot::ManifestEntry;
+    use chrono::Utc;
+
+    /// Deterministic pseudo-random bytes
+    fn sample(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn manifest(objects: Vec<Vec<ChunkId>>) -> Manifest {
+        let entries = objects
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunks)| ManifestEntry {
+                path: format!("file{i}"),
+                kind: EntryKind::File,
+                size: 0,
+                modified: Utc::now(),
+                mode: 0,
+                content_hash: [0; 32],
+                objects: chunks.into_iter().map(ObjectId::from).collect(),
+            })
+            .collect();
+        Manifest {
+            snapshot_id: "20261017T120000000000Z-00000000".to_string(),
+            created_at: Utc::now(),
+            host: "test".to_string(),
+            config_hash: [0; 32],
+            device_id: "USB001".to_string(),
+            key_version: 1,
+            key_id: [0; 8],
+            entries,
+            signer: None,
+        }
+    }
+
+    #[test]
+    fn test_chunker_sizes() {
+        let chunker = Chunker::new(1 << 20);
+        assert_eq!(chunker.target_size(), 1 << 20);
+        assert_eq!(chunker.max_size(), 4 << 20);
+        assert_eq!(Chunker::new(1 << 30).target_size(), AVERAGE_MAX as usize);
+        assert_eq!(Chunker::new(1).target_size(), AVERAGE_MIN as usize);
+
+        let data = sample(64 * 1024, 1);
+        let chunker = Chunker::new(4096);
+        let chunks: Vec<Vec<u8>> = chunker.chunks(&data[..]).map(|c| c.unwrap()).collect();
+        assert!(chunks.len() > 4);
+        assert!(chunks.iter().all(|c| c.len() <= chunker.max_size()));
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(chunker.chunks(&[][..]).count(), 0);
+    }
+
+    #[test]
+    fn test_insertion_keeps_later_chunks() {
+        let chunker = Chunker::new(4096);
+        let index = ChunkIndex::new().unwrap();
+        let ids = |data: &[u8]| -> Vec<ChunkId> {
+            chunker
+                .chunks(data)
+                .map(|c| index.chunk_id(&c.unwrap()))
+                .collect()
+        };
+
+        let original = sample(64 * 1024, 2);
+        let mut edited = b"a few new bytes at the start".to_vec();
+        edited.extend_from_slice(&original);
+
+        let before = ids(&original);
+        let after = ids(&edited);
+        let shared = after.iter().filter(|id| before.contains(id)).count();
+        assert!(shared >= before.len() - 2, "{shared} of {}", before.len());
+    }
+
+    #[test]
+    fn test_chunk_ids_are_keyed() {
+        let a = ChunkIndex::new().unwrap();
+        let b = ChunkIndex::new().unwrap();
+        assert_eq!(a.chunk_id(b"data"), a.chunk_id(b"data"));
+        assert_ne!(a.chunk_id(b"data"), b.chunk_id(b"data"));
+        assert_ne!(
+            a.chunk_id(b"data").as_bytes(),
+            blake3::hash(b"data").as_bytes()
+        );
+
+        let object = ObjectId::from(a.chunk_id(b"data"));
+        assert_eq!(ChunkId::from_object(&object), Some(a.chunk_id(b"data")));
+        assert_eq!(ChunkId::from_object(&ObjectId::new([0u8; 16])), None);
+    }
+
+    #[test]
+    fn test_store_deduplicates_and_counts_references() {
+        let device = tempfile::tempdir().unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let chunker = Chunker::new(4096);
+        let data = sample(32 * 1024, 3);
+
+        let mut store = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        let first = store.store(&data[..]).unwrap();
+        assert_eq!(first.bytes_read, data.len() as u64);
+        assert!(first.bytes_written > first.bytes_read);
+        let stored = store.index().len();
+
+        // The same contents again write nothing new
+        let second = store.store(&data[..]).unwrap();
+        assert_eq!(second.chunks, first.chunks);
+        assert_eq!(second.bytes_written, 0);
+        assert_eq!(store.index().len(), stored);
+
+        let snapshot = manifest(vec![first.chunks.clone(), second.chunks.clone()]);
+        store.commit(&snapshot).unwrap();
+
+        // The saved index reopens with its ID key and counts
+        let mut store = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        let id = first.chunks[0];
+        assert_eq!(store.index().get(&id).unwrap().references, 2);
+        assert_eq!(store.store(&data[..]).unwrap().chunks, first.chunks);
+        let contents: Vec<u8> = first
+            .chunks
+            .iter()
+            .flat_map(|id| store.read_chunk(id).unwrap().to_vec())
+            .collect();
+        assert_eq!(contents, data);
+
+        // Releasing the only snapshot frees every chunk
+        let mut index = ChunkIndex::decode(&store.index().encode()).unwrap();
+        let unused = index.release(&snapshot);
+        assert_eq!(unused.len(), stored);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let device = tempfile::tempdir().unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let chunker = Chunker::new(4096);
+
+        let mut store = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        let old = store
+            .store(&b"only in the old snapshot"[..])
+            .unwrap()
+            .chunks;
+        let shared = store.store(&b"in both snapshots"[..]).unwrap().chunks;
+        let first = manifest(vec![old.clone(), shared.clone()]);
+        store.commit(&first).unwrap();
+        store.commit(&manifest(vec![shared.clone()])).unwrap();
+
+        // A run that stored a chunk but never committed leaves it unindexed
+        let mut failed = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        let orphan = failed.store(&b"never committed"[..]).unwrap().chunks;
+        let orphan_path = failed.chunk_path(&orphan[0]);
+        fs::write(orphan_path.with_extension("tmp"), b"partial").unwrap();
+        drop(failed);
+
+        let mut store = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        assert_eq!(store.collect_garbage(&[first]).unwrap(), 2);
+        assert!(!store.chunk_path(&old[0]).exists());
+        assert!(!orphan_path.exists());
+        assert!(!orphan_path.with_extension("tmp").exists());
+        assert_eq!(
+            &store.read_chunk(&shared[0]).unwrap()[..],
+            b"in both snapshots"
+        );
+
+        let store = ChunkStore::open(device.path(), &key, &[], chunker).unwrap();
+        assert_eq!(store.index().len(), 1);
+        assert_eq!(store.index().get(&shared[0]).unwrap().references, 1);
+    }
+
+    #[test]
+    fn test_compressed_chunks() {
+        let device = tempfile::tempdir().unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let data = b"a very repetitive chunk ".repeat(1024);
+
+        let mut store = ChunkStore::open(device.path(), &key, &[], Chunker::new(64 * 1024))
+            .unwrap()
+            .with_compression(Compression::from_policy_level(3));
+        let stored = store.store(&data[..]).unwrap();
+        assert!(stored.bytes_written < stored.bytes_read / 10);
+        let id = stored.chunks[0];
+        let header =
+            FileHeader::read_from(&mut File::open(store.chunk_path(&id)).unwrap()).unwrap();
+        assert_eq!(header.compression, Compression::Zstd { level: 5 });
+        store.save().unwrap();
+
+        // The codec comes from each chunk's header, not from the store
+        let store = ChunkStore::open(device.path(), &key, &[], Chunker::new(64 * 1024)).unwrap();
+        assert_eq!(&store.read_chunk(&id).unwrap()[..], &data[..]);
+    }
+
+    #[test]
+    fn test_chunk_bound_to_its_id() {
+        let device = tempfile::tempdir().unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let mut store = ChunkStore::open(device.path(), &key, &[], Chunker::new(4096)).unwrap();
+        let a = store.store(&b"alpha"[..]).unwrap().chunks[0];
+        let b = store.store(&b"bravo"[..]).unwrap().chunks[0];
+        assert_eq!(chunk_id_from_path(&store.chunk_path(&a)), Some(a));
+
+        fs::copy(store.chunk_path(&a), store.chunk_path(&b)).unwrap();
+        assert!(store.read_chunk(&b).is_err());
+        assert!(matches!(
+            store.read_chunk(&ChunkIndex::new().unwrap().chunk_id(b"x")),
+            Err(DedupError::UnknownChunk(_))
+        ));
+    }
+}
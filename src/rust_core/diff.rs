@@ -0,0 +1,327 @@
+//! Change detection against the previous snapshot
+//!
+//! A diff walks the source directory (see [`crate::walker`]) and compares
+//! every included path with the most recent snapshot manifest on the
+//! device. A file whose size and modification time both match its manifest
+//! entry is unchanged without being read. When only the modification time
+//! differs, the file is hashed and counts as unchanged if the content hash
+//! still matches, so touching a file does not re-encrypt it. Paths in the
+//! manifest that the walk no longer finds are deleted.
+//!
+//! Sync uses a diff to encrypt only added and modified files; on its own, a
+//! diff is a dry run that reads the source but writes nothing.
+
+use crate::snapshot::{self, EntryKind, Manifest, ManifestEntry};
+use crate::sync::SyncFailure;
+use crate::walker::{SourceWalker, WalkEvent};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How a path changed since the previous snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Not in the previous snapshot
+    Added,
+    /// Contents (or type) differ from the previous snapshot
+    Modified,
+    /// In the previous snapshot, but no longer in the source
+    Deleted,
+    /// Same contents as in the previous snapshot
+    Unchanged,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Deleted => "deleted",
+            ChangeKind::Unchanged => "unchanged",
+        })
+    }
+}
+
+/// One path in a diff
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// How the path changed
+    pub kind: ChangeKind,
+    /// Path relative to the source root, components joined with `/`
+    pub path: String,
+    /// Path on disk; `None` for deleted paths
+    pub source: Option<PathBuf>,
+    /// Entry for the next manifest; `None` for deleted paths
+    ///
+    /// Unchanged files keep the previous content hash and objects with the
+    /// current metadata. Added and modified files have their content hash
+    /// left zero unless the diff had to compute it.
+    pub current: Option<ManifestEntry>,
+    /// Entry in the previous manifest; `None` for added paths
+    pub previous: Option<ManifestEntry>,
+}
+
+/// Changes between the source directory and the previous snapshot
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDiff {
+    /// Snapshot compared against; `None` if the device had none
+    pub previous_snapshot: Option<String>,
+    /// Every path in the source or the previous snapshot, sorted by path
+    pub changes: Vec<Change>,
+    /// Paths that could not be read; these are left out of `changes`, even
+    /// if the previous snapshot has them
+    pub failures: Vec<SyncFailure>,
+}
+
+impl SnapshotDiff {
+    /// Compare the source directory with `previous`
+    ///
+    /// With no previous manifest, every path is added.
+    pub fn compute(walker: &SourceWalker, previous: Option<&Manifest>) -> Self {
+        let mut known: BTreeMap<&str, &ManifestEntry> = previous
+            .map(|m| m.entries.iter().map(|e| (e.path.as_str(), e)).collect())
+            .unwrap_or_default();
+        let mut diff = SnapshotDiff {
+            previous_snapshot: previous.map(|m| m.snapshot_id.clone()),
+            ..Default::default()
+        };
+
+        for event in walker.walk() {
+            let entry = match event {
+                Ok(WalkEvent::Entry(entry)) => entry,
+                Ok(WalkEvent::Excluded { relative, reason }) => {
+                    log::debug!("Excluded {}: {reason}", relative.display());
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("{e}");
+                    diff.failures.push(SyncFailure {
+                        path: e.path().map(PathBuf::from).unwrap_or_default(),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            // A path that cannot be read is a failure, not a deletion
+            let previous = known.remove(snapshot::manifest_path(&entry.relative).as_str());
+            let classified = std::fs::metadata(&entry.path).and_then(|metadata| {
+                let current = ManifestEntry::from_metadata(&entry.relative, &metadata);
+                classify(&entry.path, current, previous)
+            });
+
+            match classified {
+                Ok((kind, current)) => diff.changes.push(Change {
+                    kind,
+                    path: current.path.clone(),
+                    source: Some(entry.path),
+                    current: Some(current),
+                    previous: previous.cloned(),
+                }),
+                Err(e) => {
+                    log::warn!("Failed to read {}: {e}", entry.relative.display());
+                    diff.failures.push(SyncFailure {
+                        path: entry.relative,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        diff.changes
+            .extend(known.into_values().map(|previous| Change {
+                kind: ChangeKind::Deleted,
+                path: previous.path.clone(),
+                source: None,
+                current: None,
+                previous: Some(previous.clone()),
+            }));
+        diff.changes.sort_by(|a, b| a.path.cmp(&b.path));
+        diff
+    }
+
+    /// Changes of one kind
+    pub fn of_kind(&self, kind: ChangeKind) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(move |c| c.kind == kind)
+    }
+
+    /// Number of changes of one kind
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.of_kind(kind).count()
+    }
+
+    /// Whether anything was added, modified or deleted
+    pub fn has_changes(&self) -> bool {
+        self.changes.iter().any(|c| c.kind != ChangeKind::Unchanged)
+    }
+}
+
+/// Compare a walked path with its previous manifest entry
+fn classify(
+    path: &std::path::Path,
+    mut current: ManifestEntry,
+    previous: Option<&ManifestEntry>,
+) -> std::io::Result<(ChangeKind, ManifestEntry)> {
+    let Some(previous) = previous else {
+        return Ok((ChangeKind::Added, current));
+    };
+    if current.kind != previous.kind {
+        return Ok((ChangeKind::Modified, current));
+    }
+    if current.kind == EntryKind::Directory {
+        return Ok((ChangeKind::Unchanged, current));
+    }
+    if current.size != previous.size {
+        return Ok((ChangeKind::Modified, current));
+    }
+
+    if current.modified != previous.modified {
+        current.content_hash = snapshot::hash_file(path)?;
+        if current.content_hash != previous.content_hash {
+            return Ok((ChangeKind::Modified, current));
+        }
+    }
+    current.content_hash = previous.content_hash;
+    current.objects = previous.objects.clone();
+    Ok((ChangeKind::Unchanged, current))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SourceConfig;
+    use crate::snapshot::ObjectId;
+    use chrono::{DateTime, Utc};
+    use std::path::Path;
+
+    fn walker(root: &Path) -> SourceWalker {
+        SourceWalker::new(&SourceConfig {
+            path: root.to_path_buf(),
+            exclude: vec![],
+            follow_symlinks: false,
+            include_hidden: false,
+        })
+        .unwrap()
+    }
+
+    /// Manifest recording the source as it is now
+    fn snapshot_of(root: &Path) -> Manifest {
+        let diff = SnapshotDiff::compute(&walker(root), None);
+        let entries = diff
+            .changes
+            .into_iter()
+            .map(|change| {
+                let mut entry = change.current.unwrap();
+                if entry.kind == EntryKind::File {
+                    entry.content_hash = snapshot::hash_file(&change.source.unwrap()).unwrap();
+                    entry.objects = vec![ObjectId::new(entry.path.as_bytes())];
+                }
+                entry
+            })
+            .collect();
+        Manifest {
+            snapshot_id: "20261017T120000000000Z-00000000".to_string(),
+            created_at: Utc::now(),
+            host: "test".to_string(),
+            config_hash: [0; snapshot::HASH_LEN],
+            device_id: "USB001".to_string(),
+            key_version: 1,
+            key_id: [0; 8],
+            entries,
+            signer: None,
+        }
+    }
+
+    fn kinds(diff: &SnapshotDiff) -> Vec<(&str, ChangeKind)> {
+        diff.changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_first_diff_adds_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/a.txt"), b"alpha").unwrap();
+
+        let diff = SnapshotDiff::compute(&walker(dir.path()), None);
+        assert_eq!(diff.previous_snapshot, None);
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("docs", ChangeKind::Added),
+                ("docs/a.txt", ChangeKind::Added)
+            ]
+        );
+        assert!(diff.has_changes());
+    }
+
+    #[test]
+    fn test_diff_classifies_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for (name, contents) in [
+            ("same.txt", "same"),
+            ("touched.txt", "touched"),
+            ("edited.txt", "edited"),
+            ("grown.txt", "grown"),
+            ("gone.txt", "gone"),
+        ] {
+            std::fs::write(root.join(name), contents).unwrap();
+        }
+        let mut previous = snapshot_of(root);
+        let old = DateTime::from_timestamp(1_000_000_000, 0).unwrap();
+        for entry in &mut previous.entries {
+            if entry.path == "touched.txt" || entry.path == "edited.txt" {
+                entry.modified = old;
+            }
+        }
+
+        // Same size but new contents, found only by hashing
+        std::fs::write(root.join("edited.txt"), "EDITED").unwrap();
+        std::fs::write(root.join("grown.txt"), "grown larger").unwrap();
+        std::fs::remove_file(root.join("gone.txt")).unwrap();
+        std::fs::write(root.join("new.txt"), "new").unwrap();
+
+        let diff = SnapshotDiff::compute(&walker(root), Some(&previous));
+        assert_eq!(
+            diff.previous_snapshot.as_deref(),
+            Some(previous.snapshot_id.as_str())
+        );
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("edited.txt", ChangeKind::Modified),
+                ("gone.txt", ChangeKind::Deleted),
+                ("grown.txt", ChangeKind::Modified),
+                ("new.txt", ChangeKind::Added),
+                ("same.txt", ChangeKind::Unchanged),
+                ("touched.txt", ChangeKind::Unchanged),
+            ]
+        );
+        assert_eq!(diff.count(ChangeKind::Unchanged), 2);
+
+        // Unchanged files carry their stored objects forward with the
+        // current metadata
+        let touched = diff
+            .changes
+            .iter()
+            .find(|c| c.path == "touched.txt")
+            .unwrap();
+        let current = touched.current.as_ref().unwrap();
+        assert_eq!(current.objects, [ObjectId::new("touched.txt")]);
+        assert_ne!(current.modified, old);
+    }
+
+    #[test]
+    fn test_unchanged_tree_has_no_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"alpha").unwrap();
+        let previous = snapshot_of(dir.path());
+
+        let diff = SnapshotDiff::compute(&walker(dir.path()), Some(&previous));
+        assert!(!diff.has_changes());
+        assert!(diff.failures.is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+//! Envelope encryption of per-file data keys
+//!
+//! Every encrypted file is protected by its own random data key, which is
+//! stored in the file header wrapped once for each party allowed to read
+//! the file:
+//!
+//! - a device key acts as a key-encryption key and wraps the data key with
+//!   [`crypto::encrypt`]
+//! - an RSA recipient wraps it with RSA-OAEP (SHA-256)
+//! - a P-256, P-384 or X25519 recipient wraps it with ephemeral-static ECIES (see
+//!   [`keys::ecies_encrypt`])
+//! - a hybrid recipient, holding an X25519 or P-256 key and an ML-KEM-768
+//!   key, wraps it under both at once (see [`keys::hybrid_encrypt`]), so the
+//!   data key stays protected if either primitive is broken
+//!
+//! Only the wrapped copies depend on the key-encryption key, so rotating a
+//! device key re-wraps a few dozen bytes per file instead of re-encrypting
+//! the data.
+
+use crate::crypto::{self, Algorithm, CryptoError, CryptoKey};
+use crate::keys::{self, AsymmetricAlgorithm, AsymmetricKey, HybridCiphertext, KeyError};
+use pkcs8::DecodePrivateKey;
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use spki::DecodePublicKey;
+
+/// Length of a recipient ID (SHA-256 of the public key)
+pub const RECIPIENT_ID_LEN: usize = 32;
+
+/// ECIES info for keys that wrap data keys to an EC recipient
+const EC_WRAP_INFO: &[u8] = b"AirGapSync ECDH key wrap v1";
+
+/// HKDF info for keys that wrap data keys to a hybrid recipient
+const HYBRID_WRAP_INFO: &[u8] = b"AirGapSync ML-KEM-768 hybrid key wrap v1";
+
+/// Public key a data key can be wrapped to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient {
+    algorithm: AsymmetricAlgorithm,
+    public_key: Vec<u8>,
+    /// ML-KEM-768 encapsulation key of a hybrid recipient
+    kem_public_key: Option<Vec<u8>>,
+}
+
+impl Recipient {
+    /// Recipient for an encoded public key
+    ///
+    /// RSA keys are SPKI DER, EC keys SEC1 points and X25519 keys raw 32
+    /// bytes, as returned by [`AsymmetricKey::public_key_bytes`]. Ed25519
+    /// keys can only sign and are rejected, as are ML-KEM-768 keys on their
+    /// own (see [`Self::with_ml_kem`]).
+    pub fn new(algorithm: AsymmetricAlgorithm, public_key: Vec<u8>) -> Result<Self, CryptoError> {
+        let valid = match algorithm {
+            AsymmetricAlgorithm::Rsa2048 | AsymmetricAlgorithm::Rsa4096 => {
+                RsaPublicKey::from_public_key_der(&public_key).is_ok()
+            }
+            AsymmetricAlgorithm::EcdsaP256 => p256::PublicKey::from_sec1_bytes(&public_key).is_ok(),
+            AsymmetricAlgorithm::EcdsaP384 => p384::PublicKey::from_sec1_bytes(&public_key).is_ok(),
+            AsymmetricAlgorithm::X25519 => public_key.len() == 32,
+            AsymmetricAlgorithm::Ed25519 => {
+                return Err(CryptoError::InvalidRecipient(
+                    "Ed25519 keys cannot be encrypted to".to_string(),
+                ))
+            }
+            AsymmetricAlgorithm::MlKem768 => {
+                return Err(CryptoError::InvalidRecipient(
+                    "ML-KEM-768 keys are only used alongside an X25519 or P-256 key".to_string(),
+                ))
+            }
+        };
+        if !valid {
+            return Err(CryptoError::InvalidRecipient(format!(
+                "malformed {} public key",
+                algorithm.as_str()
+            )));
+        }
+        Ok(Self {
+            algorithm,
+            public_key,
+            kem_public_key: None,
+        })
+    }
+
+    /// Recipient for a PEM SubjectPublicKeyInfo ("PUBLIC KEY") block
+    ///
+    /// Accepts the RSA-2048, RSA-4096, P-256, P-384 and X25519 keys
+    /// printed by `airgapsync keygen`.
+    pub fn from_public_key_pem(pem: &str) -> Result<Self, CryptoError> {
+        match AsymmetricKey::from_public_pem(pem) {
+            Ok(key) => Self::new(key.algorithm, key.public_key_bytes().to_vec()),
+            Err(KeyError::UnsupportedAlgorithm(msg)) => Err(CryptoError::InvalidRecipient(msg)),
+            Err(_) => Err(CryptoError::InvalidRecipient(
+                "not an RSA, P-256, P-384 or X25519 public key".to_string(),
+            )),
+        }
+    }
+
+    /// Recipient for the public half of a key pair
+    pub fn from_key(key: &AsymmetricKey) -> Self {
+        Self {
+            algorithm: key.algorithm,
+            public_key: key.public_key_bytes().to_vec(),
+            kem_public_key: None,
+        }
+    }
+
+    /// Turn an X25519 or P-256 recipient into a post-quantum hybrid one
+    ///
+    /// Data keys are then wrapped to both this recipient's key and the
+    /// ML-KEM-768 public key `kem_key`, and the holder needs both private
+    /// keys to unwrap them.
+    pub fn with_ml_kem(mut self, kem_key: &AsymmetricKey) -> Result<Self, CryptoError> {
+        if !matches!(
+            self.algorithm,
+            AsymmetricAlgorithm::X25519 | AsymmetricAlgorithm::EcdsaP256
+        ) {
+            return Err(CryptoError::InvalidRecipient(format!(
+                "{} keys cannot be combined with ML-KEM (use X25519 or ECDSA-P256)",
+                self.algorithm.as_str()
+            )));
+        }
+        if kem_key.algorithm != AsymmetricAlgorithm::MlKem768 {
+            return Err(CryptoError::InvalidRecipient(format!(
+                "expected an ML-KEM-768 key, got {}",
+                kem_key.algorithm.as_str()
+            )));
+        }
+        self.kem_public_key = Some(kem_key.public_key_bytes().to_vec());
+        Ok(self)
+    }
+
+    /// Algorithm of the recipient's key
+    pub fn algorithm(&self) -> AsymmetricAlgorithm {
+        self.algorithm
+    }
+
+    /// Encoded public key
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// ML-KEM-768 public key, for hybrid recipients
+    pub fn kem_public_key(&self) -> Option<&[u8]> {
+        self.kem_public_key.as_deref()
+    }
+
+    /// Whether data keys are wrapped with ML-KEM as well as ECDH
+    pub fn is_hybrid(&self) -> bool {
+        self.kem_public_key.is_some()
+    }
+
+    /// ID recorded in file headers to name this recipient
+    ///
+    /// Hybrid recipients are named by both public keys.
+    pub fn id(&self) -> [u8; RECIPIENT_ID_LEN] {
+        match &self.kem_public_key {
+            None => recipient_id(&self.public_key),
+            Some(kem_public_key) => recipient_id(&[&self.public_key[..], kem_public_key].concat()),
+        }
+    }
+}
+
+/// ID of the recipient with the given encoded public key
+pub fn recipient_id(public_key: &[u8]) -> [u8; RECIPIENT_ID_LEN] {
+    Sha256::digest(public_key).into()
+}
+
+/// Wrap `data_key` under a symmetric key-encryption key
+pub fn wrap_key(kek: &CryptoKey, data_key: &CryptoKey, aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    crypto::encrypt(kek, data_key.key(), aad)
+}
+
+/// Recover a data key wrapped with [`wrap_key`]
+pub fn unwrap_key(
+    kek: &CryptoKey,
+    wrapped: &[u8],
+    algorithm: Algorithm,
+    aad: &[u8],
+) -> Result<CryptoKey, CryptoError> {
+    CryptoKey::new(crypto::decrypt(kek, wrapped, aad)?, algorithm)
+}
+
+/// Wrap `data_key` to an RSA recipient with RSA-OAEP
+///
+/// `aad` is bound through the OAEP label.
+pub fn wrap_rsa(
+    recipient: &Recipient,
+    data_key: &CryptoKey,
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let public_key = RsaPublicKey::from_public_key_der(&recipient.public_key)
+        .map_err(|_| CryptoError::InvalidRecipient("malformed RSA public key".to_string()))?;
+    public_key
+        .encrypt(&mut rand_core::OsRng, oaep(aad), data_key.key())
+        .map_err(|_| CryptoError::EncryptionFailed)
+}
+
+/// Recover a data key wrapped with [`wrap_rsa`]
+pub fn unwrap_rsa(
+    key: &AsymmetricKey,
+    wrapped: &[u8],
+    algorithm: Algorithm,
+    aad: &[u8],
+) -> Result<CryptoKey, CryptoError> {
+    let private_key = RsaPrivateKey::from_pkcs8_der(key.private_key_bytes())
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    let material = private_key
+        .decrypt_blinded(&mut rand_core::OsRng, oaep(aad), wrapped)
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    CryptoKey::new(material, algorithm)
+}
+
+/// Wrap `data_key` to an EC or X25519 recipient with ECIES
+///
+/// Returns the ephemeral public key (SEC1 uncompressed, or raw for X25519)
+/// and the wrapped data key.
+pub fn wrap_ec(
+    recipient: &Recipient,
+    data_key: &CryptoKey,
+    aad: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+    keys::ecies_encrypt(
+        recipient.algorithm,
+        &recipient.public_key,
+        EC_WRAP_INFO,
+        data_key.key(),
+        aad,
+    )
+    .map_err(|e| match e {
+        KeyError::Crypto(e) => e,
+        KeyError::UnsupportedAlgorithm(e) => CryptoError::UnsupportedAlgorithm(e),
+        _ => CryptoError::InvalidRecipient("malformed EC public key".to_string()),
+    })
+}
+
+/// Recover a data key wrapped with [`wrap_ec`]
+pub fn unwrap_ec(
+    key: &AsymmetricKey,
+    ephemeral: &[u8],
+    wrapped: &[u8],
+    algorithm: Algorithm,
+    aad: &[u8],
+) -> Result<CryptoKey, CryptoError> {
+    let material = keys::ecies_decrypt(key, ephemeral, EC_WRAP_INFO, wrapped, aad)
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    CryptoKey::new(material, algorithm)
+}
+
+/// Wrap `data_key` to a hybrid recipient with ML-KEM-768 and ECDH
+pub fn wrap_hybrid(
+    recipient: &Recipient,
+    data_key: &CryptoKey,
+    aad: &[u8],
+) -> Result<HybridCiphertext, CryptoError> {
+    let kem_public_key = recipient
+        .kem_public_key()
+        .ok_or_else(|| CryptoError::InvalidRecipient("recipient has no ML-KEM key".to_string()))?;
+    keys::hybrid_encrypt(
+        recipient.algorithm,
+        &recipient.public_key,
+        kem_public_key,
+        HYBRID_WRAP_INFO,
+        data_key.key(),
+        aad,
+    )
+    .map_err(|e| match e {
+        KeyError::Crypto(e) => e,
+        KeyError::UnsupportedAlgorithm(e) => CryptoError::UnsupportedAlgorithm(e),
+        _ => CryptoError::InvalidRecipient("malformed hybrid public key".to_string()),
+    })
+}
+
+/// Recover a data key wrapped with [`wrap_hybrid`]
+pub fn unwrap_hybrid(
+    key: &AsymmetricKey,
+    kem_key: &AsymmetricKey,
+    wrapped: &HybridCiphertext,
+    algorithm: Algorithm,
+    aad: &[u8],
+) -> Result<CryptoKey, CryptoError> {
+    let material = keys::hybrid_decrypt(key, kem_key, HYBRID_WRAP_INFO, wrapped, aad)
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    CryptoKey::new(material, algorithm)
+}
+
+/// OAEP padding with SHA-256, binding `aad` through the label
+fn oaep(aad: &[u8]) -> Oaep {
+    Oaep::new_with_label::<Sha256, _>(hex::encode(Sha256::digest(aad)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_key() -> CryptoKey {
+        CryptoKey::generate(Algorithm::ChaCha20Poly1305).unwrap()
+    }
+
+    #[test]
+    fn test_symmetric_wrap() {
+        let kek = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let dek = data_key();
+        let wrapped = wrap_key(&kek, &dek, b"slot").unwrap();
+
+        let unwrapped = unwrap_key(&kek, &wrapped, dek.algorithm(), b"slot").unwrap();
+        assert_eq!(unwrapped.key(), dek.key());
+        assert!(matches!(
+            unwrap_key(&kek, &wrapped, dek.algorithm(), b"other"),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_rsa_wrap() {
+        let key = AsymmetricKey::generate(AsymmetricAlgorithm::Rsa2048).unwrap();
+        let recipient = Recipient::from_key(&key);
+        let dek = data_key();
+        let wrapped = wrap_rsa(&recipient, &dek, b"slot").unwrap();
+
+        let unwrapped = unwrap_rsa(&key, &wrapped, dek.algorithm(), b"slot").unwrap();
+        assert_eq!(unwrapped.key(), dek.key());
+        assert!(unwrap_rsa(&key, &wrapped, dek.algorithm(), b"other").is_err());
+    }
+
+    #[test]
+    fn test_ec_wrap() {
+        for algorithm in [
+            AsymmetricAlgorithm::EcdsaP256,
+            AsymmetricAlgorithm::EcdsaP384,
+            AsymmetricAlgorithm::X25519,
+        ] {
+            let key = AsymmetricKey::generate(algorithm).unwrap();
+            let recipient = Recipient::from_key(&key);
+            let dek = data_key();
+            let (ephemeral, wrapped) = wrap_ec(&recipient, &dek, b"slot").unwrap();
+
+            let unwrapped =
+                unwrap_ec(&key, &ephemeral, &wrapped, dek.algorithm(), b"slot").unwrap();
+            assert_eq!(unwrapped.key(), dek.key());
+
+            let other = AsymmetricKey::generate(algorithm).unwrap();
+            assert!(unwrap_ec(&other, &ephemeral, &wrapped, dek.algorithm(), b"slot").is_err());
+        }
+    }
+
+    #[test]
+    fn test_hybrid_wrap() {
+        let kem = AsymmetricKey::generate(AsymmetricAlgorithm::MlKem768).unwrap();
+        for algorithm in [AsymmetricAlgorithm::X25519, AsymmetricAlgorithm::EcdsaP256] {
+            let key = AsymmetricKey::generate(algorithm).unwrap();
+            let recipient = Recipient::from_key(&key).with_ml_kem(&kem).unwrap();
+            assert!(recipient.is_hybrid());
+            assert_ne!(recipient.id(), Recipient::from_key(&key).id());
+
+            let dek = data_key();
+            let wrapped = wrap_hybrid(&recipient, &dek, b"slot").unwrap();
+            let unwrapped = unwrap_hybrid(&key, &kem, &wrapped, dek.algorithm(), b"slot").unwrap();
+            assert_eq!(unwrapped.key(), dek.key());
+
+            // Both private keys are needed
+            let other_kem = AsymmetricKey::generate(AsymmetricAlgorithm::MlKem768).unwrap();
+            assert!(unwrap_hybrid(&key, &other_kem, &wrapped, dek.algorithm(), b"slot").is_err());
+            let other = AsymmetricKey::generate(algorithm).unwrap();
+            assert!(unwrap_hybrid(&other, &kem, &wrapped, dek.algorithm(), b"slot").is_err());
+            assert!(unwrap_hybrid(&key, &kem, &wrapped, dek.algorithm(), b"other").is_err());
+        }
+
+        // Only X25519 and P-256 pair with ML-KEM, and only with ML-KEM keys
+        let p384 = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP384).unwrap();
+        assert!(Recipient::from_key(&p384).with_ml_kem(&kem).is_err());
+        let x25519 = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        assert!(Recipient::from_key(&x25519).with_ml_kem(&x25519).is_err());
+        assert!(matches!(
+            Recipient::from_public_key_pem(&kem.public_key_pem()),
+            Err(CryptoError::InvalidRecipient(_))
+        ));
+    }
+
+    #[test]
+    fn test_recipient_validation() {
+        let key = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP256).unwrap();
+        let recipient = Recipient::new(
+            AsymmetricAlgorithm::EcdsaP256,
+            key.public_key_bytes().to_vec(),
+        )
+        .unwrap();
+        assert_eq!(recipient, Recipient::from_key(&key));
+        assert_eq!(recipient.id(), recipient_id(key.public_key_bytes()));
+
+        assert!(matches!(
+            Recipient::new(
+                AsymmetricAlgorithm::EcdsaP384,
+                key.public_key_bytes().to_vec()
+            ),
+            Err(CryptoError::InvalidRecipient(_))
+        ));
+        assert!(matches!(
+            Recipient::new(AsymmetricAlgorithm::Rsa2048, vec![1, 2, 3]),
+            Err(CryptoError::InvalidRecipient(_))
+        ));
+
+        // Ed25519 keys only sign
+        let ed25519 = AsymmetricKey::generate(AsymmetricAlgorithm::Ed25519).unwrap();
+        assert!(matches!(
+            Recipient::from_public_key_pem(&ed25519.public_key_pem()),
+            Err(CryptoError::InvalidRecipient(_))
+        ));
+    }
+
+    #[test]
+    fn test_recipient_from_pem() {
+        for algorithm in [
+            AsymmetricAlgorithm::Rsa2048,
+            AsymmetricAlgorithm::EcdsaP256,
+            AsymmetricAlgorithm::EcdsaP384,
+            AsymmetricAlgorithm::X25519,
+        ] {
+            let key = AsymmetricKey::generate(algorithm).unwrap();
+            let recipient = Recipient::from_public_key_pem(&key.public_key_pem()).unwrap();
+            assert_eq!(recipient, Recipient::from_key(&key));
+        }
+
+        assert!(matches!(
+            Recipient::from_public_key_pem(
+                "-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----"
+            ),
+            Err(CryptoError::InvalidRecipient(_))
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+//! Key fingerprints and verification codes
+//!
+//! A fingerprint identifies a key without revealing it, so operators can
+//! confirm out of band that two hosts hold the same key:
+//!
+//! - a symmetric key's fingerprint is HMAC-SHA256 keyed with the key itself
+//!   over a fixed label, which cannot be computed, or checked against a
+//!   guess, without the key
+//! - a public key's fingerprint is SHA-256 of its SubjectPublicKeyInfo (DER)
+//!
+//! The first [`KEY_ID_LEN`] bytes form the key ID recorded in file headers,
+//! and the verification code renders the fingerprint as groups of decimal
+//! digits that are easy to read aloud and compare.
+
+use crate::keys::AsymmetricKey;
+use ring::{digest, hmac};
+use std::fmt;
+
+/// Length of a fingerprint in bytes
+pub const FINGERPRINT_LEN: usize = 32;
+
+/// Length of a key ID in bytes
+pub const KEY_ID_LEN: usize = 8;
+
+/// HMAC message for symmetric key fingerprints
+const SECRET_KEY_LABEL: &[u8] = b"AirGapSync key fingerprint v1";
+
+/// Digit groups in a verification code
+const CODE_GROUPS: usize = 6;
+
+/// Fingerprint bytes consumed by each digit group
+const CODE_GROUP_BYTES: usize = 5;
+
+/// Fingerprint of a symmetric key or public key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; FINGERPRINT_LEN]);
+
+impl Fingerprint {
+    /// Fingerprint of symmetric key material
+    pub fn of_secret_key(key_material: &[u8]) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key_material);
+        Self::from_digest(hmac::sign(&key, SECRET_KEY_LABEL).as_ref())
+    }
+
+    /// Fingerprint of the public half of a key
+    pub fn of_public_key(key: &AsymmetricKey) -> Self {
+        Self::of_public_key_der(&key.public_key_der())
+    }
+
+    /// Fingerprint of a DER-encoded SubjectPublicKeyInfo
+    pub fn of_public_key_der(spki: &[u8]) -> Self {
+        Self::from_digest(digest::digest(&digest::SHA256, spki).as_ref())
+    }
+
+    /// Raw fingerprint bytes
+    pub fn as_bytes(&self) -> &[u8; FINGERPRINT_LEN] {
+        &self.0
+    }
+
+    /// Short ID naming the key in file headers
+    pub fn key_id(&self) -> [u8; KEY_ID_LEN] {
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&self.0[..KEY_ID_LEN]);
+        id
+    }
+
+    /// Key ID as lowercase hex
+    pub fn key_id_hex(&self) -> String {
+        hex::encode(self.key_id())
+    }
+
+    /// Human-comparable code, e.g. `01234 56789 ...`
+    ///
+    /// Each group of five digits is taken from five fingerprint bytes, so the
+    /// code carries about 100 bits of the fingerprint.
+    pub fn verification_code(&self) -> String {
+        self.0
+            .chunks(CODE_GROUP_BYTES)
+            .take(CODE_GROUPS)
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+                format!("{:05}", value % 100_000)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn from_digest(digest: &[u8]) -> Self {
+        let mut bytes = [0u8; FINGERPRINT_LEN];
+        bytes.copy_from_slice(digest);
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    /// Lowercase hex in groups of four characters
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = hex::encode(self.0);
+        for (i, group) in hex.as_bytes().chunks(4).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            // Hex digits are ASCII
+            f.write_str(std::str::from_utf8(group).map_err(|_| fmt::Error)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::AsymmetricAlgorithm;
+
+    #[test]
+    fn test_secret_key_fingerprint() {
+        let fingerprint = Fingerprint::of_secret_key(&[0x42; 32]);
+        // HMAC-SHA256 over the label, checked with Python's hmac module
+        assert_eq!(
+            hex::encode(fingerprint.as_bytes()),
+            "5579245a4cbf1bb1459ac61d1f26198e8260f7cbc07642768c8dc3893079efe2"
+        );
+        assert_eq!(fingerprint.key_id_hex(), "5579245a4cbf1bb1");
+        assert_eq!(
+            fingerprint.verification_code(),
+            "45708 56058 05241 49003 88044 36697"
+        );
+
+        assert_ne!(fingerprint, Fingerprint::of_secret_key(&[0x43; 32]));
+        // Never the plain hash of the key
+        assert_ne!(fingerprint, Fingerprint::of_public_key_der(&[0x42; 32]));
+    }
+
+    #[test]
+    fn test_public_key_fingerprint() {
+        let key = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        let public = AsymmetricKey::from_public_der(&key.public_key_der()).unwrap();
+        assert_eq!(
+            Fingerprint::of_public_key(&key),
+            Fingerprint::of_public_key(&public)
+        );
+        assert_eq!(
+            Fingerprint::of_public_key(&key),
+            Fingerprint::of_public_key_der(&key.public_key_der())
+        );
+
+        let other = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        assert_ne!(
+            Fingerprint::of_public_key(&key),
+            Fingerprint::of_public_key(&other)
+        );
+    }
+
+    #[test]
+    fn test_formatting() {
+        let fingerprint = Fingerprint::of_public_key_der(b"abc");
+        assert_eq!(
+            fingerprint.to_string(),
+            "ba78 16bf 8f01 cfea 4141 40de 5dae 2223 b003 61a3 9617 7a9c b410 ff61 f200 15ad"
+        );
+        assert_eq!(
+            fingerprint.key_id(),
+            [0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea]
+        );
+        assert_eq!(
+            fingerprint.verification_code(),
+            "73807 03137 88322 66979 05876 85760"
+        );
+    }
+}
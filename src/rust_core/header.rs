@@ -0,0 +1,1480 @@
+//! Self-describing encrypted file header
+//!
+//! Every encrypted file starts with a binary header, followed by a segmented
+//! stream (see [`crate::stream`]). The payload is encrypted with a random
+//! per-file data key, and the header carries that data key wrapped once for
+//! every party allowed to read the file (see [`crate::envelope`]).
+//!
+//! ```text
+//! magic        "AGSF"
+//! version      u8
+//! algorithm    u8      (1 = AES-256-GCM, 2 = ChaCha20-Poly1305)
+//! file id      16 random bytes
+//! codec        u8      (0 = none, 1 = zstd)
+//! level        u8      (codec level, 0 for none)
+//! slot count   u8
+//! each slot:
+//!   kind       u8      (1 = device key, 2 = RSA recipient, 3 = EC recipient,
+//!                       4 = hybrid recipient)
+//!   device:    key version u32 || key id (8 bytes) || u8 length || device id (UTF-8)
+//!   RSA:       recipient id (32 bytes)
+//!   EC:        curve u8 (1 = P-256, 2 = P-384, 3 = X25519) || recipient id (32 bytes)
+//!              || u8 length || ephemeral public key
+//!   hybrid:    curve u8 (1 = P-256, 3 = X25519) || recipient id (32 bytes)
+//!              || u8 length || ephemeral public key
+//!              || u16 length || ML-KEM-768 ciphertext
+//!   wrapped    u16 length || wrapped data key
+//! ```
+//!
+//! All integers are big endian. The part before the slots, followed by any
+//! caller context, is the additional data of every payload segment. Each
+//! wrapped key is bound to that same prefix and to its own slot fields, so
+//! slots can be replaced when a key is rotated without touching the payload,
+//! but cannot be relabelled or moved to another file.
+//!
+//! The codec names how the payload was compressed before encryption (see
+//! [`crate::compress`]); [`encrypt_file`] and [`decrypt_file`] compress and
+//! decompress accordingly.
+//!
+//! Format versions 1 to 4 end each device slot with a key derivation byte.
+//! No device key is derived from a password, so it is always 0 (none);
+//! readers require that, and the byte is written back for those versions.
+//!
+//! Format version 1 had no data key: the payload was encrypted directly
+//! under the device key, and the device slot fields (without kind byte or
+//! key ID) followed the algorithm in place of the file ID and slots. Such
+//! headers are still read, with no wrapped key and an all-zero file ID;
+//! `rekey` re-encrypts them into the current format.
+//!
+//! Format version 2 has no key ID in device slots and no codec or level
+//! bytes; its payload is uncompressed. Such headers are read, and encoded
+//! in their own layout when `rekey` replaces the device slot, so the
+//! payload authentication is unchanged.
+//!
+//! Format version 3 adds the key ID, but still has no codec or level bytes,
+//! and is read and re-encoded the same way.
+
+use crate::compress::{Compression, Counted};
+use crate::crypto::{Algorithm, CryptoError, CryptoKey, NonceGenerator};
+use crate::envelope::{self, Recipient, RECIPIENT_ID_LEN};
+use crate::fingerprint::{Fingerprint, KEY_ID_LEN};
+use crate::keys::{AsymmetricAlgorithm, AsymmetricKey, HybridCiphertext};
+use crate::keystore::EncryptionKey;
+use crate::stream;
+use std::io::{Read, Write};
+
+/// Magic bytes at the start of every encrypted file
+pub const MAGIC: &[u8; 4] = b"AGSF";
+
+/// Current header format version
+pub const FORMAT_VERSION: u8 = 5;
+
+/// Length of the random per-file ID
+pub const FILE_ID_LEN: usize = 16;
+
+/// Longest device ID that fits in a header
+pub const MAX_DEVICE_ID_LEN: usize = u8::MAX as usize;
+
+/// Most key slots a header can hold
+pub const MAX_SLOTS: usize = u8::MAX as usize;
+
+/// Party a key slot wraps the data key for
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotKind {
+    /// A stored device key, acting as key-encryption key
+    Device {
+        /// Device the key belongs to
+        device_id: String,
+        /// `KeyMetadata.version` of that key
+        key_version: u32,
+        /// Key ID from the key's [`Fingerprint`] (absent before format
+        /// version 3)
+        key_id: Option<[u8; KEY_ID_LEN]>,
+    },
+    /// An RSA public key, wrapped with RSA-OAEP
+    Rsa {
+        /// SHA-256 of the recipient's public key
+        recipient: [u8; RECIPIENT_ID_LEN],
+    },
+    /// A P-256, P-384 or X25519 public key, wrapped with ephemeral ECDH
+    Ec {
+        /// Curve of the recipient's key
+        curve: AsymmetricAlgorithm,
+        /// SHA-256 of the recipient's public key
+        recipient: [u8; RECIPIENT_ID_LEN],
+        /// Ephemeral public key (SEC1) used for the agreement
+        ephemeral: Vec<u8>,
+    },
+    /// An X25519 or P-256 key paired with an ML-KEM-768 key, wrapped with
+    /// both ephemeral ECDH and ML-KEM encapsulation
+    Hybrid {
+        /// Curve of the recipient's ECDH key
+        curve: AsymmetricAlgorithm,
+        /// SHA-256 of the recipient's two public keys
+        recipient: [u8; RECIPIENT_ID_LEN],
+        /// Ephemeral public key used for the agreement
+        ephemeral: Vec<u8>,
+        /// ML-KEM-768 ciphertext encapsulated to the recipient
+        kem_ciphertext: Vec<u8>,
+    },
+}
+
+/// Data key wrapped for one party
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySlot {
+    /// Who can unwrap the key
+    pub kind: SlotKind,
+    /// Wrapped data key
+    pub wrapped: Vec<u8>,
+}
+
+/// Header identifying the payload algorithm and the wrapped data keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    /// Format version the header is encoded in
+    pub version: u8,
+    /// Cipher used for the payload
+    pub algorithm: Algorithm,
+    /// Random ID binding the key slots to this file
+    pub file_id: [u8; FILE_ID_LEN],
+    /// Compression applied to the payload before encryption
+    pub compression: Compression,
+    /// Data key, wrapped for each party that may read the file
+    pub slots: Vec<KeySlot>,
+}
+
+impl FileHeader {
+    /// Empty header for a new file
+    pub fn new(algorithm: Algorithm) -> Result<Self, CryptoError> {
+        let mut file_id = [0u8; FILE_ID_LEN];
+        file_id.copy_from_slice(&NonceGenerator::new().generate(FILE_ID_LEN)?);
+        Ok(Self {
+            version: FORMAT_VERSION,
+            algorithm,
+            file_id,
+            compression: Compression::None,
+            slots: Vec::new(),
+        })
+    }
+
+    /// Header and fresh data key for a file readable by a device key and
+    /// any number of public-key recipients
+    ///
+    /// The payload uses the same algorithm as the device key.
+    pub fn seal(
+        key: &EncryptionKey,
+        recipients: &[Recipient],
+    ) -> Result<(Self, CryptoKey), CryptoError> {
+        Self::seal_compressed(key, recipients, Compression::None)
+    }
+
+    /// Like [`FileHeader::seal`], for a payload compressed with `compression`
+    ///
+    /// The compression is part of the authenticated header, so it must be
+    /// chosen before any key slot is added.
+    pub fn seal_compressed(
+        key: &EncryptionKey,
+        recipients: &[Recipient],
+        compression: Compression,
+    ) -> Result<(Self, CryptoKey), CryptoError> {
+        let algorithm = key.to_crypto_key()?.algorithm();
+        let data_key = CryptoKey::generate(algorithm)?;
+        let mut header = Self::new(algorithm)?;
+        header.compression = compression;
+        header.add_device_key(&data_key, key)?;
+        for recipient in recipients {
+            header.add_recipient(&data_key, recipient)?;
+        }
+        Ok((header, data_key))
+    }
+
+    /// Add a slot wrapping `data_key` under a stored device key
+    pub fn add_device_key(
+        &mut self,
+        data_key: &CryptoKey,
+        key: &EncryptionKey,
+    ) -> Result<(), CryptoError> {
+        let kind = self.device_kind(key);
+        let wrapped = envelope::wrap_key(&key.to_crypto_key()?, data_key, &self.slot_aad(&kind)?)?;
+        self.push_slot(KeySlot { kind, wrapped })
+    }
+
+    /// Add a slot wrapping `data_key` to a public-key recipient
+    pub fn add_recipient(
+        &mut self,
+        data_key: &CryptoKey,
+        recipient: &Recipient,
+    ) -> Result<(), CryptoError> {
+        if recipient.is_hybrid() {
+            let mut kind = SlotKind::Hybrid {
+                curve: recipient.algorithm(),
+                recipient: recipient.id(),
+                ephemeral: Vec::new(),
+                kem_ciphertext: Vec::new(),
+            };
+            // As for EC slots, the ephemeral key and ML-KEM ciphertext are
+            // bound through the key derivation
+            let wrapped = envelope::wrap_hybrid(recipient, data_key, &self.slot_aad(&kind)?)?;
+            if let SlotKind::Hybrid {
+                ephemeral,
+                kem_ciphertext,
+                ..
+            } = &mut kind
+            {
+                *ephemeral = wrapped.ephemeral_public_key;
+                *kem_ciphertext = wrapped.kem_ciphertext;
+            }
+            return self.push_slot(KeySlot {
+                kind,
+                wrapped: wrapped.ciphertext,
+            });
+        }
+
+        let slot = match recipient.algorithm() {
+            AsymmetricAlgorithm::Rsa2048 | AsymmetricAlgorithm::Rsa4096 => {
+                let kind = SlotKind::Rsa {
+                    recipient: recipient.id(),
+                };
+                let wrapped = envelope::wrap_rsa(recipient, data_key, &self.slot_aad(&kind)?)?;
+                KeySlot { kind, wrapped }
+            }
+            curve @ (AsymmetricAlgorithm::EcdsaP256
+            | AsymmetricAlgorithm::EcdsaP384
+            | AsymmetricAlgorithm::X25519) => {
+                let mut kind = SlotKind::Ec {
+                    curve,
+                    recipient: recipient.id(),
+                    ephemeral: Vec::new(),
+                };
+                // The ephemeral key is not part of the slot AAD; it is bound
+                // through the key derivation instead
+                let (ephemeral, wrapped) =
+                    envelope::wrap_ec(recipient, data_key, &self.slot_aad(&kind)?)?;
+                if let SlotKind::Ec {
+                    ephemeral: slot, ..
+                } = &mut kind
+                {
+                    *slot = ephemeral;
+                }
+                KeySlot { kind, wrapped }
+            }
+            other @ (AsymmetricAlgorithm::Ed25519 | AsymmetricAlgorithm::MlKem768) => {
+                return Err(CryptoError::InvalidRecipient(format!(
+                    "{} keys cannot be encrypted to on their own",
+                    other.as_str()
+                )))
+            }
+        };
+        self.push_slot(slot)
+    }
+
+    /// Device key that wraps the data key, as `(device ID, key version)`
+    ///
+    /// Returns the first device slot; files written by this crate have
+    /// exactly one.
+    pub fn device_key(&self) -> Option<(&str, u32)> {
+        self.slots.iter().find_map(|slot| match &slot.kind {
+            SlotKind::Device {
+                device_id,
+                key_version,
+                ..
+            } => Some((device_id.as_str(), *key_version)),
+            _ => None,
+        })
+    }
+
+    /// Unwrap the data key with a stored device key
+    ///
+    /// For a format version 1 header, the device key itself is the data key.
+    pub fn open_with_device_key(&self, key: &EncryptionKey) -> Result<CryptoKey, CryptoError> {
+        let slot = &self.slots[self.device_slot(key)?];
+        if self.version == 1 {
+            return key.to_crypto_key();
+        }
+        envelope::unwrap_key(
+            &key.to_crypto_key()?,
+            &slot.wrapped,
+            self.algorithm,
+            &self.slot_aad(&slot.kind)?,
+        )
+    }
+
+    /// Unwrap the data key with a recipient's private key
+    pub fn open_with_private_key(&self, key: &AsymmetricKey) -> Result<CryptoKey, CryptoError> {
+        let id = envelope::recipient_id(key.public_key_bytes());
+        for slot in &self.slots {
+            let aad = self.slot_aad(&slot.kind)?;
+            match &slot.kind {
+                SlotKind::Rsa { recipient } if *recipient == id => {
+                    return envelope::unwrap_rsa(key, &slot.wrapped, self.algorithm, &aad);
+                }
+                SlotKind::Ec {
+                    recipient,
+                    ephemeral,
+                    ..
+                } if *recipient == id => {
+                    return envelope::unwrap_ec(
+                        key,
+                        ephemeral,
+                        &slot.wrapped,
+                        self.algorithm,
+                        &aad,
+                    );
+                }
+                _ => {}
+            }
+        }
+        Err(CryptoError::KeyMismatch(
+            "file is not encrypted to this key".to_string(),
+        ))
+    }
+
+    /// Unwrap the data key with a hybrid recipient's ECDH and ML-KEM-768
+    /// private keys
+    pub fn open_with_hybrid_key(
+        &self,
+        key: &AsymmetricKey,
+        kem_key: &AsymmetricKey,
+    ) -> Result<CryptoKey, CryptoError> {
+        let id = Recipient::from_key(key).with_ml_kem(kem_key)?.id();
+        for slot in &self.slots {
+            if let SlotKind::Hybrid {
+                recipient,
+                ephemeral,
+                kem_ciphertext,
+                ..
+            } = &slot.kind
+            {
+                if *recipient != id {
+                    continue;
+                }
+                let wrapped = HybridCiphertext {
+                    ephemeral_public_key: ephemeral.clone(),
+                    kem_ciphertext: kem_ciphertext.clone(),
+                    ciphertext: slot.wrapped.clone(),
+                };
+                return envelope::unwrap_hybrid(
+                    key,
+                    kem_key,
+                    &wrapped,
+                    self.algorithm,
+                    &self.slot_aad(&slot.kind)?,
+                );
+            }
+        }
+        Err(CryptoError::KeyMismatch(
+            "file is not encrypted to this key pair".to_string(),
+        ))
+    }
+
+    /// Re-wrap the data key from an old device key to a new one
+    ///
+    /// Replaces the slot for `old` in place; other slots and the format
+    /// version are unchanged.
+    pub fn rewrap_device_key(
+        &mut self,
+        old: &EncryptionKey,
+        new: &EncryptionKey,
+    ) -> Result<(), CryptoError> {
+        if self.version == 1 {
+            return Err(CryptoError::InvalidHeader(
+                "format version 1 files have no data key to re-wrap".to_string(),
+            ));
+        }
+        let data_key = self.open_with_device_key(old)?;
+        let index = self.device_slot(old)?;
+
+        let kind = self.device_kind(new);
+        let wrapped = envelope::wrap_key(&new.to_crypto_key()?, &data_key, &self.slot_aad(&kind)?)?;
+        self.slots[index] = KeySlot { kind, wrapped };
+        Ok(())
+    }
+
+    /// Encode the header
+    pub fn encode(&self) -> Result<Vec<u8>, CryptoError> {
+        let mut out = self.prefix()?;
+        if self.version == 1 {
+            return Ok(out);
+        }
+        out.push(self.slots.len() as u8);
+        for slot in &self.slots {
+            encode_kind(&slot.kind, self.version, &mut out)?;
+            match &slot.kind {
+                SlotKind::Ec { ephemeral, .. } => {
+                    push_short(&mut out, ephemeral, "ephemeral key")?;
+                }
+                SlotKind::Hybrid {
+                    ephemeral,
+                    kem_ciphertext,
+                    ..
+                } => {
+                    push_short(&mut out, ephemeral, "ephemeral key")?;
+                    push_long(&mut out, kem_ciphertext, "ML-KEM ciphertext")?;
+                }
+                _ => {}
+            }
+            push_long(&mut out, &slot.wrapped, "wrapped key")?;
+        }
+        Ok(out)
+    }
+
+    /// Read and parse a header from the start of `reader`
+    ///
+    /// The reader is left positioned at the first byte of the payload.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, CryptoError> {
+        let mut magic = [0u8; 4];
+        read_exact(reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(CryptoError::InvalidHeader(
+                "not an AirGapSync encrypted file".to_string(),
+            ));
+        }
+
+        let version = read_u8(reader)?;
+        if !is_readable(version) {
+            return Err(CryptoError::InvalidHeader(format!(
+                "unsupported format version {version}"
+            )));
+        }
+
+        let algorithm = algorithm_from_id(read_u8(reader)?)?;
+        if version == 1 {
+            return Ok(Self {
+                version,
+                algorithm,
+                file_id: [0; FILE_ID_LEN],
+                compression: Compression::None,
+                slots: vec![KeySlot {
+                    kind: read_device(reader, version)?,
+                    wrapped: Vec::new(),
+                }],
+            });
+        }
+
+        let mut file_id = [0u8; FILE_ID_LEN];
+        read_exact(reader, &mut file_id)?;
+        let compression = if version >= 4 {
+            compression_from_ids(read_u8(reader)?, read_u8(reader)?)?
+        } else {
+            Compression::None
+        };
+
+        let count = read_u8(reader)?;
+        let mut slots = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let kind = read_kind(reader, version)?;
+            let wrapped = read_long(reader)?;
+            slots.push(KeySlot { kind, wrapped });
+        }
+
+        Ok(Self {
+            version,
+            algorithm,
+            file_id,
+            compression,
+            slots,
+        })
+    }
+
+    /// Index of the slot for a device key, checking the key version and ID
+    fn device_slot(&self, key: &EncryptionKey) -> Result<usize, CryptoError> {
+        let mut versions = Vec::new();
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let SlotKind::Device {
+                device_id,
+                key_version,
+                key_id,
+                ..
+            } = &slot.kind
+            {
+                if *device_id != key.metadata.device_id {
+                    continue;
+                }
+                if *key_version == key.metadata.version {
+                    let expected = Fingerprint::of_secret_key(&key.key_material).key_id();
+                    match key_id {
+                        Some(key_id) if *key_id != expected => {
+                            return Err(CryptoError::KeyMismatch(format!(
+                                "file was encrypted with key {}, not {} (same device and version)",
+                                hex::encode(key_id),
+                                hex::encode(expected)
+                            )));
+                        }
+                        _ => return Ok(index),
+                    }
+                }
+                versions.push(key_version.to_string());
+            }
+        }
+
+        Err(CryptoError::KeyMismatch(if versions.is_empty() {
+            format!(
+                "file is not encrypted for device {}",
+                key.metadata.device_id
+            )
+        } else {
+            format!(
+                "file was encrypted with key version {}, not {}",
+                versions.join(", "),
+                key.metadata.version
+            )
+        }))
+    }
+
+    /// Device slot fields for `key` in this header's format version
+    fn device_kind(&self, key: &EncryptionKey) -> SlotKind {
+        SlotKind::Device {
+            device_id: key.metadata.device_id.clone(),
+            key_version: key.metadata.version,
+            key_id: (self.version >= 3)
+                .then(|| Fingerprint::of_secret_key(&key.key_material).key_id()),
+        }
+    }
+
+    /// Add a slot, enforcing the slot limit
+    fn push_slot(&mut self, slot: KeySlot) -> Result<(), CryptoError> {
+        if self.version == 1 {
+            return Err(CryptoError::InvalidHeader(
+                "format version 1 headers have no key slots".to_string(),
+            ));
+        }
+        if self.slots.len() >= MAX_SLOTS {
+            return Err(CryptoError::InvalidHeader(format!(
+                "at most {MAX_SLOTS} key slots"
+            )));
+        }
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    /// Check that a data key uses the algorithm this header names
+    fn verify_algorithm(&self, key: &CryptoKey) -> Result<(), CryptoError> {
+        if key.algorithm() != self.algorithm {
+            return Err(CryptoError::KeyMismatch(format!(
+                "file was encrypted with {:?}, key is {:?}",
+                self.algorithm,
+                key.algorithm()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fixed part of the header, before the key slots
+    ///
+    /// For format version 1 this is the whole header.
+    fn prefix(&self) -> Result<Vec<u8>, CryptoError> {
+        let mut out = Vec::with_capacity(128);
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        out.push(algorithm_id(self.algorithm));
+        if self.version == 1 {
+            match self.slots.as_slice() {
+                [KeySlot {
+                    kind: kind @ SlotKind::Device { key_id: None, .. },
+                    ..
+                }] => {
+                    // The device slot fields, without the kind byte
+                    let mut slot = Vec::new();
+                    encode_kind(kind, self.version, &mut slot)?;
+                    out.extend_from_slice(&slot[1..]);
+                }
+                _ => {
+                    return Err(CryptoError::InvalidHeader(
+                        "format version 1 headers have exactly one device key".to_string(),
+                    ))
+                }
+            }
+            return Ok(out);
+        }
+        if !is_readable(self.version) {
+            return Err(CryptoError::InvalidHeader(format!(
+                "cannot encode format version {}",
+                self.version
+            )));
+        }
+        out.extend_from_slice(&self.file_id);
+        if self.version >= 4 {
+            out.extend_from_slice(&compression_ids(self.compression));
+        } else if self.compression != Compression::None {
+            return Err(CryptoError::InvalidHeader(format!(
+                "format version {} cannot record compression",
+                self.version
+            )));
+        }
+        Ok(out)
+    }
+
+    /// Additional data for a wrapped key: the prefix, then the slot fields
+    fn slot_aad(&self, kind: &SlotKind) -> Result<Vec<u8>, CryptoError> {
+        let mut aad = self.prefix()?;
+        encode_kind(kind, self.version, &mut aad)?;
+        Ok(aad)
+    }
+
+    /// Additional data for the payload: the prefix, then `context`
+    fn payload_aad(&self, context: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut aad = self.prefix()?;
+        aad.extend_from_slice(context);
+        Ok(aad)
+    }
+}
+
+/// Write `header` and encrypt everything from `reader` after it
+///
+/// `data_key` is the key the header's slots wrap. `context` is optional
+/// extra additional data the decryptor must supply again, such as a file's
+/// path on the device. The payload is compressed first as the header's
+/// compression says. Returns the number of plaintext bytes consumed.
+pub fn encrypt_file<R: Read, W: Write>(
+    data_key: &CryptoKey,
+    header: &FileHeader,
+    reader: &mut R,
+    mut writer: W,
+    segment_size: usize,
+    context: &[u8],
+) -> Result<u64, CryptoError> {
+    if header.version != FORMAT_VERSION {
+        return Err(CryptoError::InvalidHeader(format!(
+            "new files are written in format version {FORMAT_VERSION}"
+        )));
+    }
+    header.verify_algorithm(data_key)?;
+    writer.write_all(&header.encode()?)?;
+    let mut counted = Counted::new(reader);
+    stream::encrypt_stream(
+        data_key,
+        &mut header.compression.compress(&mut counted)?,
+        writer,
+        segment_size,
+        &header.payload_aad(context)?,
+    )?;
+    Ok(counted.count())
+}
+
+/// Decrypt the payload following a header read with [`FileHeader::read_from`]
+///
+/// `data_key` comes from one of the header's `open_with_*` methods. Returns
+/// the number of plaintext bytes written. On error, `writer` may already
+/// hold plaintext from earlier segments and must be discarded.
+pub fn decrypt_file<R: Read, W: Write>(
+    data_key: &CryptoKey,
+    header: &FileHeader,
+    reader: R,
+    writer: &mut W,
+    context: &[u8],
+) -> Result<u64, CryptoError> {
+    header.verify_algorithm(data_key)?;
+    let mut counted = Counted::new(writer);
+    let mut decompressed = header.compression.decompress(&mut counted)?;
+    stream::decrypt_stream(
+        data_key,
+        reader,
+        &mut decompressed,
+        &header.payload_aad(context)?,
+    )?;
+    decompressed.flush()?;
+    drop(decompressed);
+    Ok(counted.count())
+}
+
+/// Reader over the decrypted payload following a header
+///
+/// Like [`decrypt_file`], but lets the plaintext be consumed as a stream.
+pub fn decryptor<'a, R: Read + 'a>(
+    data_key: &CryptoKey,
+    header: &FileHeader,
+    reader: R,
+    context: &[u8],
+) -> Result<Box<dyn Read + 'a>, CryptoError> {
+    header.verify_algorithm(data_key)?;
+    let decryptor = stream::StreamDecryptor::new(data_key, reader, &header.payload_aad(context)?)?;
+    Ok(header.compression.decompress_reader(decryptor)?)
+}
+
+/// Encoded length of `header`
+pub fn encoded_len(header: &FileHeader) -> usize {
+    if header.version == 1 {
+        // The device slot fields, without kind byte or wrapped key, are the
+        // rest of the header
+        let fields: usize = header
+            .slots
+            .iter()
+            .map(|slot| kind_len(&slot.kind, header.version))
+            .sum();
+        return MAGIC.len() + 1 + 1 + fields;
+    }
+    let slots: usize = header
+        .slots
+        .iter()
+        .map(|slot| 1 + kind_len(&slot.kind, header.version) + 2 + slot.wrapped.len())
+        .sum();
+    let compression = if header.version >= 4 { 2 } else { 0 };
+    MAGIC.len() + 1 + 1 + FILE_ID_LEN + compression + 1 + slots
+}
+
+/// Encoded length of a slot's fields after its kind byte in format
+/// `version`
+fn kind_len(kind: &SlotKind, version: u8) -> usize {
+    match kind {
+        SlotKind::Device {
+            device_id, key_id, ..
+        } => {
+            let key_id = if key_id.is_some() { KEY_ID_LEN } else { 0 };
+            let kdf = if version < 5 { 1 } else { 0 };
+            4 + key_id + 1 + device_id.len() + kdf
+        }
+        SlotKind::Rsa { .. } => RECIPIENT_ID_LEN,
+        SlotKind::Ec { ephemeral, .. } => 1 + RECIPIENT_ID_LEN + 1 + ephemeral.len(),
+        SlotKind::Hybrid {
+            ephemeral,
+            kem_ciphertext,
+            ..
+        } => 1 + RECIPIENT_ID_LEN + 1 + ephemeral.len() + 2 + kem_ciphertext.len(),
+    }
+}
+
+/// Encode a slot's kind and identifying fields in format `version`
+///
+/// The ephemeral key (and ML-KEM ciphertext) of EC and hybrid slots is
+/// left out; [`FileHeader::encode`] appends it.
+fn encode_kind(kind: &SlotKind, version: u8, out: &mut Vec<u8>) -> Result<(), CryptoError> {
+    match kind {
+        SlotKind::Device {
+            device_id,
+            key_version,
+            key_id,
+        } => {
+            if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LEN {
+                return Err(CryptoError::InvalidHeader(format!(
+                    "device ID must be 1-{MAX_DEVICE_ID_LEN} bytes"
+                )));
+            }
+            out.push(1);
+            out.extend_from_slice(&key_version.to_be_bytes());
+            match (key_id, version >= 3) {
+                (Some(key_id), true) => out.extend_from_slice(key_id),
+                (None, false) => {}
+                _ => {
+                    return Err(CryptoError::InvalidHeader(format!(
+                        "device slot key ID does not match format version {version}"
+                    )))
+                }
+            }
+            push_short(out, device_id.as_bytes(), "device ID")?;
+            if version < 5 {
+                // No key derivation
+                out.push(0);
+            }
+        }
+        SlotKind::Rsa { recipient } => {
+            out.push(2);
+            out.extend_from_slice(recipient);
+        }
+        SlotKind::Ec {
+            curve, recipient, ..
+        } => {
+            out.push(3);
+            out.push(curve_id(*curve)?);
+            out.extend_from_slice(recipient);
+        }
+        SlotKind::Hybrid {
+            curve, recipient, ..
+        } => {
+            out.push(4);
+            out.push(curve_id(*curve)?);
+            out.extend_from_slice(recipient);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a slot's kind and identifying fields in format `version`
+fn read_kind<R: Read>(reader: &mut R, version: u8) -> Result<SlotKind, CryptoError> {
+    match read_u8(reader)? {
+        1 => read_device(reader, version),
+        2 => {
+            let mut recipient = [0u8; RECIPIENT_ID_LEN];
+            read_exact(reader, &mut recipient)?;
+            Ok(SlotKind::Rsa { recipient })
+        }
+        3 => {
+            let curve = curve_from_id(read_u8(reader)?)?;
+            let mut recipient = [0u8; RECIPIENT_ID_LEN];
+            read_exact(reader, &mut recipient)?;
+            Ok(SlotKind::Ec {
+                curve,
+                recipient,
+                ephemeral: read_short(reader)?,
+            })
+        }
+        4 => {
+            let curve = curve_from_id(read_u8(reader)?)?;
+            let mut recipient = [0u8; RECIPIENT_ID_LEN];
+            read_exact(reader, &mut recipient)?;
+            Ok(SlotKind::Hybrid {
+                curve,
+                recipient,
+                ephemeral: read_short(reader)?,
+                kem_ciphertext: read_long(reader)?,
+            })
+        }
+        other => Err(CryptoError::InvalidHeader(format!(
+            "unknown key slot kind {other}"
+        ))),
+    }
+}
+
+/// Parse the fields of a device slot after its kind byte
+fn read_device<R: Read>(reader: &mut R, version: u8) -> Result<SlotKind, CryptoError> {
+    let key_version = read_u32(reader)?;
+    let key_id = if version >= 3 {
+        let mut key_id = [0u8; KEY_ID_LEN];
+        read_exact(reader, &mut key_id)?;
+        Some(key_id)
+    } else {
+        None
+    };
+    let device_id = String::from_utf8(read_short(reader)?)
+        .map_err(|_| CryptoError::InvalidHeader("device ID is not UTF-8".to_string()))?;
+    if device_id.is_empty() {
+        return Err(CryptoError::InvalidHeader("empty device ID".to_string()));
+    }
+
+    if version < 5 {
+        match read_u8(reader)? {
+            0 => {}
+            other => {
+                return Err(CryptoError::InvalidHeader(format!(
+                    "unsupported key derivation {other} for a device key"
+                )))
+            }
+        }
+    }
+
+    Ok(SlotKind::Device {
+        device_id,
+        key_version,
+        key_id,
+    })
+}
+
+/// Whether headers in format `version` can be read
+fn is_readable(version: u8) -> bool {
+    (1..=FORMAT_VERSION).contains(&version)
+}
+
+fn curve_id(curve: AsymmetricAlgorithm) -> Result<u8, CryptoError> {
+    match curve {
+        AsymmetricAlgorithm::EcdsaP256 => Ok(1),
+        AsymmetricAlgorithm::EcdsaP384 => Ok(2),
+        AsymmetricAlgorithm::X25519 => Ok(3),
+        other => Err(CryptoError::InvalidHeader(format!(
+            "{} is not an EC curve",
+            other.as_str()
+        ))),
+    }
+}
+
+fn curve_from_id(id: u8) -> Result<AsymmetricAlgorithm, CryptoError> {
+    match id {
+        1 => Ok(AsymmetricAlgorithm::EcdsaP256),
+        2 => Ok(AsymmetricAlgorithm::EcdsaP384),
+        3 => Ok(AsymmetricAlgorithm::X25519),
+        other => Err(CryptoError::InvalidHeader(format!("unknown curve {other}"))),
+    }
+}
+
+/// Append `bytes` with a u8 length prefix
+fn push_short(out: &mut Vec<u8>, bytes: &[u8], what: &str) -> Result<(), CryptoError> {
+    if bytes.len() > u8::MAX as usize {
+        return Err(CryptoError::InvalidHeader(format!("{what} too long")));
+    }
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Append `bytes` with a u16 length prefix
+fn push_long(out: &mut Vec<u8>, bytes: &[u8], what: &str) -> Result<(), CryptoError> {
+    if bytes.len() > u16::MAX as usize {
+        return Err(CryptoError::InvalidHeader(format!("{what} too long")));
+    }
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn algorithm_id(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::Aes256Gcm => 1,
+        Algorithm::ChaCha20Poly1305 => 2,
+    }
+}
+
+fn algorithm_from_id(id: u8) -> Result<Algorithm, CryptoError> {
+    match id {
+        1 => Ok(Algorithm::Aes256Gcm),
+        2 => Ok(Algorithm::ChaCha20Poly1305),
+        other => Err(CryptoError::InvalidHeader(format!(
+            "unknown algorithm {other}"
+        ))),
+    }
+}
+
+fn compression_ids(compression: Compression) -> [u8; 2] {
+    match compression {
+        Compression::None => [0, 0],
+        Compression::Zstd { level } => [1, level],
+    }
+}
+
+fn compression_from_ids(codec: u8, level: u8) -> Result<Compression, CryptoError> {
+    match (codec, level) {
+        (0, 0) => Ok(Compression::None),
+        (1, 1..=22) => Ok(Compression::Zstd { level }),
+        (0 | 1, level) => Err(CryptoError::InvalidHeader(format!(
+            "invalid compression level {level}"
+        ))),
+        (other, _) => Err(CryptoError::InvalidHeader(format!(
+            "unknown compression codec {other}"
+        ))),
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), CryptoError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => {
+            CryptoError::InvalidHeader("truncated header".to_string())
+        }
+        _ => CryptoError::Io(e),
+    })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, CryptoError> {
+    let mut byte = [0u8; 1];
+    read_exact(reader, &mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, CryptoError> {
+    let mut bytes = [0u8; 2];
+    read_exact(reader, &mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, CryptoError> {
+    let mut bytes = [0u8; 4];
+    read_exact(reader, &mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Read a u8 length prefix and that many bytes
+fn read_short<R: Read>(reader: &mut R) -> Result<Vec<u8>, CryptoError> {
+    let mut bytes = vec![0u8; read_u8(reader)? as usize];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read a u16 length prefix and that many bytes
+fn read_long<R: Read>(reader: &mut R) -> Result<Vec<u8>, CryptoError> {
+    let mut bytes = vec![0u8; read_u16(reader)? as usize];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::generate_key;
+
+    fn seal(key: &EncryptionKey, plaintext: &[u8], context: &[u8]) -> Vec<u8> {
+        let (header, data_key) = FileHeader::seal(key, &[]).unwrap();
+        let mut out = Vec::new();
+        encrypt_file(
+            &data_key,
+            &header,
+            &mut &plaintext[..],
+            &mut out,
+            64,
+            context,
+        )
+        .unwrap();
+        out
+    }
+
+    fn open(key: &EncryptionKey, file: &[u8], context: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut reader = file;
+        let header = FileHeader::read_from(&mut reader)?;
+        let data_key = header.open_with_device_key(key)?;
+        let mut out = Vec::new();
+        decrypt_file(&data_key, &header, reader, &mut out, context)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut header = FileHeader::new(Algorithm::ChaCha20Poly1305).unwrap();
+        header.slots.push(KeySlot {
+            kind: SlotKind::Device {
+                device_id: "USB001".to_string(),
+                key_version: 7,
+                key_id: Some([8; KEY_ID_LEN]),
+            },
+            wrapped: vec![1; 60],
+        });
+        header.slots.push(KeySlot {
+            kind: SlotKind::Rsa {
+                recipient: [2; RECIPIENT_ID_LEN],
+            },
+            wrapped: vec![3; 256],
+        });
+        header.slots.push(KeySlot {
+            kind: SlotKind::Ec {
+                curve: AsymmetricAlgorithm::EcdsaP384,
+                recipient: [4; RECIPIENT_ID_LEN],
+                ephemeral: vec![5; 97],
+            },
+            wrapped: vec![6; 60],
+        });
+
+        let encoded = header.encode().unwrap();
+        assert_eq!(encoded.len(), encoded_len(&header));
+        assert_eq!(FileHeader::read_from(&mut &encoded[..]).unwrap(), header);
+        assert_eq!(header.device_key(), Some(("USB001", 7)));
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let plaintext = vec![5u8; 200];
+        let file = seal(&key, &plaintext, b"");
+
+        let header = FileHeader::read_from(&mut &file[..]).unwrap();
+        assert_eq!(header.device_key(), Some(("USB001", 1)));
+        assert_eq!(header.algorithm, Algorithm::Aes256Gcm);
+        assert_eq!(open(&key, &file, b"").unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_compressed_file_round_trip() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let plaintext = b"compress me ".repeat(1000);
+        let compression = Compression::Zstd { level: 19 };
+        let (header, data_key) = FileHeader::seal_compressed(&key, &[], compression).unwrap();
+        let mut file = Vec::new();
+        let read =
+            encrypt_file(&data_key, &header, &mut &plaintext[..], &mut file, 64, b"").unwrap();
+        assert_eq!(read, plaintext.len() as u64);
+        assert!(file.len() < plaintext.len() / 4);
+
+        // Readers learn the codec from the header alone
+        let parsed = FileHeader::read_from(&mut &file[..]).unwrap();
+        assert_eq!(parsed.compression, compression);
+        assert_eq!(encoded_len(&parsed), parsed.encode().unwrap().len());
+        assert_eq!(open(&key, &file, b"").unwrap(), plaintext);
+
+        let mut reader = &file[..];
+        let parsed = FileHeader::read_from(&mut reader).unwrap();
+        let mut out = Vec::new();
+        decryptor(&data_key, &parsed, reader, b"")
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, plaintext);
+
+        // The codec is authenticated with the rest of the prefix
+        let mut tampered = file.clone();
+        tampered[MAGIC.len() + 2 + FILE_ID_LEN + 1] = 3;
+        assert!(open(&key, &tampered, b"").is_err());
+        tampered[MAGIC.len() + 2 + FILE_ID_LEN] = 9;
+        assert!(matches!(
+            FileHeader::read_from(&mut &tampered[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_each_file_has_own_data_key() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let (first, first_key) = FileHeader::seal(&key, &[]).unwrap();
+        let (second, second_key) = FileHeader::seal(&key, &[]).unwrap();
+        assert_ne!(first.file_id, second.file_id);
+        assert_ne!(first_key.key(), second_key.key());
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let key = generate_key("ChaCha20", "USB001").unwrap();
+        let file = seal(&key, b"secret", b"");
+        let data_key = {
+            let header = FileHeader::read_from(&mut &file[..]).unwrap();
+            header.open_with_device_key(&key).unwrap()
+        };
+
+        // Rewriting the file ID is caught by the payload AAD even when the
+        // reader already holds the data key
+        let mut tampered = file.clone();
+        tampered[6] ^= 1;
+        let mut reader = &tampered[..];
+        let header = FileHeader::read_from(&mut reader).unwrap();
+        let mut out = Vec::new();
+        assert!(matches!(
+            decrypt_file(&data_key, &header, reader, &mut out, b""),
+            Err(CryptoError::DecryptionFailed)
+        ));
+
+        // Relabelling a slot with another key version breaks the unwrap
+        let mut header = FileHeader::read_from(&mut &file[..]).unwrap();
+        if let SlotKind::Device { key_version, .. } = &mut header.slots[0].kind {
+            *key_version = 2;
+        }
+        let mut relabelled = key.clone();
+        relabelled.metadata.version = 2;
+        assert!(matches!(
+            header.open_with_device_key(&relabelled),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    /// Device key the format version fixtures were written with
+    fn fixture_key() -> EncryptionKey {
+        let mut key = generate_key("AES-256", "USB001").unwrap();
+        key.key_material = vec![7; 32];
+        key.metadata.version = 1;
+        key
+    }
+
+    #[test]
+    fn test_reads_format_version_1() {
+        // Written before envelope encryption: the payload is encrypted
+        // directly under the device key
+        let file = hex::decode(concat!(
+            "41475346010100000001065553423030310020c5046d407269000000402953ca",
+            "161bf063dce1cb3b1a18f1d25db2646f21f48b9663e5af80f0f48aa3d46e1704",
+            "fd7329fbe372fa6e6b83",
+        ))
+        .unwrap();
+        let key = fixture_key();
+        assert_eq!(
+            open(&key, &file, b"docs/a.txt").unwrap(),
+            b"written by an earlier release"
+        );
+        assert!(matches!(
+            open(&key, &file, b"docs/b.txt"),
+            Err(CryptoError::DecryptionFailed)
+        ));
+
+        let mut header = FileHeader::read_from(&mut &file[..]).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.device_key(), Some(("USB001", 1)));
+        let encoded = header.encode().unwrap();
+        assert_eq!(encoded, file[..encoded.len()]);
+        assert_eq!(encoded_len(&header), encoded.len());
+
+        // There is no data key to re-wrap, and no new file is written in
+        // the old format
+        let mut rotated = key.clone();
+        rotated.metadata.version = 2;
+        assert!(matches!(
+            open(&rotated, &file, b"docs/a.txt"),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+        assert!(matches!(
+            header.rewrap_device_key(&key, &rotated),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+        let data_key = header.open_with_device_key(&key).unwrap();
+        assert!(matches!(
+            encrypt_file(&data_key, &header, &mut &b"new"[..], Vec::new(), 64, b""),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+    }
+
+    /// Open a file written by an earlier release with its device key and
+    /// X25519 recipient key, then rotate its device slot
+    fn check_old_format(file: &str, recipient: &str, version: u8) {
+        let file = hex::decode(file).unwrap();
+        let plaintext = b"written by an earlier release";
+        let context = b"docs/a.txt";
+        let key = fixture_key();
+        assert_eq!(open(&key, &file, context).unwrap(), plaintext);
+
+        let mut reader = &file[..];
+        let mut header = FileHeader::read_from(&mut reader).unwrap();
+        assert_eq!(header.version, version);
+        assert_eq!(header.compression, Compression::None);
+        let encoded = header.encode().unwrap();
+        assert_eq!(encoded, file[..encoded.len()]);
+        assert_eq!(encoded_len(&header), encoded.len());
+
+        let recipient = AsymmetricKey::from_pkcs8_der(&hex::decode(recipient).unwrap()).unwrap();
+        let data_key = header.open_with_private_key(&recipient).unwrap();
+        let mut out = Vec::new();
+        decrypt_file(&data_key, &header, reader, &mut out, context).unwrap();
+        assert_eq!(out, plaintext);
+
+        // Rotation keeps the file's own layout, so the payload still opens
+        let mut new = generate_key("AES-256", "USB001").unwrap();
+        new.metadata.version = 2;
+        header.rewrap_device_key(&key, &new).unwrap();
+        assert_eq!(header.version, version);
+        let mut rotated = header.encode().unwrap();
+        rotated.extend_from_slice(reader);
+        assert_eq!(open(&new, &rotated, context).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_reads_format_version_2() {
+        let file = concat!(
+            "414753460201ed6e5b9424ce639e75c6185c550aa7f302010000000106555342",
+            "30303100003c29dcb3e862c2b1ddc3667f415a31c13dda19471fdb6817143bce",
+            "fd25b75271810300965ebc6f914151923c5067e265526d4228f1dc581d540ebc",
+            "0ab80303c0449655b2a02df0b5438537cb825cdb359a999b303342135fd77f71",
+            "8294fbbb201812ecb1a4b9d90536ee08607fa6d93eddbd86d69651e0573fd450",
+            "9fc4139578003caebd246b1af921d288cccf81a7818b924315c9e034a7d927f3",
+            "e597fa6404106dcb9e36c0a62994aa96980a790b2f48d8d7c97ae0f1304545ba",
+            "34a89b68d5f68d9d3658000000407191257fc1ea11b9b7499294a8198dd9ea36",
+            "3eebbcdaee2a08d434bac1d04368effba6fb06d037bd13d30c5f6b",
+        );
+        let recipient = concat!(
+            "302e020100300506032b656e042204205a384517413d6b1fee0bc52826380690",
+            "1c09273b70a3efb6ee6d5d18d8f13bca",
+        );
+        check_old_format(file, recipient, 2);
+
+        // Older layouts cannot record compression
+        let mut header = FileHeader::new(Algorithm::Aes256Gcm).unwrap();
+        header.version = 2;
+        header.compression = Compression::Zstd { level: 3 };
+        assert!(matches!(
+            header.encode(),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_reads_format_version_3() {
+        let file = concat!(
+            "41475346030148ceda741fa1d4cbe82a41e1eab0996a020100000001e7273ebe",
+            "5aaba6ad0655534230303100003c8456f80a9f729a3840b9af4542deb18a20ca",
+            "23dcda9b2f5955f2f6c7ef29b9807a25fb728eead2fbcbecc6a84932d31cbdcf",
+            "0a7749c4656ae5344ba80303f17f8f62f78318983f07ff116e81ea94ba4aa861",
+            "70be35626e8a44b113e37ad42089321e226a02abe6d504db554b2b8994aaefda",
+            "1ab75f57c955805eaaabb3c735003c23ec222488860964d6050170d1c1c42022",
+            "f80a67f90421c34baab166a213db84a7c17795da5c304a95cf3ca7290da3bf62",
+            "475cfd0f1048d41d2d984ffa8cd11a37c31c000000404e5a1dc4f9f6ae7c5737",
+            "dd0fe8abced3a1d33d183e3aacb7d60573e9e8a2c9b68bd2122e42900575b67d",
+            "c4f9cf",
+        );
+        let recipient = concat!(
+            "302e020100300506032b656e04220420eb766f35eb9c29f5460dc18d9e08f7ea",
+            "2226998b733c63c5dda3fc36962ade94",
+        );
+        check_old_format(file, recipient, 3);
+
+        // A key with the right device and version but other material is
+        // still caught by key ID
+        let mut regenerated = fixture_key();
+        regenerated.key_material = vec![8; 32];
+        assert!(matches!(
+            open(&regenerated, &hex::decode(file).unwrap(), b"docs/a.txt"),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_reads_format_version_4() {
+        // Version 4 device slots end with a key derivation byte, always 0
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let data_key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let mut header = FileHeader::new(Algorithm::Aes256Gcm).unwrap();
+        header.version = 4;
+        header.add_device_key(&data_key, &key).unwrap();
+        let mut encoded = header.encode().unwrap();
+        assert_eq!(encoded_len(&header), encoded.len());
+        let kdf = encoded.len() - 2 - header.slots[0].wrapped.len() - 1;
+        assert_eq!(encoded[kdf], 0);
+
+        let parsed = FileHeader::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(
+            parsed.open_with_device_key(&key).unwrap().key(),
+            data_key.key()
+        );
+
+        encoded[kdf] = 2;
+        assert!(matches!(
+            FileHeader::read_from(&mut &encoded[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_mismatch_rejected() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let file = seal(&key, b"secret", b"");
+
+        let other_device = generate_key("AES-256", "USB002").unwrap();
+        assert!(matches!(
+            open(&other_device, &file, b""),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+
+        let mut rotated = key.clone();
+        rotated.metadata.version = 2;
+        assert!(matches!(
+            open(&rotated, &file, b""),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+
+        // A different key stored under the same device and version is
+        // caught by the key ID before any unwrap is attempted
+        let regenerated = generate_key("AES-256", "USB001").unwrap();
+        let key_id = hex::encode(Fingerprint::of_secret_key(&key.key_material).key_id());
+        assert!(matches!(
+            open(&regenerated, &file, b""),
+            Err(CryptoError::KeyMismatch(message)) if message.contains(&key_id)
+        ));
+
+        let mut chacha = generate_key("ChaCha20", "USB001").unwrap();
+        chacha.key_material = key.key_material.clone();
+        assert!(matches!(
+            open(&chacha, &file, b""),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_rewrap_keeps_payload() {
+        let old = generate_key("AES-256", "USB001").unwrap();
+        let mut new = generate_key("AES-256", "USB001").unwrap();
+        new.metadata.version = 2;
+        let file = seal(&old, b"secret", b"ctx");
+
+        let mut reader = &file[..];
+        let mut header = FileHeader::read_from(&mut reader).unwrap();
+        header.rewrap_device_key(&old, &new).unwrap();
+        assert_eq!(header.device_key(), Some(("USB001", 2)));
+
+        let mut rewritten = header.encode().unwrap();
+        rewritten.extend_from_slice(reader);
+        assert_eq!(open(&new, &rewritten, b"ctx").unwrap(), b"secret");
+        assert!(matches!(
+            open(&old, &rewritten, b"ctx"),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_public_key_recipients() {
+        let device = generate_key("AES-256", "USB001").unwrap();
+        let rsa = AsymmetricKey::generate(AsymmetricAlgorithm::Rsa2048).unwrap();
+        let ec = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP256).unwrap();
+        let x25519 = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        let recipients = [
+            Recipient::from_key(&rsa),
+            Recipient::from_key(&ec),
+            Recipient::from_key(&x25519),
+        ];
+
+        let (header, data_key) = FileHeader::seal(&device, &recipients).unwrap();
+        let mut file = Vec::new();
+        encrypt_file(&data_key, &header, &mut &b"secret"[..], &mut file, 64, b"").unwrap();
+
+        let mut reader = &file[..];
+        let header = FileHeader::read_from(&mut reader).unwrap();
+        assert_eq!(header.slots.len(), 4);
+        for key in [&rsa, &ec, &x25519] {
+            let data_key = header.open_with_private_key(key).unwrap();
+            let mut out = Vec::new();
+            decrypt_file(&data_key, &header, reader, &mut out, b"").unwrap();
+            assert_eq!(out, b"secret");
+        }
+
+        let stranger = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP256).unwrap();
+        assert!(matches!(
+            header.open_with_private_key(&stranger),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_hybrid_recipient() {
+        let device = generate_key("AES-256", "USB001").unwrap();
+        let x25519 = AsymmetricKey::generate(AsymmetricAlgorithm::X25519).unwrap();
+        let kem = AsymmetricKey::generate(AsymmetricAlgorithm::MlKem768).unwrap();
+        let recipient = Recipient::from_key(&x25519).with_ml_kem(&kem).unwrap();
+
+        let (header, data_key) = FileHeader::seal(&device, &[recipient]).unwrap();
+        let mut file = Vec::new();
+        encrypt_file(&data_key, &header, &mut &b"secret"[..], &mut file, 64, b"").unwrap();
+        assert_eq!(encoded_len(&header), header.encode().unwrap().len());
+
+        let mut reader = &file[..];
+        let mut header = FileHeader::read_from(&mut reader).unwrap();
+        let data_key = header.open_with_hybrid_key(&x25519, &kem).unwrap();
+        let mut out = Vec::new();
+        decrypt_file(&data_key, &header, reader, &mut out, b"").unwrap();
+        assert_eq!(out, b"secret");
+
+        // The ECDH key alone does not open a hybrid slot
+        assert!(matches!(
+            header.open_with_private_key(&x25519),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+        let other_kem = AsymmetricKey::generate(AsymmetricAlgorithm::MlKem768).unwrap();
+        assert!(matches!(
+            header.open_with_hybrid_key(&x25519, &other_kem),
+            Err(CryptoError::KeyMismatch(_))
+        ));
+
+        // The ML-KEM ciphertext is bound to the wrapped key
+        if let SlotKind::Hybrid { kem_ciphertext, .. } = &mut header.slots[1].kind {
+            kem_ciphertext[0] ^= 1;
+        }
+        assert!(matches!(
+            header.open_with_hybrid_key(&x25519, &kem),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_context_is_bound() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let file = seal(&key, b"secret", b"file:a.txt");
+        assert!(open(&key, &file, b"file:a.txt").is_ok());
+        assert!(matches!(
+            open(&key, &file, b"file:b.txt"),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        assert!(matches!(
+            FileHeader::read_from(&mut &b"nonce||ciphertext"[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            FileHeader::read_from(&mut &b"AGSF"[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            FileHeader::read_from(&mut &b"AGSF\x01\x01"[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+        for version in [0, FORMAT_VERSION + 1] {
+            assert!(matches!(
+                FileHeader::read_from(&mut &[b"AGSF".as_slice(), &[version, 1]].concat()[..]),
+                Err(CryptoError::InvalidHeader(message)) if message.contains("format version")
+            ));
+        }
+        let mut unknown_slot = b"AGSF\x03\x01".to_vec();
+        unknown_slot.extend_from_slice(&[0; FILE_ID_LEN]);
+        unknown_slot.extend_from_slice(b"\x01\x09");
+        assert!(matches!(
+            FileHeader::read_from(&mut &unknown_slot[..]),
+            Err(CryptoError::InvalidHeader(_))
+        ));
+    }
+}
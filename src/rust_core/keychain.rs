@@ -3,16 +3,18 @@
 //! This module provides a safe Rust wrapper around the macOS Security Framework
 //! for storing and retrieving encryption keys from the system keychain.
 
-use chrono::{DateTime, Utc};
+use crate::keystore::{validate_entry_id, KeyData, KeyStore, KeyStoreError};
 use core_foundation::base::TCFType;
 use std::ffi::CString;
 use security_framework::os::macos::keychain::{CreateOptions, SecKeychain};
+use security_framework::item::{ItemClass, ItemSearchOptions, Limit};
 use security_framework::os::macos::passwords::find_generic_password;
-use security_framework::passwords::set_generic_password;
-use serde::{Deserialize, Serialize};
+use security_framework::passwords::{delete_generic_password, set_generic_password};
 use thiserror::Error;
 use zeroize::Zeroize;
 
+pub use crate::keystore::{generate_key, rotate_key, EncryptionKey, KeyMetadata, KeyRole};
+
 /// Keychain-related error types
 #[derive(Debug, Error)]
 pub enum KeychainError {
@@ -39,40 +41,20 @@ pub enum KeychainError {
     /// Underlying Security Framework error
     #[error("Security framework error: {0}")]
     SecurityFramework(#[from] security_framework::base::Error),
+
+    /// Shared key handling error
+    #[error("{0}")]
+    KeyStore(#[from] KeyStoreError),
 }
 
 /// Service name for keychain entries
 const SERVICE_NAME: &str = "com.airgapsync.keys";
 
-/// Key metadata stored alongside the actual key
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KeyMetadata {
-    /// Key algorithm (RSA-2048, RSA-4096, ECDSA-P256, etc.)
-    pub algorithm: String,
-    /// Creation timestamp
-    pub created_at: DateTime<Utc>,
-    /// Last rotation timestamp
-    pub rotated_at: Option<DateTime<Utc>>,
-    /// Key version number
-    pub version: u32,
-    /// Device ID this key belongs to
-    pub device_id: String,
-}
+/// `errSecItemNotFound`
+const ERR_ITEM_NOT_FOUND: i32 = -25300;
 
-/// Encryption key with metadata
-#[derive(Clone)]
-pub struct EncryptionKey {
-    /// Raw key material (will be zeroed on drop)
-    pub key_material: Vec<u8>,
-    /// Key metadata
-    pub metadata: KeyMetadata,
-}
-
-impl Drop for EncryptionKey {
-    fn drop(&mut self) {
-        self.key_material.zeroize();
-    }
-}
+/// Keychain attribute key holding an item's account name (`kSecAttrAccount`)
+const ATTR_ACCOUNT: &str = "acct";
 
 /// Keychain manager for AirGapSync
 pub struct KeychainManager {
@@ -108,20 +90,14 @@ impl KeychainManager {
     /// Store a key in the keychain
     pub fn store_key(&self, device_id: &str, key: &EncryptionKey) -> Result<(), KeychainError> {
         // Serialize key data with metadata
-        use base64::Engine;
-        let key_data = KeyData {
-            material: base64::engine::general_purpose::STANDARD.encode(&key.key_material),
-            metadata: key.metadata.clone(),
-        };
-
-        let serialized = serde_json::to_string(&key_data)
-            .map_err(|e| KeychainError::EncodingError(e.to_string()))?;
+        let mut serialized = KeyData::encode(key)?;
 
         // Store in keychain
-        set_generic_password(&self.service_name, device_id, serialized.as_bytes())
-            .map_err(KeychainError::SecurityFramework)?;
+        let result = set_generic_password(&self.service_name, device_id, &serialized)
+            .map_err(KeychainError::SecurityFramework);
+        serialized.zeroize();
 
-        Ok(())
+        result
     }
 
     /// Retrieve a key from the keychain
@@ -129,24 +105,12 @@ impl KeychainManager {
         // Find the password entry
         let (password_data, _) = find_generic_password(None, &self.service_name, device_id)
             .map_err(|e| match e.code() {
-                -25300 => KeychainError::KeyNotFound,
+                ERR_ITEM_NOT_FOUND => KeychainError::KeyNotFound,
                 _ => KeychainError::SecurityFramework(e),
             })?;
 
         // Deserialize key data
-        let key_data: KeyData = serde_json::from_slice(&password_data)
-            .map_err(|e| KeychainError::EncodingError(e.to_string()))?;
-
-        // Decode key material
-        use base64::Engine;
-        let key_material = base64::engine::general_purpose::STANDARD
-            .decode(&key_data.material)
-            .map_err(|e| KeychainError::EncodingError(e.to_string()))?;
-
-        Ok(EncryptionKey {
-            key_material,
-            metadata: key_data.metadata,
-        })
+        Ok(KeyData::decode(&password_data)?)
     }
 
     /// Check if a key exists for a device
@@ -156,25 +120,37 @@ impl KeychainManager {
 
     /// Delete a key from the keychain
     pub fn delete_key(&self, device_id: &str) -> Result<(), KeychainError> {
-        // First check if key exists
-        if !self.key_exists(device_id) {
-            return Err(KeychainError::KeyNotFound);
-        }
-
-        // Note: security-framework doesn't expose delete directly,
-        // so we'll use a workaround by updating with empty data
-        // In a real implementation, we'd use the C API directly
-        set_generic_password(&format!("{}.deleted", self.service_name), device_id, b"")
-            .map_err(KeychainError::SecurityFramework)?;
-
-        Ok(())
+        delete_generic_password(&self.service_name, device_id).map_err(|e| match e.code() {
+            ERR_ITEM_NOT_FOUND => KeychainError::KeyNotFound,
+            _ => KeychainError::SecurityFramework(e),
+        })
     }
 
-    /// List all device IDs with stored keys
-    pub fn list_devices(&self) -> Result<Vec<String>, KeychainError> {
-        // Note: This is a simplified implementation
-        // In production, we'd query the keychain properly
-        Ok(vec![])
+    /// List every entry name, including archived key versions
+    ///
+    /// Every generic password item under the service name is enumerated and
+    /// its account name (the device ID) returned, sorted.
+    pub fn list_entries(&self) -> Result<Vec<String>, KeychainError> {
+        let results = match ItemSearchOptions::new()
+            .class(ItemClass::generic_password())
+            .service(&self.service_name)
+            .load_attributes(true)
+            .limit(Limit::All)
+            .search()
+        {
+            Ok(results) => results,
+            Err(e) if e.code() == ERR_ITEM_NOT_FOUND => return Ok(Vec::new()),
+            Err(e) => return Err(KeychainError::SecurityFramework(e)),
+        };
+
+        let mut entries: Vec<String> = results
+            .iter()
+            .filter_map(|result| result.simplify_dict())
+            .filter_map(|mut attributes| attributes.remove(ATTR_ACCOUNT))
+            .collect();
+        entries.sort();
+        entries.dedup();
+        Ok(entries)
     }
 
     /// Update key metadata without changing the key material
@@ -190,63 +166,44 @@ impl KeychainManager {
     }
 }
 
-/// Internal structure for JSON serialization
-#[derive(Serialize, Deserialize)]
-struct KeyData {
-    material: String, // Base64 encoded
-    metadata: KeyMetadata,
-}
+impl KeyStore for KeychainManager {
+    fn store_key(&self, device_id: &str, key: &EncryptionKey) -> Result<(), KeyStoreError> {
+        validate_entry_id(device_id)?;
+        Ok(KeychainManager::store_key(self, device_id, key)?)
+    }
 
-/// Generate a new encryption key
-pub fn generate_key(algorithm: &str, device_id: &str) -> Result<EncryptionKey, KeychainError> {
-    use ring::rand::{SecureRandom, SystemRandom};
-
-    let rng = SystemRandom::new();
-    let key_size = match algorithm {
-        "AES-256" => 32,
-        "AES-128" => 16,
-        "ChaCha20" => 32,
-        _ => return Err(KeychainError::InvalidKeyFormat),
-    };
-
-    let mut key_material = vec![0u8; key_size];
-    rng.fill(&mut key_material)
-        .map_err(|_| KeychainError::EncodingError("Failed to generate random key".to_string()))?;
-
-    let metadata = KeyMetadata {
-        algorithm: algorithm.to_string(),
-        created_at: Utc::now(),
-        rotated_at: None,
-        version: 1,
-        device_id: device_id.to_string(),
-    };
-
-    Ok(EncryptionKey {
-        key_material,
-        metadata,
-    })
-}
+    fn get_key(&self, device_id: &str) -> Result<EncryptionKey, KeyStoreError> {
+        Ok(KeychainManager::get_key(self, device_id)?)
+    }
 
-/// Rotate an existing key
-pub fn rotate_key(
-    keychain: &KeychainManager,
-    device_id: &str,
-) -> Result<EncryptionKey, KeychainError> {
-    // Get existing key to preserve algorithm
-    let old_key = keychain.get_key(device_id)?;
+    fn key_exists(&self, device_id: &str) -> bool {
+        KeychainManager::key_exists(self, device_id)
+    }
 
-    // Generate new key with same algorithm
-    let mut new_key = generate_key(&old_key.metadata.algorithm, device_id)?;
+    fn delete_key(&self, device_id: &str) -> Result<(), KeyStoreError> {
+        Ok(KeychainManager::delete_key(self, device_id)?)
+    }
 
-    // Update metadata
-    new_key.metadata.version = old_key.metadata.version + 1;
-    new_key.metadata.rotated_at = Some(Utc::now());
-    new_key.metadata.created_at = old_key.metadata.created_at;
+    fn list_entries(&self) -> Result<Vec<String>, KeyStoreError> {
+        Ok(KeychainManager::list_entries(self)?)
+    }
 
-    // Store new key
-    keychain.store_key(device_id, &new_key)?;
+    fn update_metadata(&self, device_id: &str, metadata: KeyMetadata) -> Result<(), KeyStoreError> {
+        Ok(KeychainManager::update_metadata(self, device_id, metadata)?)
+    }
+}
 
-    Ok(new_key)
+impl From<KeychainError> for KeyStoreError {
+    fn from(e: KeychainError) -> Self {
+        match e {
+            KeychainError::AccessDenied => KeyStoreError::AccessDenied,
+            KeychainError::KeyNotFound => KeyStoreError::KeyNotFound,
+            KeychainError::EncodingError(msg) => KeyStoreError::EncodingError(msg),
+            KeychainError::InvalidKeyFormat => KeyStoreError::InvalidKeyFormat,
+            KeychainError::KeyStore(e) => e,
+            other => KeyStoreError::Backend(other.to_string()),
+        }
+    }
 }
 
 impl KeychainManager {
@@ -315,15 +272,7 @@ impl KeychainManager {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[test]
-    fn test_key_generation() {
-        let key = generate_key("AES-256", "test-device").unwrap();
-        assert_eq!(key.key_material.len(), 32);
-        assert_eq!(key.metadata.algorithm, "AES-256");
-        assert_eq!(key.metadata.device_id, "test-device");
-        assert_eq!(key.metadata.version, 1);
-    }
+    use chrono::Utc;
 
     #[test]
     fn test_key_metadata_serialization() {
@@ -333,6 +282,7 @@ mod tests {
             rotated_at: None,
             version: 1,
             device_id: "USB001".to_string(),
+            role: KeyRole::Encryption,
         };
 
         let json = serde_json::to_string(&metadata).unwrap();
@@ -360,7 +310,17 @@ mod tests {
         assert_eq!(retrieved.key_material, key.key_material);
         assert_eq!(retrieved.metadata.algorithm, key.metadata.algorithm);
 
+        assert!(keychain
+            .list_devices()
+            .unwrap()
+            .contains(&device_id.to_string()));
+
         // Clean up
         keychain.delete_key(device_id).unwrap();
+        assert!(!keychain.key_exists(device_id));
+        assert!(matches!(
+            keychain.delete_key(device_id),
+            Err(KeychainError::KeyNotFound)
+        ));
     }
 }
@@ -0,0 +1,1088 @@
+//! Platform-independent key storage
+//!
+//! This module holds the key types shared by every key backend, the
+//! `KeyStore` trait they implement, and the in-memory backend used in
+//! tests. Keys are kept in the macOS Keychain or, on any platform, in a
+//! passphrase-protected vault (see [`crate::vault`]).
+//!
+//! Rotation keeps every earlier key version so existing media stays
+//! readable. Archived versions are ordinary entries named
+//! `<device>~v<version>`; `~` is not valid in device IDs, and every backend
+//! rejects other names containing it (see [`validate_entry_id`]), so they
+//! can never collide with a real device.
+//!
+//! Asymmetric key pairs are stored the same way as symmetric keys, with the
+//! PKCS#8 private key as key material, so they share versioning and
+//! rotation. Each key carries a [`KeyRole`] naming what it may be used for.
+
+use crate::config::{KeyStoreBackend, SecurityConfig};
+use crate::crypto::{Algorithm, CryptoError, CryptoKey};
+use crate::fingerprint::Fingerprint;
+use crate::keys::{AsymmetricAlgorithm, AsymmetricKey, KeyError};
+use crate::vault::VaultKeyStore;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+/// Key store error types
+#[derive(Debug, Error)]
+pub enum KeyStoreError {
+    /// User denied access to the key store
+    #[error("Key store access denied")]
+    AccessDenied,
+
+    /// Requested key was not found in the store
+    #[error("Key not found in key store")]
+    KeyNotFound,
+
+    /// Key store must be unlocked before use
+    #[error("Key store is locked")]
+    Locked,
+
+    /// Device ID cannot be used as a key store entry name
+    #[error("Invalid device ID: {0}")]
+    InvalidDeviceId(String),
+
+    /// Failed to encode or decode key data
+    #[error("Failed to encode/decode key data: {0}")]
+    EncodingError(String),
+
+    /// Key has invalid format or structure
+    #[error("Invalid key format")]
+    InvalidKeyFormat,
+
+    /// Algorithm cannot be used for the requested key role
+    #[error("{algorithm} keys cannot be used for {role}")]
+    UnsupportedRole {
+        /// Key algorithm
+        algorithm: String,
+        /// Requested role
+        role: KeyRole,
+    },
+
+    /// Stored entry failed to decrypt or authenticate
+    #[error("Failed to decrypt key store entry")]
+    DecryptionFailed,
+
+    /// Backend-specific failure
+    #[error("Key store backend error: {0}")]
+    Backend(String),
+
+    /// Underlying file system error
+    #[error("Key store I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Common interface implemented by every key storage backend
+pub trait KeyStore {
+    /// Store a key, replacing any existing key for the device
+    fn store_key(&self, device_id: &str, key: &EncryptionKey) -> Result<(), KeyStoreError>;
+
+    /// Retrieve the key for a device
+    fn get_key(&self, device_id: &str) -> Result<EncryptionKey, KeyStoreError>;
+
+    /// Check if a key exists for a device
+    fn key_exists(&self, device_id: &str) -> bool;
+
+    /// Delete the key for a device
+    fn delete_key(&self, device_id: &str) -> Result<(), KeyStoreError>;
+
+    /// List every entry name, including archived key versions
+    fn list_entries(&self) -> Result<Vec<String>, KeyStoreError>;
+
+    /// List all device IDs with a current key
+    fn list_devices(&self) -> Result<Vec<String>, KeyStoreError> {
+        Ok(self
+            .list_entries()?
+            .into_iter()
+            .filter(|id| parse_history_entry_id(id).is_none())
+            .collect())
+    }
+
+    /// Keep a copy of a key version that is about to be replaced
+    fn archive_key(&self, key: &EncryptionKey) -> Result<(), KeyStoreError> {
+        let id = history_entry_id(&key.metadata.device_id, key.metadata.version);
+        self.store_key(&id, key)
+    }
+
+    /// Retrieve a specific version of a device's key, current or archived
+    fn get_key_version(
+        &self,
+        device_id: &str,
+        version: u32,
+    ) -> Result<EncryptionKey, KeyStoreError> {
+        match self.get_key(device_id) {
+            Ok(key) if key.metadata.version == version => return Ok(key),
+            Ok(_) | Err(KeyStoreError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        self.get_key(&history_entry_id(device_id, version))
+    }
+
+    /// Metadata of every stored version of a device's key, oldest first
+    fn key_history(&self, device_id: &str) -> Result<Vec<KeyMetadata>, KeyStoreError> {
+        let mut history = Vec::new();
+        for id in self.list_entries()? {
+            if matches!(parse_history_entry_id(&id), Some((device, _)) if device == device_id) {
+                history.push(self.get_key(&id)?.metadata.clone());
+            }
+        }
+        match self.get_key(device_id) {
+            Ok(current) => history.push(current.metadata.clone()),
+            Err(KeyStoreError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        history.sort_by_key(|metadata| metadata.version);
+        Ok(history)
+    }
+
+    /// Delete an archived key version
+    fn retire_key_version(&self, device_id: &str, version: u32) -> Result<(), KeyStoreError> {
+        self.delete_key(&history_entry_id(device_id, version))
+    }
+
+    /// Update key metadata without changing the key material
+    fn update_metadata(&self, device_id: &str, metadata: KeyMetadata) -> Result<(), KeyStoreError> {
+        let mut key = self.get_key(device_id)?;
+        key.metadata = metadata;
+        self.store_key(device_id, &key)
+    }
+
+    /// Whether a passphrase is needed before keys can be accessed
+    fn is_locked(&self) -> bool {
+        false
+    }
+
+    /// Unlock the store with a passphrase (no-op for unprotected backends)
+    fn unlock(&self, _passphrase: &[u8]) -> Result<(), KeyStoreError> {
+        Ok(())
+    }
+
+    /// Forget any cached unlock secret (no-op for unprotected backends)
+    fn lock(&self) {}
+}
+
+/// What a stored key may be used for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRole {
+    /// Encrypting data or wrapping data keys
+    #[default]
+    Encryption,
+    /// Creating signatures
+    Signing,
+    /// ECDH key agreement
+    Agreement,
+}
+
+impl KeyRole {
+    /// Get the role name as shown to users
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyRole::Encryption => "encryption",
+            KeyRole::Signing => "signing",
+            KeyRole::Agreement => "agreement",
+        }
+    }
+
+    /// Parse a role name as returned by [`Self::as_str`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "encryption" => Some(KeyRole::Encryption),
+            "signing" => Some(KeyRole::Signing),
+            "agreement" => Some(KeyRole::Agreement),
+            _ => None,
+        }
+    }
+
+    /// Role given to new keys of an algorithm when none is requested
+    pub fn default_for(algorithm: &str) -> Self {
+        match AsymmetricAlgorithm::from_name(algorithm) {
+            Some(
+                AsymmetricAlgorithm::EcdsaP256
+                | AsymmetricAlgorithm::EcdsaP384
+                | AsymmetricAlgorithm::Ed25519,
+            ) => KeyRole::Signing,
+            Some(AsymmetricAlgorithm::X25519) => KeyRole::Agreement,
+            _ => KeyRole::Encryption,
+        }
+    }
+
+    /// Whether keys of an algorithm can fill this role
+    ///
+    /// Symmetric keys only encrypt, RSA keys encrypt (OAEP) or sign, EC
+    /// keys sign or agree, Ed25519 keys only sign, X25519 keys only agree
+    /// and ML-KEM keys only encrypt (as half of a hybrid recipient).
+    pub fn supports(&self, algorithm: &str) -> bool {
+        match AsymmetricAlgorithm::from_name(algorithm) {
+            None => *self == KeyRole::Encryption,
+            Some(AsymmetricAlgorithm::Rsa2048 | AsymmetricAlgorithm::Rsa4096) => {
+                matches!(self, KeyRole::Encryption | KeyRole::Signing)
+            }
+            Some(AsymmetricAlgorithm::EcdsaP256 | AsymmetricAlgorithm::EcdsaP384) => {
+                matches!(self, KeyRole::Signing | KeyRole::Agreement)
+            }
+            Some(AsymmetricAlgorithm::Ed25519) => *self == KeyRole::Signing,
+            Some(AsymmetricAlgorithm::X25519) => *self == KeyRole::Agreement,
+            Some(AsymmetricAlgorithm::MlKem768) => *self == KeyRole::Encryption,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Key metadata stored alongside the actual key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyMetadata {
+    /// Key algorithm (RSA-2048, RSA-4096, ECDSA-P256, etc.)
+    pub algorithm: String,
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+    /// Last rotation timestamp
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// Key version number
+    pub version: u32,
+    /// Device ID this key belongs to
+    pub device_id: String,
+    /// What the key may be used for (entries written before roles existed
+    /// are encryption keys)
+    #[serde(default)]
+    pub role: KeyRole,
+}
+
+impl KeyMetadata {
+    /// Time since the current key material was created or last rotated
+    pub fn age(&self) -> chrono::Duration {
+        Utc::now() - self.rotated_at.unwrap_or(self.created_at)
+    }
+}
+
+/// Encryption key with metadata
+#[derive(Clone)]
+pub struct EncryptionKey {
+    /// Raw key material (will be zeroed on drop)
+    pub key_material: Vec<u8>,
+    /// Key metadata
+    pub metadata: KeyMetadata,
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        self.key_material.zeroize();
+    }
+}
+
+impl EncryptionKey {
+    /// Build a `CryptoKey` for bulk encryption from this stored key
+    pub fn to_crypto_key(&self) -> Result<CryptoKey, CryptoError> {
+        let algorithm = match self.metadata.algorithm.as_str() {
+            "AES-256" => Algorithm::Aes256Gcm,
+            "ChaCha20" => Algorithm::ChaCha20Poly1305,
+            other => return Err(CryptoError::UnsupportedAlgorithm(other.to_string())),
+        };
+        CryptoKey::new(self.key_material.clone(), algorithm)
+    }
+
+    /// Whether this is an asymmetric key pair
+    pub fn is_asymmetric(&self) -> bool {
+        AsymmetricAlgorithm::from_name(&self.metadata.algorithm).is_some()
+    }
+
+    /// Load the key pair held by an asymmetric key
+    pub fn to_asymmetric_key(&self) -> Result<AsymmetricKey, KeyError> {
+        let algorithm = AsymmetricAlgorithm::from_name(&self.metadata.algorithm)
+            .ok_or_else(|| KeyError::UnsupportedAlgorithm(self.metadata.algorithm.clone()))?;
+        let key = AsymmetricKey::from_pkcs8_der(&self.key_material)?;
+        if key.algorithm != algorithm {
+            return Err(KeyError::InvalidFormat);
+        }
+        Ok(key)
+    }
+
+    /// Fingerprint of the key: a keyed HMAC for symmetric keys, the
+    /// public key's SHA-256 for key pairs
+    pub fn fingerprint(&self) -> Result<Fingerprint, KeyError> {
+        if self.is_asymmetric() {
+            Ok(self.to_asymmetric_key()?.fingerprint())
+        } else {
+            Ok(Fingerprint::of_secret_key(&self.key_material))
+        }
+    }
+}
+
+/// Serialized form of an `EncryptionKey` shared by all backends
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeyData {
+    material: String, // Base64 encoded
+    metadata: KeyMetadata,
+}
+
+impl Drop for KeyData {
+    fn drop(&mut self) {
+        self.material.zeroize();
+    }
+}
+
+impl KeyData {
+    /// Build the serializable form of a key
+    pub(crate) fn from_key(key: &EncryptionKey) -> Self {
+        KeyData {
+            material: general_purpose::STANDARD.encode(&key.key_material),
+            metadata: key.metadata.clone(),
+        }
+    }
+
+    /// Recover the key from its serializable form
+    pub(crate) fn to_key(&self) -> Result<EncryptionKey, KeyStoreError> {
+        let key_material = general_purpose::STANDARD
+            .decode(&self.material)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+        Ok(EncryptionKey {
+            key_material,
+            metadata: self.metadata.clone(),
+        })
+    }
+
+    /// Encode a key for storage
+    #[cfg(any(target_os = "macos", test))]
+    pub(crate) fn encode(key: &EncryptionKey) -> Result<Vec<u8>, KeyStoreError> {
+        serde_json::to_vec(&Self::from_key(key))
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))
+    }
+
+    /// Decode a key previously produced by `encode`
+    pub(crate) fn decode(bytes: &[u8]) -> Result<EncryptionKey, KeyStoreError> {
+        let key_data: KeyData = serde_json::from_slice(bytes)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+        key_data.to_key()
+    }
+}
+
+/// Generate a new key with the default role for its algorithm
+pub fn generate_key(algorithm: &str, device_id: &str) -> Result<EncryptionKey, KeyStoreError> {
+    generate_key_with_role(algorithm, KeyRole::default_for(algorithm), device_id)
+}
+
+/// Generate a new key for a role
+///
+/// `algorithm` is a symmetric algorithm (`AES-256`, `AES-128`, `ChaCha20`)
+/// or an [`AsymmetricAlgorithm`] name, whose key pair is stored as PKCS#8.
+pub fn generate_key_with_role(
+    algorithm: &str,
+    role: KeyRole,
+    device_id: &str,
+) -> Result<EncryptionKey, KeyStoreError> {
+    if !role.supports(algorithm) {
+        return Err(KeyStoreError::UnsupportedRole {
+            algorithm: algorithm.to_string(),
+            role,
+        });
+    }
+
+    let key_material = match algorithm {
+        "AES-256" | "ChaCha20" => random_key_material(32)?,
+        "AES-128" => random_key_material(16)?,
+        _ => {
+            let algorithm =
+                AsymmetricAlgorithm::from_name(algorithm).ok_or(KeyStoreError::InvalidKeyFormat)?;
+            AsymmetricKey::generate(algorithm)
+                .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?
+                .private_key_bytes()
+                .to_vec()
+        }
+    };
+
+    let metadata = KeyMetadata {
+        algorithm: algorithm.to_string(),
+        created_at: Utc::now(),
+        rotated_at: None,
+        version: 1,
+        device_id: device_id.to_string(),
+        role,
+    };
+
+    Ok(EncryptionKey {
+        key_material,
+        metadata,
+    })
+}
+
+/// Random symmetric key material
+fn random_key_material(len: usize) -> Result<Vec<u8>, KeyStoreError> {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let mut key_material = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut key_material)
+        .map_err(|_| KeyStoreError::EncodingError("Failed to generate random key".to_string()))?;
+    Ok(key_material)
+}
+
+/// Volatile key store for tests and short-lived tools
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    keys: Mutex<BTreeMap<String, EncryptionKey>>,
+}
+
+impl MemoryKeyStore {
+    /// Create an empty in-memory key store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn keys(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, EncryptionKey>> {
+        // A panic while holding the lock cannot leave the map half-updated
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn store_key(&self, device_id: &str, key: &EncryptionKey) -> Result<(), KeyStoreError> {
+        validate_entry_id(device_id)?;
+        self.keys().insert(device_id.to_string(), key.clone());
+        Ok(())
+    }
+
+    fn get_key(&self, device_id: &str) -> Result<EncryptionKey, KeyStoreError> {
+        self.keys()
+            .get(device_id)
+            .cloned()
+            .ok_or(KeyStoreError::KeyNotFound)
+    }
+
+    fn key_exists(&self, device_id: &str) -> bool {
+        self.keys().contains_key(device_id)
+    }
+
+    fn delete_key(&self, device_id: &str) -> Result<(), KeyStoreError> {
+        self.keys()
+            .remove(device_id)
+            .map(|_| ())
+            .ok_or(KeyStoreError::KeyNotFound)
+    }
+
+    fn list_entries(&self) -> Result<Vec<String>, KeyStoreError> {
+        Ok(self.keys().keys().cloned().collect())
+    }
+}
+
+/// Open the key store selected by the security configuration
+///
+/// A vault is returned locked; callers must `unlock` it before use.
+pub fn open_key_store(security: &SecurityConfig) -> Result<Box<dyn KeyStore>, KeyStoreError> {
+    match security.key_store {
+        KeyStoreBackend::Keychain => open_keychain(),
+        KeyStoreBackend::Vault => Ok(Box::new(VaultKeyStore::open(
+            key_store_path(security)?,
+            &security.vault,
+        )?)),
+    }
+}
+
+/// Location of the vault file, falling back to the default
+pub fn key_store_path(security: &SecurityConfig) -> Result<PathBuf, KeyStoreError> {
+    match &security.key_store_path {
+        Some(path) => Ok(PathBuf::from(
+            shellexpand::tilde(&path.to_string_lossy()).as_ref(),
+        )),
+        None => VaultKeyStore::default_path(),
+    }
+}
+
+/// Directory where earlier releases kept unencrypted key files
+/// (`~/.airgapsync/keys`)
+pub fn legacy_key_dir() -> Result<PathBuf, KeyStoreError> {
+    Ok(airgapsync_home()?.join("keys"))
+}
+
+/// Copy the unencrypted `<entry>.key` files written by earlier releases
+/// into `store`
+///
+/// Entries already in `store` are left alone. The files are not deleted;
+/// once the import is verified, the caller should remove them. Returns the
+/// imported entry names.
+pub fn import_legacy_key_files(
+    dir: &Path,
+    store: &dyn KeyStore,
+) -> Result<Vec<String>, KeyStoreError> {
+    let mut imported = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(imported),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    for path in paths {
+        if path.extension().and_then(|e| e.to_str()) != Some(LEGACY_KEY_EXTENSION) {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if store.key_exists(id) {
+            continue;
+        }
+        let mut contents = fs::read(&path)?;
+        let key = KeyData::decode(&contents);
+        contents.zeroize();
+        store.store_key(id, &key?)?;
+        imported.push(id.to_string());
+    }
+    Ok(imported)
+}
+
+#[cfg(target_os = "macos")]
+fn open_keychain() -> Result<Box<dyn KeyStore>, KeyStoreError> {
+    Ok(Box::new(crate::keychain::KeychainManager::new()))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn open_keychain() -> Result<Box<dyn KeyStore>, KeyStoreError> {
+    Err(KeyStoreError::Backend(
+        "Keychain backend requires macOS".to_string(),
+    ))
+}
+
+/// Separator between a device ID and version in archived entry names
+const HISTORY_SEPARATOR: &str = "~v";
+
+/// Entry name under which an earlier key version is archived
+pub fn history_entry_id(device_id: &str, version: u32) -> String {
+    format!("{device_id}{HISTORY_SEPARATOR}{version}")
+}
+
+/// Split an archived entry name into its device ID and version
+pub fn parse_history_entry_id(id: &str) -> Option<(&str, u32)> {
+    let (device_id, version) = id.rsplit_once(HISTORY_SEPARATOR)?;
+    if device_id.is_empty() || version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((device_id, version.parse().ok()?))
+}
+
+/// Rotate an existing key
+///
+/// The previous version is archived first, so data encrypted under it can
+/// still be decrypted with `get_key_version`.
+pub fn rotate_key(store: &dyn KeyStore, device_id: &str) -> Result<EncryptionKey, KeyStoreError> {
+    // Get existing key to preserve algorithm
+    let old_key = store.get_key(device_id)?;
+    store.archive_key(&old_key)?;
+
+    // Generate new key with same algorithm and role
+    let mut new_key = generate_key_with_role(
+        &old_key.metadata.algorithm,
+        old_key.metadata.role,
+        device_id,
+    )?;
+
+    // Update metadata
+    new_key.metadata.version = old_key.metadata.version + 1;
+    new_key.metadata.rotated_at = Some(Utc::now());
+    new_key.metadata.created_at = old_key.metadata.created_at;
+
+    // Store new key
+    store.store_key(device_id, &new_key)?;
+
+    Ok(new_key)
+}
+
+/// Retire archived versions of a device key that nothing still uses
+///
+/// `in_use` must hold every key version referenced by data on every known
+/// device; versions outside it are deleted. The current key is never
+/// retired. Returns the retired versions.
+pub fn retire_unused_versions(
+    store: &dyn KeyStore,
+    device_id: &str,
+    in_use: &BTreeSet<u32>,
+) -> Result<Vec<u32>, KeyStoreError> {
+    let current = store.get_key(device_id)?.metadata.version;
+    let mut retired = Vec::new();
+    for metadata in store.key_history(device_id)? {
+        if metadata.version != current && !in_use.contains(&metadata.version) {
+            store.retire_key_version(device_id, metadata.version)?;
+            retired.push(metadata.version);
+        }
+    }
+    Ok(retired)
+}
+
+/// Delete a device's current key and every archived version
+pub fn delete_all_versions(store: &dyn KeyStore, device_id: &str) -> Result<(), KeyStoreError> {
+    let current = store.get_key(device_id)?.metadata.version;
+    for metadata in store.key_history(device_id)? {
+        if metadata.version != current {
+            store.retire_key_version(device_id, metadata.version)?;
+        }
+    }
+    store.delete_key(device_id)
+}
+
+/// Per-user AirGapSync state directory (`~/.airgapsync`)
+pub(crate) fn airgapsync_home() -> Result<PathBuf, KeyStoreError> {
+    let home = dirs::home_dir().ok_or_else(|| {
+        KeyStoreError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Could not determine home directory",
+        ))
+    })?;
+    Ok(home.join(".airgapsync"))
+}
+
+/// File extension of the key files in [`legacy_key_dir`]
+const LEGACY_KEY_EXTENSION: &str = "key";
+
+/// Reject entry names other than valid device IDs and their archived
+/// versions
+///
+/// Every backend checks names before storing a key.
+pub fn validate_entry_id(id: &str) -> Result<(), KeyStoreError> {
+    match parse_history_entry_id(id) {
+        Some((device_id, _)) => validate_device_id(device_id),
+        None => validate_device_id(id),
+    }
+}
+
+/// Reject device IDs that cannot safely be used as file names
+fn validate_device_id(device_id: &str) -> Result<(), KeyStoreError> {
+    let valid = !device_id.is_empty()
+        && device_id != "."
+        && device_id != ".."
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(KeyStoreError::InvalidDeviceId(device_id.to_string()))
+    }
+}
+
+/// Create a directory readable only by the current user
+pub(crate) fn create_private_dir(dir: &Path) -> Result<(), KeyStoreError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+
+    #[cfg(not(unix))]
+    {
+        fs::create_dir_all(dir)?;
+    }
+
+    Ok(())
+}
+
+/// Atomically write a file readable only by the current user
+///
+/// The contents go to a new file with a random name next to `path`, created
+/// exclusively so an existing file or planted symlink is never reused, and
+/// are synced before that file is renamed over `path`.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), KeyStoreError> {
+    let tmp_path = private_temp_path(path)?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    let written = file
+        .write_all(contents)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| {
+            drop(file);
+            fs::rename(&tmp_path, path)
+        });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Unique temporary path in the same directory as `path`
+fn private_temp_path(path: &Path) -> Result<PathBuf, KeyStoreError> {
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let suffix = hex::encode(random_key_material(8)?);
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".{suffix}.tmp"));
+    Ok(path.with_file_name(tmp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_generation() {
+        let key = generate_key("AES-256", "test-device").unwrap();
+        assert_eq!(key.key_material.len(), 32);
+        assert_eq!(key.metadata.algorithm, "AES-256");
+        assert_eq!(key.metadata.device_id, "test-device");
+        assert_eq!(key.metadata.version, 1);
+    }
+
+    #[test]
+    fn test_key_data_round_trip() {
+        let key = generate_key("ChaCha20", "USB001").unwrap();
+        let encoded = KeyData::encode(&key).unwrap();
+        let decoded = KeyData::decode(&encoded).unwrap();
+
+        assert_eq!(decoded.key_material, key.key_material);
+        assert_eq!(decoded.metadata.algorithm, "ChaCha20");
+    }
+
+    #[test]
+    fn test_key_age() {
+        let mut key = generate_key("AES-256", "USB001").unwrap();
+        key.metadata.created_at = Utc::now() - chrono::Duration::days(30);
+        assert_eq!(key.metadata.age().num_days(), 30);
+
+        key.metadata.rotated_at = Some(Utc::now() - chrono::Duration::days(2));
+        assert_eq!(key.metadata.age().num_days(), 2);
+    }
+
+    #[test]
+    fn test_to_crypto_key() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        assert_eq!(
+            key.to_crypto_key().unwrap().algorithm(),
+            Algorithm::Aes256Gcm
+        );
+
+        let key = generate_key("AES-128", "USB001").unwrap();
+        assert!(key.to_crypto_key().is_err());
+    }
+
+    #[test]
+    fn test_key_roles() {
+        assert_eq!(KeyRole::default_for("AES-256"), KeyRole::Encryption);
+        assert_eq!(KeyRole::default_for("RSA-4096"), KeyRole::Encryption);
+        assert_eq!(KeyRole::default_for("ECDSA-P384"), KeyRole::Signing);
+        assert_eq!(KeyRole::default_for("Ed25519"), KeyRole::Signing);
+        assert_eq!(KeyRole::default_for("X25519"), KeyRole::Agreement);
+        assert_eq!(KeyRole::default_for("ML-KEM-768"), KeyRole::Encryption);
+
+        assert!(KeyRole::Signing.supports("RSA-2048"));
+        assert!(KeyRole::Agreement.supports("ECDSA-P256"));
+        assert!(!KeyRole::Encryption.supports("ECDSA-P256"));
+        assert!(!KeyRole::Agreement.supports("RSA-2048"));
+        assert!(!KeyRole::Agreement.supports("Ed25519"));
+        assert!(!KeyRole::Signing.supports("X25519"));
+        assert!(!KeyRole::Agreement.supports("ML-KEM-768"));
+        assert!(matches!(
+            generate_key_with_role("ChaCha20", KeyRole::Signing, "USB001"),
+            Err(KeyStoreError::UnsupportedRole { .. })
+        ));
+
+        // Entries stored before roles existed are encryption keys
+        let metadata: KeyMetadata = serde_json::from_str(
+            r#"{"algorithm":"AES-256","created_at":"2024-01-01T00:00:00Z",
+                "rotated_at":null,"version":1,"device_id":"USB001"}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.role, KeyRole::Encryption);
+    }
+
+    #[test]
+    fn test_asymmetric_key_storage() {
+        let store = MemoryKeyStore::new();
+
+        let key = generate_key_with_role("ECDSA-P256", KeyRole::Agreement, "ecdh").unwrap();
+        assert!(key.is_asymmetric());
+        assert!(key.to_crypto_key().is_err());
+        store.store_key("ecdh", &key).unwrap();
+
+        let stored = store.get_key("ecdh").unwrap();
+        assert_eq!(stored.metadata.role, KeyRole::Agreement);
+        let pair = stored.to_asymmetric_key().unwrap();
+        assert_eq!(pair.algorithm, AsymmetricAlgorithm::EcdsaP256);
+        assert_eq!(
+            pair.public_key_bytes(),
+            key.to_asymmetric_key().unwrap().public_key_bytes()
+        );
+        // Key pairs are fingerprinted by their public half
+        assert_eq!(
+            stored.fingerprint().unwrap(),
+            Fingerprint::of_public_key_der(&pair.public_key_der())
+        );
+
+        // Rotation keeps the algorithm and role and archives the old pair
+        let rotated = rotate_key(&store, "ecdh").unwrap();
+        assert_eq!(rotated.metadata.algorithm, "ECDSA-P256");
+        assert_eq!(rotated.metadata.role, KeyRole::Agreement);
+        assert_ne!(rotated.key_material, key.key_material);
+        assert_eq!(
+            store.get_key_version("ecdh", 1).unwrap().key_material,
+            key.key_material
+        );
+
+        // Symmetric keys hold no key pair
+        let symmetric = generate_key("AES-256", "USB001").unwrap();
+        assert!(!symmetric.is_asymmetric());
+        assert!(symmetric.to_asymmetric_key().is_err());
+        assert_eq!(
+            symmetric.fingerprint().unwrap(),
+            Fingerprint::of_secret_key(&symmetric.key_material)
+        );
+    }
+
+    #[test]
+    fn test_rotation_archives_previous_version() {
+        let store = MemoryKeyStore::new();
+
+        let key = generate_key("AES-256", "USB001").unwrap();
+        store.store_key("USB001", &key).unwrap();
+
+        let rotated = rotate_key(&store, "USB001").unwrap();
+        assert_eq!(rotated.metadata.version, 2);
+        assert_ne!(rotated.key_material, key.key_material);
+        assert_eq!(
+            store.get_key("USB001").unwrap().key_material,
+            rotated.key_material
+        );
+
+        // The previous version survives rotation
+        assert_eq!(
+            store.get_key_version("USB001", 1).unwrap().key_material,
+            key.key_material
+        );
+        assert_eq!(store.list_devices().unwrap(), vec!["USB001"]);
+        assert_eq!(store.list_entries().unwrap(), vec!["USB001", "USB001~v1"]);
+    }
+
+    #[test]
+    fn test_key_history() {
+        let store = MemoryKeyStore::new();
+        store
+            .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+            .unwrap();
+        store
+            .store_key("USB002", &generate_key("AES-256", "USB002").unwrap())
+            .unwrap();
+        for _ in 0..3 {
+            rotate_key(&store, "USB001").unwrap();
+        }
+
+        let versions: Vec<u32> = store
+            .key_history("USB001")
+            .unwrap()
+            .iter()
+            .map(|m| m.version)
+            .collect();
+        assert_eq!(versions, vec![1, 2, 3, 4]);
+        assert_eq!(store.key_history("USB002").unwrap().len(), 1);
+        assert!(store.key_history("USB003").unwrap().is_empty());
+        assert!(matches!(
+            store.get_key_version("USB001", 9),
+            Err(KeyStoreError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_retire_unused_versions() {
+        let store = MemoryKeyStore::new();
+        store
+            .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+            .unwrap();
+        for _ in 0..3 {
+            rotate_key(&store, "USB001").unwrap();
+        }
+
+        // Version 2 is still referenced; the current version 4 never retires
+        let retired = retire_unused_versions(&store, "USB001", &BTreeSet::from([2])).unwrap();
+        assert_eq!(retired, vec![1, 3]);
+        let versions: Vec<u32> = store
+            .key_history("USB001")
+            .unwrap()
+            .iter()
+            .map(|m| m.version)
+            .collect();
+        assert_eq!(versions, vec![2, 4]);
+
+        delete_all_versions(&store, "USB001").unwrap();
+        assert!(store.list_entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_history_entry_ids() {
+        assert_eq!(history_entry_id("USB001", 3), "USB001~v3");
+        assert_eq!(parse_history_entry_id("USB001~v3"), Some(("USB001", 3)));
+        assert_eq!(parse_history_entry_id("USB001"), None);
+        assert_eq!(parse_history_entry_id("~v3"), None);
+        assert_eq!(parse_history_entry_id("USB001~v"), None);
+        assert_eq!(parse_history_entry_id("USB001~v+3"), None);
+
+        // Archived names are only accepted in their exact form, and no
+        // other name may contain `~`
+        let store = MemoryKeyStore::new();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        assert!(store.store_key("USB001~v1", &key).is_ok());
+        for id in ["USB001~x", "USB~001", "~v1", "../escape", ""] {
+            assert!(matches!(
+                store.store_key(id, &key),
+                Err(KeyStoreError::InvalidDeviceId(_))
+            ));
+            assert!(!store.key_exists(id));
+        }
+    }
+
+    #[test]
+    fn test_import_legacy_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let archived = generate_key("AES-256", "USB001").unwrap();
+        let mut key = generate_key("AES-256", "USB001").unwrap();
+        key.metadata.version = 2;
+        fs::write(
+            dir.path().join("USB001.key"),
+            KeyData::encode(&key).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("USB001~v1.key"),
+            KeyData::encode(&archived).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not a key").unwrap();
+
+        let store = MemoryKeyStore::new();
+        let imported = import_legacy_key_files(dir.path(), &store).unwrap();
+        assert_eq!(imported, ["USB001", "USB001~v1"]);
+        assert_eq!(
+            store.get_key_version("USB001", 1).unwrap().key_material,
+            archived.key_material
+        );
+        assert!(dir.path().join("USB001.key").exists());
+
+        // Entries already in the store are kept
+        let current = rotate_key(&store, "USB001").unwrap();
+        assert!(import_legacy_key_files(dir.path(), &store)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store.get_key("USB001").unwrap().key_material,
+            current.key_material
+        );
+        let missing = dir.path().join("missing");
+        assert!(import_legacy_key_files(&missing, &store)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_write_private_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        // A stale temp file from the old fixed name, or a symlink planted
+        // there, must be left alone
+        let target = dir.path().join("target");
+        fs::write(&target, b"untouched").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&target, path.with_extension("tmp")).unwrap();
+
+        // An existing file with looser permissions is replaced, not reused
+        fs::write(&path, b"old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        write_private_file(&path, b"first").unwrap();
+        write_private_file(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read(&target).unwrap(), b"untouched");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Only the file itself, the target and the planted link remain
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        let expected = if cfg!(unix) { 3 } else { 2 };
+        assert_eq!(names.len(), expected, "{names:?}");
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryKeyStore::new();
+        let key = generate_key("ChaCha20", "USB001").unwrap();
+        store.store_key("USB001", &key).unwrap();
+
+        assert!(store.key_exists("USB001"));
+        assert_eq!(store.list_devices().unwrap(), vec!["USB001".to_string()]);
+
+        let rotated = rotate_key(&store, "USB001").unwrap();
+        assert_eq!(rotated.metadata.version, 2);
+
+        let mut metadata = rotated.metadata.clone();
+        metadata.version = 5;
+        store.update_metadata("USB001", metadata).unwrap();
+        assert_eq!(store.get_key("USB001").unwrap().metadata.version, 5);
+
+        store.delete_key("USB001").unwrap();
+        assert!(matches!(
+            store.delete_key("USB001"),
+            Err(KeyStoreError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_open_vault_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let security = SecurityConfig {
+            key_store: KeyStoreBackend::Vault,
+            key_store_path: Some(dir.path().join("keys.vault")),
+            vault: crate::config::EncryptionConfig {
+                iterations: 100_000,
+                ..Default::default()
+            },
+            ..SecurityConfig::default()
+        };
+
+        let store = open_key_store(&security).unwrap();
+        assert!(store.is_locked());
+        store.unlock(b"passphrase").unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        store.store_key("USB001", &key).unwrap();
+        assert!(dir.path().join("keys.vault").is_file());
+    }
+}
@@ -6,24 +6,43 @@
 #![warn(missing_docs)]
 #![deny(unsafe_code)]
 
-// Feature gates
-#[cfg(not(target_os = "macos"))]
-compile_error!("AirGapSync currently only supports macOS");
-
 // Module declarations
+pub mod backup;
+pub mod compress;
 pub mod config;
 pub mod crypto;
+pub mod dedup;
+pub mod diff;
+pub mod envelope;
+pub mod fingerprint;
+pub mod header;
 #[cfg(target_os = "macos")]
 pub mod keychain;
 pub mod keys;
+pub mod keystore;
+pub mod mlkem;
+pub mod rekey;
+pub mod restore;
 pub mod schema;
+pub mod shares;
+pub mod snapshot;
+pub mod stream;
+pub mod sync;
+pub mod vault;
+pub mod walker;
 
 // Re-exports for convenience
 pub use config::{Config, ConfigError};
 pub use crypto::{Algorithm as EncryptionAlgorithm, CryptoError, CryptoKey};
+pub use fingerprint::Fingerprint;
 #[cfg(target_os = "macos")]
-pub use keychain::{EncryptionKey, KeychainError, KeychainManager};
+pub use keychain::{KeychainError, KeychainManager};
 pub use keys::{AsymmetricAlgorithm, AsymmetricKey, KeyAgreement};
+pub use keystore::{EncryptionKey, KeyMetadata, KeyRole, KeyStore, KeyStoreError, MemoryKeyStore};
+pub use rekey::{RekeyJob, RekeyReport};
+pub use restore::{RestoreJob, RestoreReport};
+pub use sync::{SyncEngine, SyncReport};
+pub use vault::VaultKeyStore;
 
 use thiserror::Error;
 
@@ -47,6 +66,26 @@ pub enum AirGapError {
     #[error("Key error: {0}")]
     Key(#[from] keys::KeyError),
 
+    /// Key store error
+    #[error("Key store error: {0}")]
+    KeyStore(#[from] KeyStoreError),
+
+    /// Key backup error
+    #[error("Key backup error: {0}")]
+    Backup(#[from] backup::BackupError),
+
+    /// Chunk store error
+    #[error("Chunk store error: {0}")]
+    Dedup(#[from] dedup::DedupError),
+
+    /// Snapshot manifest error
+    #[error("Snapshot error: {0}")]
+    Snapshot(#[from] snapshot::SnapshotError),
+
+    /// Source walk error
+    #[error("Source walk error: {0}")]
+    Walk(#[from] walker::WalkError),
+
     /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -81,14 +120,6 @@ pub fn initialize() -> Result<()> {
 
     log::info!("Initializing AirGapSync v{VERSION}");
 
-    // Verify we're on macOS
-    #[cfg(not(target_os = "macos"))]
-    {
-        return Err(AirGapError::SyncError(
-            "AirGapSync requires macOS for Keychain integration".to_string(),
-        ));
-    }
-
     // Check for required system capabilities
     verify_system_requirements()?;
 
@@ -98,31 +129,52 @@ pub fn initialize() -> Result<()> {
 
 /// Verify system requirements
 fn verify_system_requirements() -> Result<()> {
-    // Check macOS version (10.15+ required)
-    #[cfg(target_os = "macos")]
-    {
-        use std::process::Command;
-
-        let output = Command::new("sw_vers").arg("-productVersion").output()?;
+    // Key generation and encryption depend on the system RNG
+    crypto::generate_salt()?;
 
-        let version = String::from_utf8_lossy(&output.stdout);
-        let parts: Vec<&str> = version.trim().split('.').collect();
-
-        if parts.len() >= 2 {
-            let major: u32 = parts[0].parse().unwrap_or(0);
-            let minor: u32 = parts[1].parse().unwrap_or(0);
+    verify_platform()
+}
 
-            if major < 10 || (major == 10 && minor < 15) {
-                return Err(AirGapError::SyncError(
-                    "macOS 10.15 or later required".to_string(),
-                ));
-            }
+/// Check macOS version (10.15+ required)
+#[cfg(target_os = "macos")]
+fn verify_platform() -> Result<()> {
+    let plist = std::fs::read_to_string("/System/Library/CoreServices/SystemVersion.plist")?;
+
+    match parse_product_version(&plist) {
+        Some((major, minor)) if major < 10 || (major == 10 && minor < 15) => Err(
+            AirGapError::SyncError("macOS 10.15 or later required".to_string()),
+        ),
+        Some(_) => Ok(()),
+        None => {
+            log::warn!("Could not determine macOS version");
+            Ok(())
         }
     }
+}
 
+/// Other platforms use the key vault and have no version floor
+#[cfg(not(target_os = "macos"))]
+fn verify_platform() -> Result<()> {
+    log::debug!(
+        "Running on {}; using vault key storage",
+        std::env::consts::OS
+    );
     Ok(())
 }
 
+/// Extract `(major, minor)` from the contents of `SystemVersion.plist`
+#[cfg(any(target_os = "macos", test))]
+fn parse_product_version(plist: &str) -> Option<(u32, u32)> {
+    let after_key = &plist[plist.find("<key>ProductVersion</key>")?..];
+    let start = after_key.find("<string>")? + "<string>".len();
+    let end = after_key[start..].find("</string>")? + start;
+
+    let mut parts = after_key[start..end].trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|m| m.parse().ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
 /// Get library information
 pub fn get_info() -> String {
     format!(
@@ -140,6 +192,17 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_parse_product_version() {
+        let plist = "<dict>\n\t<key>ProductName</key>\n\t<string>macOS</string>\n\t<key>ProductVersion</key>\n\t<string>14.4.1</string>\n</dict>";
+        assert_eq!(parse_product_version(plist), Some((14, 4)));
+        assert_eq!(
+            parse_product_version("<key>ProductVersion</key><string>11</string>"),
+            Some((11, 0))
+        );
+        assert_eq!(parse_product_version("<dict></dict>"), None);
+    }
+
     #[test]
     fn test_get_info() {
         let info = get_info();
@@ -0,0 +1,665 @@
+//! ML-KEM-768 key encapsulation (FIPS 203)
+//!
+//! Used alongside X25519 or P-256 ECDH for post-quantum hybrid recipients
+//! (see [`crate::keys::hybrid_encrypt`]). Only the ML-KEM-768 parameter set
+//! is implemented. Key pairs are expanded from a 64-byte seed `d || z`,
+//! which is also how [`crate::keys::AsymmetricKey`] stores them.
+//!
+//! Coefficients derived from secrets are never divided with a hardware
+//! divide, whose timing can depend on its operands; reduction mod q and
+//! compression multiply by a precomputed reciprocal and shift instead.
+//!
+//! This module is meant to be replaced by the RustCrypto `ml-kem` crate
+//! behind the same functions once it can be vendored; the OpenSSL vectors
+//! and the hybrid KAT in the tests stay as regression tests for that switch.
+
+use crate::keys::KeyError;
+use rand_core::RngCore;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Digest, Sha3_256, Sha3_512, Shake128, Shake256};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use zeroize::Zeroizing;
+
+/// Length of the key generation seed `d || z`
+pub const SEED_LEN: usize = 64;
+
+/// Length of an encapsulation (public) key
+pub const ENCAPSULATION_KEY_LEN: usize = 384 * K + 32;
+
+/// Length of an expanded decapsulation (private) key
+pub const DECAPSULATION_KEY_LEN: usize = 768 * K + 96;
+
+/// Length of a ciphertext
+pub const CIPHERTEXT_LEN: usize = 32 * (DU as usize * K + DV as usize);
+
+/// Length of the shared secret
+pub const SHARED_SECRET_LEN: usize = 32;
+
+const N: usize = 256;
+const Q: u16 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: u32 = 10;
+const DV: u32 = 4;
+
+/// Encoded length of a polynomial with 12-bit coefficients
+const POLY_BYTES: usize = 384;
+
+/// `ceil(2^40 / q)`, see [`divide`]
+const RECIPROCAL_Q: u64 = (1u64 << 40).div_ceil(Q as u64);
+
+/// `ceil(2^40 / 2q)`, see [`divide`]
+const RECIPROCAL_2Q: u64 = (1u64 << 40).div_ceil(2 * Q as u64);
+
+/// 128^-1 mod q, the scaling factor of the inverse NTT
+const INVERSE_128: u16 = 3303;
+
+type Poly = [u16; N];
+type PolyVec = [Poly; K];
+
+/// `17^BitRev7(i)`, the NTT twiddle factors
+const ZETAS: [u16; 128] = {
+    let mut zetas = [0u16; 128];
+    let mut i = 0;
+    while i < 128 {
+        zetas[i] = pow17(bit_rev7(i));
+        i += 1;
+    }
+    zetas
+};
+
+/// `17^(2 BitRev7(i) + 1)`, the base case multiplication factors
+const GAMMAS: [u16; 128] = {
+    let mut gammas = [0u16; 128];
+    let mut i = 0;
+    while i < 128 {
+        gammas[i] = pow17(2 * bit_rev7(i) + 1);
+        i += 1;
+    }
+    gammas
+};
+
+const fn bit_rev7(x: usize) -> usize {
+    let mut out = 0;
+    let mut bit = 0;
+    while bit < 7 {
+        out |= ((x >> bit) & 1) << (6 - bit);
+        bit += 1;
+    }
+    out
+}
+
+const fn pow17(exponent: usize) -> u16 {
+    let mut out = 1u32;
+    let mut i = 0;
+    while i < exponent {
+        out = out * 17 % Q as u32;
+        i += 1;
+    }
+    out as u16
+}
+
+/// Expand a seed into `(encapsulation key, decapsulation key)`
+///
+/// Implements `ML-KEM.KeyGen_internal` with `seed = d || z`.
+pub fn generate(seed: &[u8; SEED_LEN]) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+    let (d, z) = seed.split_at(32);
+    let (ek, dk_pke) = pke_generate(d);
+
+    let mut dk = Zeroizing::new(Vec::with_capacity(DECAPSULATION_KEY_LEN));
+    dk.extend_from_slice(&dk_pke);
+    dk.extend_from_slice(&ek);
+    dk.extend_from_slice(&h(&ek));
+    dk.extend_from_slice(z);
+    (ek, dk)
+}
+
+/// Check the length and coefficient range of an encapsulation key
+pub fn check_encapsulation_key(ek: &[u8]) -> Result<(), KeyError> {
+    if ek.len() != ENCAPSULATION_KEY_LEN {
+        return Err(KeyError::InvalidFormat);
+    }
+    // Every coefficient must already be reduced mod q
+    let mut encoded = [0u8; POLY_BYTES];
+    for chunk in ek[..POLY_BYTES * K].chunks(POLY_BYTES) {
+        byte_encode(&byte_decode(chunk, 12), 12, &mut encoded);
+        if encoded[..] != *chunk {
+            return Err(KeyError::InvalidFormat);
+        }
+    }
+    Ok(())
+}
+
+/// Encapsulate a fresh shared secret to `ek`
+///
+/// Returns the shared secret and the ciphertext to send to the key's owner.
+pub fn encapsulate(ek: &[u8]) -> Result<(Zeroizing<[u8; 32]>, Vec<u8>), KeyError> {
+    let mut m = Zeroizing::new([0u8; 32]);
+    rand_core::OsRng.fill_bytes(m.as_mut());
+    encapsulate_with(ek, &m)
+}
+
+/// `ML-KEM.Encaps_internal` with caller-chosen randomness `m`
+fn encapsulate_with(ek: &[u8], m: &[u8; 32]) -> Result<(Zeroizing<[u8; 32]>, Vec<u8>), KeyError> {
+    check_encapsulation_key(ek)?;
+    let (shared, r) = g(&[&m[..], &h(ek)[..]]);
+    Ok((shared, pke_encrypt(ek, m, &r)))
+}
+
+/// Recover the shared secret from a ciphertext
+///
+/// A ciphertext that was not produced for this key yields a pseudorandom
+/// secret rather than an error (implicit rejection), so callers find out
+/// through the authenticated encryption keyed with it.
+pub fn decapsulate(dk: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<[u8; 32]>, KeyError> {
+    if dk.len() != DECAPSULATION_KEY_LEN || ciphertext.len() != CIPHERTEXT_LEN {
+        return Err(KeyError::InvalidFormat);
+    }
+    let (dk_pke, rest) = dk.split_at(POLY_BYTES * K);
+    let (ek, rest) = rest.split_at(ENCAPSULATION_KEY_LEN);
+    let (hash, z) = rest.split_at(32);
+    if h(ek)[..] != *hash {
+        return Err(KeyError::InvalidFormat);
+    }
+
+    let m = pke_decrypt(dk_pke, ciphertext);
+    let (mut shared, r) = g(&[&m[..], hash]);
+    let rejected = j(z, ciphertext);
+    let reencrypted = pke_encrypt(ek, &m, &r);
+
+    let matches = ciphertext.ct_eq(&reencrypted);
+    for (byte, fallback) in shared.iter_mut().zip(rejected.iter()) {
+        *byte = u8::conditional_select(fallback, byte, matches);
+    }
+    Ok(shared)
+}
+
+/// `K-PKE.KeyGen`: returns `(ek, dk_pke)`
+fn pke_generate(d: &[u8]) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+    let (rho, sigma) = g(&[d, &[K as u8][..]]);
+    let a = sample_matrix(&rho[..]);
+
+    let mut s = Zeroizing::new([[0u16; N]; K]);
+    let mut e = Zeroizing::new([[0u16; N]; K]);
+    for (nonce, poly) in s.iter_mut().chain(e.iter_mut()).enumerate() {
+        *poly = sample_cbd(&prf(&sigma[..], nonce as u8, ETA1), ETA1);
+        ntt(poly);
+    }
+
+    let mut ek = vec![0u8; ENCAPSULATION_KEY_LEN];
+    let mut dk = Zeroizing::new(vec![0u8; POLY_BYTES * K]);
+    for i in 0..K {
+        let mut t = e[i];
+        for (a, s) in a[i].iter().zip(s.iter()) {
+            add_assign(&mut t, &multiply_ntts(a, s));
+        }
+        byte_encode(&t, 12, &mut ek[i * POLY_BYTES..(i + 1) * POLY_BYTES]);
+        byte_encode(&s[i], 12, &mut dk[i * POLY_BYTES..(i + 1) * POLY_BYTES]);
+    }
+    ek[POLY_BYTES * K..].copy_from_slice(&rho[..]);
+    (ek, dk)
+}
+
+/// `K-PKE.Encrypt` of the 32-byte message `m` with randomness `r`
+fn pke_encrypt(ek: &[u8], m: &[u8; 32], r: &[u8; 32]) -> Vec<u8> {
+    let (t, rho) = ek.split_at(POLY_BYTES * K);
+    let a = sample_matrix(rho);
+
+    let mut nonce = 0u8;
+    let mut y = Zeroizing::new([[0u16; N]; K]);
+    for poly in y.iter_mut() {
+        *poly = sample_cbd(&prf(&r[..], nonce, ETA1), ETA1);
+        ntt(poly);
+        nonce += 1;
+    }
+
+    let mut ciphertext = vec![0u8; CIPHERTEXT_LEN];
+    let du_bytes = 32 * DU as usize;
+    for i in 0..K {
+        let mut u = [0u16; N];
+        for (row, y) in a.iter().zip(y.iter()) {
+            add_assign(&mut u, &multiply_ntts(&row[i], y));
+        }
+        ntt_inverse(&mut u);
+        add_assign(&mut u, &sample_cbd(&prf(&r[..], nonce, ETA2), ETA2));
+        nonce += 1;
+        compress(&mut u, DU);
+        byte_encode(&u, DU, &mut ciphertext[i * du_bytes..(i + 1) * du_bytes]);
+    }
+
+    let mut v = [0u16; N];
+    for (t, y) in t.chunks(POLY_BYTES).zip(y.iter()) {
+        add_assign(&mut v, &multiply_ntts(&byte_decode(t, 12), y));
+    }
+    ntt_inverse(&mut v);
+    add_assign(&mut v, &sample_cbd(&prf(&r[..], nonce, ETA2), ETA2));
+    let mut mu = byte_decode(m, 1);
+    decompress(&mut mu, 1);
+    add_assign(&mut v, &mu);
+    compress(&mut v, DV);
+    byte_encode(&v, DV, &mut ciphertext[du_bytes * K..]);
+    ciphertext
+}
+
+/// `K-PKE.Decrypt`: recover the message from a ciphertext
+fn pke_decrypt(dk_pke: &[u8], ciphertext: &[u8]) -> Zeroizing<[u8; 32]> {
+    let du_bytes = 32 * DU as usize;
+    let mut product = [0u16; N];
+    for (c, s) in ciphertext[..du_bytes * K]
+        .chunks(du_bytes)
+        .zip(dk_pke.chunks(POLY_BYTES))
+    {
+        let mut u = byte_decode(c, DU);
+        decompress(&mut u, DU);
+        ntt(&mut u);
+        let s = Zeroizing::new(byte_decode(s, 12));
+        add_assign(&mut product, &multiply_ntts(&s, &u));
+    }
+    ntt_inverse(&mut product);
+
+    let mut w = byte_decode(&ciphertext[du_bytes * K..], DV);
+    decompress(&mut w, DV);
+    for (w, p) in w.iter_mut().zip(product.iter()) {
+        *w = sub(*w, *p);
+    }
+    compress(&mut w, 1);
+
+    let mut m = Zeroizing::new([0u8; 32]);
+    byte_encode(&w, 1, m.as_mut());
+    m
+}
+
+/// The public matrix Â, with `Â[i][j]` sampled from `rho || j || i`
+fn sample_matrix(rho: &[u8]) -> [PolyVec; K] {
+    let mut a = [[[0u16; N]; K]; K];
+    for (i, row) in a.iter_mut().enumerate() {
+        for (j, entry) in row.iter_mut().enumerate() {
+            *entry = sample_ntt(rho, j as u8, i as u8);
+        }
+    }
+    a
+}
+
+/// `SampleNTT`: rejection-sample a polynomial in NTT form from SHAKE128
+fn sample_ntt(rho: &[u8], j: u8, i: u8) -> Poly {
+    let mut xof = Shake128::default();
+    xof.update(rho);
+    xof.update(&[j, i]);
+    let mut reader = xof.finalize_xof();
+
+    let mut poly = [0u16; N];
+    let mut count = 0;
+    let mut bytes = [0u8; 3];
+    while count < N {
+        reader.read(&mut bytes);
+        let d1 = u16::from(bytes[0]) | (u16::from(bytes[1] & 0x0f) << 8);
+        let d2 = u16::from(bytes[1] >> 4) | (u16::from(bytes[2]) << 4);
+        for d in [d1, d2] {
+            if d < Q && count < N {
+                poly[count] = d;
+                count += 1;
+            }
+        }
+    }
+    poly
+}
+
+/// `SamplePolyCBD`: centered binomial sample from `64 * eta` bytes
+fn sample_cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |index: usize| u16::from((bytes[index / 8] >> (index % 8)) & 1);
+    let mut poly = [0u16; N];
+    for (i, coefficient) in poly.iter_mut().enumerate() {
+        let (mut x, mut y) = (0, 0);
+        for j in 0..eta {
+            x += bit(2 * i * eta + j);
+            y += bit(2 * i * eta + eta + j);
+        }
+        *coefficient = sub(x, y);
+    }
+    poly
+}
+
+/// `PRF_eta(s, b)`: SHAKE256 of `s || b`, `64 * eta` bytes long
+fn prf(s: &[u8], b: u8, eta: usize) -> Zeroizing<Vec<u8>> {
+    let mut out = Zeroizing::new(vec![0u8; 64 * eta]);
+    Shake256::default()
+        .chain(s)
+        .chain([b])
+        .finalize_xof_into(&mut out[..]);
+    out
+}
+
+/// `G`: SHA3-512 of the concatenated inputs, split into two halves
+fn g(inputs: &[&[u8]]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let mut hasher = Sha3_512::new();
+    for input in inputs {
+        Digest::update(&mut hasher, input);
+    }
+    let digest = Zeroizing::new(hasher.finalize());
+    let mut first = Zeroizing::new([0u8; 32]);
+    let mut second = Zeroizing::new([0u8; 32]);
+    first.copy_from_slice(&digest[..32]);
+    second.copy_from_slice(&digest[32..]);
+    (first, second)
+}
+
+/// `H`: SHA3-256
+fn h(input: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(input).into()
+}
+
+/// `J`: SHAKE256 of `z || c`, 32 bytes long
+fn j(z: &[u8], ciphertext: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut out = Zeroizing::new([0u8; 32]);
+    Shake256::default()
+        .chain(z)
+        .chain(ciphertext)
+        .finalize_xof_into(out.as_mut());
+    out
+}
+
+/// `x / d` for `x < 2^24`, where `reciprocal` is `ceil(2^40 / d)`
+///
+/// Exact because `x` times the rounding error of `reciprocal` stays below
+/// `2^40` for `d < 2^16`.
+fn divide(x: u32, reciprocal: u64) -> u32 {
+    debug_assert!(x < 1 << 24);
+    ((u64::from(x) * reciprocal) >> 40) as u32
+}
+
+/// `x mod q` for `x < 2^24`
+fn reduce(x: u32) -> u16 {
+    (x - divide(x, RECIPROCAL_Q) * u32::from(Q)) as u16
+}
+
+fn add(a: u16, b: u16) -> u16 {
+    reduce(u32::from(a) + u32::from(b))
+}
+
+fn sub(a: u16, b: u16) -> u16 {
+    reduce(u32::from(a) + u32::from(Q) - u32::from(b))
+}
+
+fn mul(a: u16, b: u16) -> u16 {
+    reduce(u32::from(a) * u32::from(b))
+}
+
+fn add_assign(f: &mut Poly, g: &Poly) {
+    for (a, b) in f.iter_mut().zip(g.iter()) {
+        *a = add(*a, *b);
+    }
+}
+
+/// Number-theoretic transform, in place
+fn ntt(f: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k += 1;
+            for j in start..start + len {
+                let t = mul(zeta, f[j + len]);
+                f[j + len] = sub(f[j], t);
+                f[j] = add(f[j], t);
+            }
+        }
+        len /= 2;
+    }
+}
+
+/// Inverse number-theoretic transform, in place
+fn ntt_inverse(f: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = add(t, f[j + len]);
+                f[j + len] = mul(zeta, sub(f[j + len], t));
+            }
+        }
+        len *= 2;
+    }
+    for coefficient in f.iter_mut() {
+        *coefficient = mul(*coefficient, INVERSE_128);
+    }
+}
+
+/// Product of two polynomials in NTT form
+fn multiply_ntts(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0u16; N];
+    for i in 0..128 {
+        let (a0, a1) = (f[2 * i], f[2 * i + 1]);
+        let (b0, b1) = (g[2 * i], g[2 * i + 1]);
+        h[2 * i] = add(mul(a0, b0), mul(mul(a1, b1), GAMMAS[i]));
+        h[2 * i + 1] = add(mul(a0, b1), mul(a1, b0));
+    }
+    h
+}
+
+/// `Compress_d`, in place: round `2^d x / q`
+fn compress(f: &mut Poly, d: u32) {
+    for x in f.iter_mut() {
+        let scaled = (u32::from(*x) << (d + 1)) + u32::from(Q);
+        *x = (divide(scaled, RECIPROCAL_2Q) & ((1 << d) - 1)) as u16;
+    }
+}
+
+/// `Decompress_d`, in place: round `q y / 2^d`
+fn decompress(f: &mut Poly, d: u32) {
+    for y in f.iter_mut() {
+        *y = ((u32::from(*y) * u32::from(Q) + (1 << (d - 1))) >> d) as u16;
+    }
+}
+
+/// `ByteEncode_d`: pack `d`-bit coefficients little endian into `32 d` bytes
+fn byte_encode(f: &Poly, d: u32, out: &mut [u8]) {
+    let (mut acc, mut bits, mut pos) = (0u32, 0u32, 0);
+    for &coefficient in f {
+        acc |= u32::from(coefficient) << bits;
+        bits += d;
+        while bits >= 8 {
+            out[pos] = acc as u8;
+            pos += 1;
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+}
+
+/// `ByteDecode_d`: unpack `32 d` bytes (12-bit values are reduced mod q)
+fn byte_decode(bytes: &[u8], d: u32) -> Poly {
+    let mask = (1u32 << d) - 1;
+    let mut poly = [0u16; N];
+    let (mut acc, mut bits, mut index) = (0u32, 0u32, 0);
+    for &byte in bytes {
+        acc |= u32::from(byte) << bits;
+        bits += 8;
+        while bits >= d {
+            let value = (acc & mask) as u16;
+            poly[index] = if d == 12 {
+                reduce(u32::from(value))
+            } else {
+                value
+            };
+            index += 1;
+            acc >>= d;
+            bits -= d;
+        }
+    }
+    poly
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Seed 00 01 .. 3f, as passed to `openssl genpkey -algorithm ML-KEM-768
+    /// -pkeyopt hexseed:...`
+    fn test_seed() -> [u8; SEED_LEN] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn sha3_hex(data: &[u8]) -> String {
+        hex::encode(Sha3_256::digest(data))
+    }
+
+    #[test]
+    fn test_openssl_vectors() {
+        // Cross-checked against OpenSSL 3.5: key generation from a seed,
+        // `pkeyutl -encap -pkeyopt hexikme:64..83`, and `pkeyutl -decap`
+        // of the ciphertext with its first byte flipped
+        let (ek, dk) = generate(&test_seed());
+        assert_eq!(ek.len(), ENCAPSULATION_KEY_LEN);
+        assert_eq!(
+            sha3_hex(&ek),
+            "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7"
+        );
+        assert_eq!(
+            sha3_hex(&dk),
+            "1149f17c3c4ac6ab1e3e2d9d8bd0171355ac0fa31bb8855c48ceade874c0864b"
+        );
+
+        let m: [u8; 32] = std::array::from_fn(|i| 100 + i as u8);
+        let (shared, mut ciphertext) = encapsulate_with(&ek, &m).unwrap();
+        assert_eq!(ciphertext.len(), CIPHERTEXT_LEN);
+        assert_eq!(
+            sha3_hex(&ciphertext),
+            "ce221a0989a8597aa562b69a8c235edc93ccf72fadc91d96785c9a09075e5cd1"
+        );
+        assert_eq!(
+            hex::encode(shared.as_ref()),
+            "c5a74110c158acbaf9c01deb86fa6cc10c14533feda54bec1fdd000d61f07e4e"
+        );
+        assert_eq!(*decapsulate(&dk, &ciphertext).unwrap(), *shared);
+
+        // Implicit rejection
+        ciphertext[0] ^= 1;
+        assert_eq!(
+            hex::encode(decapsulate(&dk, &ciphertext).unwrap().as_ref()),
+            "bb28c25ed3222c13ce49d65f663f1c9f148565a664747e142f1abe06f33f4826"
+        );
+    }
+
+    #[test]
+    fn test_openssl_vectors_other_seeds() {
+        // Generated as above with OpenSSL 3.5; implicit rejection flips the
+        // top bit of the last ciphertext byte. Columns: seed, m, SHA3-256 of
+        // the encapsulation key and ciphertext, shared secret, rejection
+        // secret
+        type Bytes = fn(usize) -> u8;
+        let cases: [(Bytes, Bytes, &str, &str, &str, &str); 3] = [
+            (
+                |i| 255 - i as u8,
+                |i| (7 * i) as u8,
+                "87272f8dd8572f17da12e139463ed26488a49ec76bd51174a3a5687084d8dc00",
+                "27611d5cafc9bf99bc5b97f9f8446127d31f43d590a641c7a1ba3a69c1ebd527",
+                "40c86e417adf1326796fc79eaf84a81ae41ea61d209bb0d467987717f474e41b",
+                "2a04c95e05ca7915df6401bece8a3761a64e2871dfb9b4adc2b74a5ac47c9085",
+            ),
+            (
+                |i| (i * 37 + 11) as u8,
+                |_| 0xff,
+                "de4b2307557d9a657bd65dfd7bcf40652617ce3ff4a90cd6c519059afdabce59",
+                "7ccf069c137091c032269a4b8e003dbe769453595bb2552578622d144318bfc9",
+                "5f999d805abf58c59c7108066fbde30b2b1f8d483101c3b62dff3e3513ddf428",
+                "7b5a2e2329fdb1adfc8b299f25128ff814b75d4eabef27af6de1037f0248a67f",
+            ),
+            (
+                |_| 0,
+                |_| 0,
+                "07f81a8b0e266a3ee92d3a63cdae5cff921905544c9dd797a849e1d054180eca",
+                "458a9896b26a4cba613b45288e09d89f688d69f181d4f11e3c486057fb3066ac",
+                "b4d29cd55bab43e16554b74b9098cdfce583996c968bcd2cfd1ad9455e351fbf",
+                "6c3e6a5f59d8c0a0ab4694e3eadd0a08811042a77f38a09455e397b6344538df",
+            ),
+        ];
+
+        for (seed, m, ek_hash, ciphertext_hash, shared, rejected) in cases {
+            let (ek, dk) = generate(&std::array::from_fn(seed));
+            assert_eq!(sha3_hex(&ek), ek_hash);
+            let (secret, mut ciphertext) = encapsulate_with(&ek, &std::array::from_fn(m)).unwrap();
+            assert_eq!(sha3_hex(&ciphertext), ciphertext_hash);
+            assert_eq!(hex::encode(secret.as_ref()), shared);
+            assert_eq!(*decapsulate(&dk, &ciphertext).unwrap(), *secret);
+
+            ciphertext[CIPHERTEXT_LEN - 1] ^= 0x80;
+            assert_eq!(
+                hex::encode(decapsulate(&dk, &ciphertext).unwrap().as_ref()),
+                rejected
+            );
+        }
+    }
+
+    #[test]
+    fn test_division_by_reciprocal() {
+        for x in 0..1u32 << 24 {
+            assert_eq!(divide(x, RECIPROCAL_Q), x / u32::from(Q));
+            assert_eq!(divide(x, RECIPROCAL_2Q), x / (2 * u32::from(Q)));
+        }
+        for d in [1, DV, DU, 11] {
+            for x in 0..Q {
+                let mut f = [x; N];
+                compress(&mut f, d);
+                let expected = ((u32::from(x) << (d + 1)) + u32::from(Q)) / (2 * u32::from(Q));
+                assert_eq!(
+                    u32::from(f[0]),
+                    expected & ((1 << d) - 1),
+                    "x = {x}, d = {d}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut seed = [0u8; SEED_LEN];
+        rand_core::OsRng.fill_bytes(&mut seed);
+        let (ek, dk) = generate(&seed);
+
+        let (shared, ciphertext) = encapsulate(&ek).unwrap();
+        assert_eq!(*decapsulate(&dk, &ciphertext).unwrap(), *shared);
+
+        let (other, _) = encapsulate(&ek).unwrap();
+        assert_ne!(*other, *shared);
+    }
+
+    #[test]
+    fn test_key_checks() {
+        let (mut ek, mut dk) = generate(&test_seed());
+        assert!(check_encapsulation_key(&ek).is_ok());
+        assert!(check_encapsulation_key(&ek[1..]).is_err());
+
+        // A coefficient of q (0xd01) is not reduced
+        ek[0] = 0x01;
+        ek[1] = (ek[1] & 0xf0) | 0x0d;
+        assert!(matches!(encapsulate(&ek), Err(KeyError::InvalidFormat)));
+
+        let (_, ciphertext) = encapsulate(&generate(&test_seed()).0).unwrap();
+        assert!(decapsulate(&dk, &ciphertext[1..]).is_err());
+        // The embedded hash of the public key must match
+        dk[POLY_BYTES * K] ^= 1;
+        assert!(matches!(
+            decapsulate(&dk, &ciphertext),
+            Err(KeyError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn test_compression_rounding() {
+        let mut f = [0u16; N];
+        f[..4].copy_from_slice(&[0, 832, 833, 3328]);
+        compress(&mut f, 1);
+        assert_eq!(f[..4], [0, 0, 1, 0]);
+
+        let mut f = [0u16; N];
+        f[..2].copy_from_slice(&[0, 1]);
+        decompress(&mut f, 1);
+        assert_eq!(f[..2], [0, 1665]);
+    }
+}
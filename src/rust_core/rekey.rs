@@ -0,0 +1,765 @@
+//! Re-encryption of device data after key rotation
+//!
+//! `rekey` rewrites every encrypted file, snapshot manifest, chunk and chunk
+//! index on a device whose data key is wrapped under an older key version so that the current
+//! key wraps it instead. Only the header changes: the data key is unwrapped
+//! with the old version and wrapped again with the current one, and the
+//! payload is copied unchanged into a temporary file next to the original.
+//! The temporary file is then fully decrypted with the current key before it
+//! atomically replaces the original, so an interrupted run never leaves a
+//! file unreadable.
+//!
+//! Files in header format version 1 have no data key to re-wrap; their
+//! payload is decrypted with the key version they name and encrypted again
+//! under a fresh data key in the current format, whatever that version is.
+//!
+//! Progress is recorded in a checkpoint file on the device after every
+//! file, and a later run for the same target version resumes from it.
+
+use crate::dedup;
+use crate::header::{self, FileHeader};
+use crate::keystore::{EncryptionKey, KeyStore};
+use crate::snapshot::{self, MANIFEST_EXTENSION};
+use crate::sync::{self, DEVICE_ROOT, ENCRYPTED_EXTENSION};
+use crate::{AirGapError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Checkpoint file name under the device root
+pub const CHECKPOINT_FILE: &str = "rekey.checkpoint";
+
+/// Extension of in-progress rewrites
+const TEMP_EXTENSION: &str = "rekey";
+
+/// Segment size for payloads encrypted again from format version 1
+const SEGMENT_SIZE: usize = 1 << 20;
+
+/// What happened to a single encrypted file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyAction {
+    /// Rewritten under the current key version
+    Rekeyed,
+    /// Already used the current key version
+    Current,
+    /// Completed by an earlier, interrupted run
+    Resumed,
+    /// Could not be rewritten; the original is unchanged
+    Failed,
+}
+
+/// Progress notification for one file
+#[derive(Debug, Clone)]
+pub struct RekeyProgress<'a> {
+    /// Files handled so far, including this one
+    pub processed: usize,
+    /// Encrypted files found on the device
+    pub total: usize,
+    /// Path relative to the data directory, or to the device root (such as
+    /// `snapshots/<id>.manifest`) for snapshot manifests and chunks
+    pub path: &'a Path,
+    /// Outcome for this file
+    pub action: RekeyAction,
+}
+
+/// Summary of a completed rekey run
+#[derive(Debug, Clone)]
+pub struct RekeyReport {
+    /// Device whose data was rewritten
+    pub device_id: String,
+    /// Key version all data now uses
+    pub target_version: u32,
+    /// Files rewritten by this run
+    pub rekeyed: u64,
+    /// Files that already used the target version
+    pub current: u64,
+    /// Files skipped because an earlier run completed them
+    pub resumed: u64,
+    /// Files that could not be rewritten
+    pub failures: Vec<sync::SyncFailure>,
+}
+
+impl RekeyReport {
+    /// Whether every file now uses the target key version
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// What an encrypted file on the device holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    /// Encrypted copy of a source file
+    Data,
+    /// Snapshot manifest
+    Manifest,
+    /// Deduplicated chunk
+    Chunk,
+    /// Chunk index
+    ChunkIndex,
+}
+
+/// Persistent record of files finished by a rekey run
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    device_id: String,
+    target_version: u32,
+    completed: BTreeSet<String>,
+}
+
+/// Re-encrypts a device's data under its current key
+pub struct RekeyJob<'a> {
+    store: &'a dyn KeyStore,
+    device_id: String,
+    mount_point: PathBuf,
+}
+
+impl<'a> RekeyJob<'a> {
+    /// Prepare to rekey the data for `device_id` on `mount_point`
+    pub fn new(store: &'a dyn KeyStore, device_id: &str, mount_point: impl Into<PathBuf>) -> Self {
+        Self {
+            store,
+            device_id: device_id.to_string(),
+            mount_point: mount_point.into(),
+        }
+    }
+
+    /// Location of the checkpoint file on the device
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.mount_point.join(DEVICE_ROOT).join(CHECKPOINT_FILE)
+    }
+
+    /// Rewrite every file not yet under the current key version
+    ///
+    /// `progress` is called once per encrypted file found on the device.
+    pub fn run(&self, progress: &mut dyn FnMut(&RekeyProgress)) -> Result<RekeyReport> {
+        if !self.mount_point.is_dir() {
+            return Err(AirGapError::DeviceNotFound(format!(
+                "{} (mount point {} is not available)",
+                self.device_id,
+                self.mount_point.display()
+            )));
+        }
+
+        let current = self.store.get_key(&self.device_id)?;
+        let target_version = current.metadata.version;
+        let data_dir = sync::device_data_dir(&self.mount_point);
+        let device_root = self.mount_point.join(DEVICE_ROOT);
+
+        let mut checkpoint = self.load_checkpoint(target_version)?;
+        let mut files = encrypted_files(&data_dir, ENCRYPTED_EXTENSION, FileKind::Data)?;
+        files.extend(encrypted_files(
+            &snapshot::snapshot_dir(&self.mount_point),
+            MANIFEST_EXTENSION,
+            FileKind::Manifest,
+        )?);
+        let chunk_dir = dedup::chunk_dir(&self.mount_point);
+        files.extend(encrypted_files(
+            &chunk_dir,
+            dedup::CHUNK_EXTENSION,
+            FileKind::Chunk,
+        )?);
+        files.extend(encrypted_files(
+            &chunk_dir,
+            dedup::INDEX_EXTENSION,
+            FileKind::ChunkIndex,
+        )?);
+
+        let mut report = RekeyReport {
+            device_id: self.device_id.clone(),
+            target_version,
+            rekeyed: 0,
+            current: 0,
+            resumed: 0,
+            failures: Vec::new(),
+        };
+
+        for (index, (path, kind)) in files.iter().enumerate() {
+            let relative = match kind {
+                FileKind::Data => path.strip_prefix(&data_dir),
+                FileKind::Manifest | FileKind::Chunk | FileKind::ChunkIndex => {
+                    path.strip_prefix(&device_root)
+                }
+            }
+            .unwrap_or(path);
+            let key = checkpoint_key(relative);
+
+            let action = if checkpoint.completed.contains(&key) {
+                report.resumed += 1;
+                RekeyAction::Resumed
+            } else {
+                match self.rekey_file(path, *kind, &current) {
+                    Ok(action) => {
+                        match action {
+                            RekeyAction::Current => report.current += 1,
+                            _ => report.rekeyed += 1,
+                        }
+                        checkpoint.completed.insert(key);
+                        self.save_checkpoint(&checkpoint)?;
+                        action
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to rekey {}: {e}", relative.display());
+                        report.failures.push(sync::SyncFailure {
+                            path: relative.to_path_buf(),
+                            reason: e.to_string(),
+                        });
+                        RekeyAction::Failed
+                    }
+                }
+            };
+
+            progress(&RekeyProgress {
+                processed: index + 1,
+                total: files.len(),
+                path: relative,
+                action,
+            });
+        }
+
+        // Keep the checkpoint while failures remain so a rerun skips the
+        // files that are already done
+        if report.is_success() {
+            match fs::remove_file(self.checkpoint_path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Rewrite one file under `current` if it uses an older version
+    fn rekey_file(
+        &self,
+        path: &Path,
+        kind: FileKind,
+        current: &EncryptionKey,
+    ) -> Result<RekeyAction> {
+        let unexpected =
+            || AirGapError::SyncError(format!("Unexpected file name: {}", path.display()));
+        let context = match kind {
+            FileKind::Data => {
+                let data_dir = sync::device_data_dir(&self.mount_point);
+                sync::file_aad(&sync::source_relative_path(&data_dir, path).ok_or_else(unexpected)?)
+            }
+            FileKind::Manifest => {
+                let snapshot_id = path.file_stem().and_then(|s| s.to_str());
+                snapshot::snapshot_aad(snapshot_id.ok_or_else(unexpected)?)
+            }
+            FileKind::Chunk => {
+                dedup::chunk_aad(&dedup::chunk_id_from_path(path).ok_or_else(unexpected)?)
+            }
+            FileKind::ChunkIndex => dedup::INDEX_AAD.to_string(),
+        };
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = FileHeader::read_from(&mut reader)?;
+        let old_version = match header.device_key() {
+            Some((device_id, version)) if device_id == self.device_id => version,
+            other => {
+                return Err(AirGapError::SyncError(format!(
+                    "Encrypted for device {}, not {}",
+                    other.map_or("(none)", |(device_id, _)| device_id),
+                    self.device_id
+                )))
+            }
+        };
+        let legacy = header.version == 1;
+        if old_version == current.metadata.version && !legacy {
+            return Ok(RekeyAction::Current);
+        }
+
+        let old_key = self.store.get_key_version(&self.device_id, old_version)?;
+        if !legacy {
+            header.rewrap_device_key(&old_key, current)?;
+        }
+
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".");
+        temp.push(TEMP_EXTENSION);
+        let temp = PathBuf::from(temp);
+
+        let result = (|| -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            if legacy {
+                // The old payload is under the device key itself, so it is
+                // encrypted again under a new data key
+                let data_key = header.open_with_device_key(&old_key)?;
+                let mut plaintext =
+                    header::decryptor(&data_key, &header, &mut reader, context.as_bytes())?;
+                let (new_header, new_key) = FileHeader::seal(current, &[])?;
+                header::encrypt_file(
+                    &new_key,
+                    &new_header,
+                    &mut plaintext,
+                    &mut writer,
+                    SEGMENT_SIZE,
+                    context.as_bytes(),
+                )?;
+            } else {
+                // Write the new header and copy the payload through unchanged
+                writer.write_all(&header.encode()?)?;
+                io::copy(&mut reader, &mut writer)?;
+            }
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+
+            // Verify the rewrite opens with the current key and the whole
+            // payload still authenticates
+            let mut reader = BufReader::new(File::open(&temp)?);
+            let written_header = FileHeader::read_from(&mut reader)?;
+            let data_key = written_header.open_with_device_key(current)?;
+            header::decrypt_file(
+                &data_key,
+                &written_header,
+                reader,
+                &mut io::sink(),
+                context.as_bytes(),
+            )?;
+
+            fs::rename(&temp, path)?;
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result.map(|_| RekeyAction::Rekeyed)
+    }
+
+    /// Load the checkpoint for this device and target version, if any
+    ///
+    /// A checkpoint for a different device or key version is stale and
+    /// ignored; files it lists are checked again from their headers.
+    fn load_checkpoint(&self, target_version: u32) -> Result<Checkpoint> {
+        let fresh = Checkpoint {
+            device_id: self.device_id.clone(),
+            target_version,
+            completed: BTreeSet::new(),
+        };
+
+        let contents = match fs::read(self.checkpoint_path()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(fresh),
+            Err(e) => return Err(e.into()),
+        };
+        let checkpoint: Checkpoint = serde_json::from_slice(&contents)
+            .map_err(|e| AirGapError::SyncError(format!("Corrupt rekey checkpoint: {e}")))?;
+
+        if checkpoint.device_id == self.device_id && checkpoint.target_version == target_version {
+            log::info!(
+                "Resuming rekey of {}: {} files already done",
+                self.device_id,
+                checkpoint.completed.len()
+            );
+            Ok(checkpoint)
+        } else {
+            Ok(fresh)
+        }
+    }
+
+    /// Atomically replace the checkpoint file
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let path = self.checkpoint_path();
+        let temp = path.with_extension("tmp");
+        let contents = serde_json::to_vec(checkpoint)
+            .map_err(|e| AirGapError::SyncError(format!("Failed to save checkpoint: {e}")))?;
+
+        let mut file = File::create(&temp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+}
+
+/// Files of one kind under `dir`, sorted, after removing stale rewrites
+fn encrypted_files(
+    dir: &Path,
+    extension: &str,
+    kind: FileKind,
+) -> Result<Vec<(PathBuf, FileKind)>> {
+    let mut files = Vec::new();
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| AirGapError::SyncError(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match entry.path().extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext == extension => files.push((entry.into_path(), kind)),
+            // Left behind by an interrupted run; the original is intact
+            Some(TEMP_EXTENSION) => fs::remove_file(entry.path())?,
+            _ => {}
+        }
+    }
+    Ok(files)
+}
+
+/// Checkpoint entry for a file's path as reported in progress
+fn checkpoint_key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::keystore::{generate_key, rotate_key, MemoryKeyStore};
+    use crate::sync::SyncEngine;
+
+    struct Fixture {
+        source: tempfile::TempDir,
+        device: tempfile::TempDir,
+        store: MemoryKeyStore,
+    }
+
+    impl Fixture {
+        fn new(files: &[(&str, &[u8])]) -> Self {
+            let source = tempfile::tempdir().unwrap();
+            let device = tempfile::tempdir().unwrap();
+            for (name, contents) in files {
+                let path = source.path().join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+
+            let store = MemoryKeyStore::new();
+            store
+                .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+                .unwrap();
+
+            let fixture = Self {
+                source,
+                device,
+                store,
+            };
+            fixture.sync();
+            fixture
+        }
+
+        fn config(&self) -> Config {
+            let mut config: Config = toml::from_str(&format!(
+                "[general]\n[source]\npath = {:?}\n[[device]]\nid = \"USB001\"\nname = \"USB\"\nmount_point = {:?}\n[policy]\n[security]\nkey_store = \"vault\"\n[notifications]\n[advanced]\n",
+                self.source.path(),
+                self.device.path()
+            ))
+            .unwrap();
+            config.policy.chunk_size_mb = 1;
+            config
+        }
+
+        fn sync(&self) {
+            let key = self.store.get_key("USB001").unwrap();
+            let config = self.config();
+            let report = SyncEngine::new(&config, "USB001", &key)
+                .unwrap()
+                .run()
+                .unwrap();
+            assert!(report.is_success());
+        }
+
+        fn job(&self) -> RekeyJob<'_> {
+            RekeyJob::new(&self.store, "USB001", self.device.path())
+        }
+
+        fn versions(&self) -> BTreeSet<u32> {
+            sync::key_versions_in_use(self.device.path())
+                .unwrap()
+                .remove("USB001")
+                .unwrap_or_default()
+        }
+
+        fn payload(&self, name: &str) -> Vec<u8> {
+            let data_dir = sync::device_data_dir(self.device.path());
+            let file = fs::read(sync::encrypted_path(&data_dir, Path::new(name))).unwrap();
+            let mut reader = &file[..];
+            FileHeader::read_from(&mut reader).unwrap();
+            reader.to_vec()
+        }
+
+        fn decrypt(&self, name: &str) -> Vec<u8> {
+            let data_dir = sync::device_data_dir(self.device.path());
+            let path = sync::encrypted_path(&data_dir, Path::new(name));
+            let mut reader = BufReader::new(File::open(path).unwrap());
+            let header = FileHeader::read_from(&mut reader).unwrap();
+            let (_, version) = header.device_key().unwrap();
+            let key = self.store.get_key_version("USB001", version).unwrap();
+            let mut out = Vec::new();
+            header::decrypt_file(
+                &header.open_with_device_key(&key).unwrap(),
+                &header,
+                reader,
+                &mut out,
+                sync::file_aad(Path::new(name)).as_bytes(),
+            )
+            .unwrap();
+            out
+        }
+
+        /// Write `name` in header format version 1, with the payload
+        /// encrypted directly under the current device key
+        fn write_version_1(&self, name: &str, contents: &[u8]) {
+            let key = self.store.get_key("USB001").unwrap();
+            let mut file = b"AGSF\x01\x01".to_vec();
+            file.extend_from_slice(&key.metadata.version.to_be_bytes());
+            file.push(6);
+            file.extend_from_slice(b"USB001");
+            file.push(0);
+            let mut aad = file.clone();
+            aad.extend_from_slice(sync::file_aad(Path::new(name)).as_bytes());
+            crate::stream::encrypt_stream(
+                &key.to_crypto_key().unwrap(),
+                &mut &contents[..],
+                &mut file,
+                64,
+                &aad,
+            )
+            .unwrap();
+            let data_dir = sync::device_data_dir(self.device.path());
+            fs::write(sync::encrypted_path(&data_dir, Path::new(name)), file).unwrap();
+        }
+
+        fn format_version(&self, name: &str) -> u8 {
+            let data_dir = sync::device_data_dir(self.device.path());
+            let file = fs::read(sync::encrypted_path(&data_dir, Path::new(name))).unwrap();
+            FileHeader::read_from(&mut &file[..]).unwrap().version
+        }
+    }
+
+    #[test]
+    fn test_rekey_rewrites_old_versions() {
+        let big = vec![3u8; 1000];
+        let fixture = Fixture::new(&[("a.txt", b"alpha"), ("docs/b.bin", &big)]);
+        rotate_key(&fixture.store, "USB001").unwrap();
+        assert_eq!(fixture.versions(), BTreeSet::from([1]));
+        let payload = fixture.payload("docs/b.bin");
+
+        let mut seen = Vec::new();
+        let report = fixture
+            .job()
+            .run(&mut |p| seen.push((p.processed, p.total, p.action)))
+            .unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(report.target_version, 2);
+        // Both files and the snapshot manifest
+        assert_eq!(report.rekeyed, 3);
+        assert_eq!(
+            seen,
+            vec![
+                (1, 3, RekeyAction::Rekeyed),
+                (2, 3, RekeyAction::Rekeyed),
+                (3, 3, RekeyAction::Rekeyed)
+            ]
+        );
+        assert_eq!(fixture.versions(), BTreeSet::from([2]));
+        let current = fixture.store.get_key("USB001").unwrap();
+        let manifest = snapshot::SnapshotReader::new(&current)
+            .latest(fixture.device.path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(manifest.key_version, 1);
+        assert_eq!(fixture.decrypt("a.txt"), b"alpha");
+        assert_eq!(fixture.decrypt("docs/b.bin"), big);
+        // Only the wrapped data key changed
+        assert_eq!(fixture.payload("docs/b.bin"), payload);
+        assert!(!fixture.job().checkpoint_path().exists());
+
+        // A second run has nothing left to do
+        let report = fixture.job().run(&mut |_| {}).unwrap();
+        assert_eq!((report.rekeyed, report.current), (0, 3));
+    }
+
+    #[test]
+    fn test_rekey_rewrites_chunks() {
+        use crate::dedup::{ChunkId, ChunkStore, Chunker};
+
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        let mut config = fixture.config();
+        config.advanced.experimental_dedup = true;
+        let key = fixture.store.get_key("USB001").unwrap();
+        let report = SyncEngine::new(&config, "USB001", &key)
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(report.files_synced, 1);
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        let report = fixture.job().run(&mut |_| {}).unwrap();
+        assert!(report.is_success());
+        // The whole file, both manifests, the chunk and the chunk index
+        assert_eq!(report.rekeyed, 5);
+        assert_eq!(fixture.versions(), BTreeSet::from([2]));
+
+        let current = fixture.store.get_key("USB001").unwrap();
+        let manifest = snapshot::SnapshotReader::new(&current)
+            .latest(fixture.device.path())
+            .unwrap()
+            .unwrap();
+        let id = ChunkId::from_object(&manifest.entry("a.txt").unwrap().objects[0]).unwrap();
+        let store =
+            ChunkStore::open(fixture.device.path(), &current, &[], Chunker::new(1 << 20)).unwrap();
+        assert_eq!(&store.read_chunk(&id).unwrap()[..], b"alpha");
+    }
+
+    #[test]
+    fn test_sync_after_rotation_reseals_chunk_index() {
+        use crate::dedup::{ChunkId, ChunkStore, Chunker};
+
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        let mut config = fixture.config();
+        config.advanced.experimental_dedup = true;
+        let key = fixture.store.get_key("USB001").unwrap();
+        SyncEngine::new(&config, "USB001", &key)
+            .unwrap()
+            .run()
+            .unwrap();
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        // The index is still wrapped under version 1
+        let current = fixture.store.get_key("USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &current).unwrap();
+        assert!(engine.run().is_err());
+
+        let report = engine.with_key_store(&fixture.store).run().unwrap();
+        assert!(report.is_success());
+        let manifest = snapshot::SnapshotReader::new(&current)
+            .read(fixture.device.path(), &report.snapshot_id)
+            .unwrap();
+        let id = ChunkId::from_object(&manifest.entry("a.txt").unwrap().objects[0]).unwrap();
+        let store =
+            ChunkStore::open(fixture.device.path(), &current, &[], Chunker::new(1 << 20)).unwrap();
+        assert_eq!(store.index().get(&id).unwrap().references, 2);
+    }
+
+    #[test]
+    fn test_read_reused_chunk_after_rotation() {
+        use crate::dedup::{ChunkId, ChunkStore, Chunker};
+
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        let mut config = fixture.config();
+        config.advanced.experimental_dedup = true;
+        let key = fixture.store.get_key("USB001").unwrap();
+        SyncEngine::new(&config, "USB001", &key)
+            .unwrap()
+            .run()
+            .unwrap();
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        // The new snapshot reuses the chunk written under version 1
+        let current = fixture.store.get_key("USB001").unwrap();
+        let report = SyncEngine::new(&config, "USB001", &current)
+            .unwrap()
+            .with_key_store(&fixture.store)
+            .run()
+            .unwrap();
+        let manifest = snapshot::SnapshotReader::new(&current)
+            .read(fixture.device.path(), &report.snapshot_id)
+            .unwrap();
+        let id = ChunkId::from_object(&manifest.entry("a.txt").unwrap().objects[0]).unwrap();
+
+        let store =
+            ChunkStore::open(fixture.device.path(), &current, &[], Chunker::new(1 << 20)).unwrap();
+        assert!(store.read_chunk(&id).is_err());
+        let store = ChunkStore::open_with_archive(
+            fixture.device.path(),
+            &current,
+            &fixture.store,
+            &[],
+            Chunker::new(1 << 20),
+        )
+        .unwrap();
+        assert_eq!(&store.read_chunk(&id).unwrap()[..], b"alpha");
+    }
+
+    #[test]
+    fn test_rekey_upgrades_format_version_1() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        let old = vec![4u8; 300];
+        fixture.write_version_1("old.txt", &old);
+        assert_eq!(fixture.decrypt("old.txt"), old);
+        rotate_key(&fixture.store, "USB001").unwrap();
+        // Already under the current key version, but still without a data key
+        fixture.write_version_1("new.txt", b"newer");
+
+        let report = fixture.job().run(&mut |_| {}).unwrap();
+        assert!(report.is_success());
+        // a.txt, the manifest and both version 1 files
+        assert_eq!(report.rekeyed, 4);
+        assert_eq!(fixture.versions(), BTreeSet::from([2]));
+        for (name, contents) in [("old.txt", &old[..]), ("new.txt", b"newer")] {
+            assert_eq!(fixture.format_version(name), header::FORMAT_VERSION);
+            assert_eq!(fixture.decrypt(name), contents);
+        }
+    }
+
+    #[test]
+    fn test_rekey_resumes_from_checkpoint() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha"), ("b.txt", b"bravo")]);
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        // Simulate a run interrupted after the first file, mid-way through
+        // writing the second
+        let job = fixture.job();
+        let data_dir = sync::device_data_dir(fixture.device.path());
+        let current = fixture.store.get_key("USB001").unwrap();
+        job.rekey_file(&data_dir.join("a.txt.enc"), FileKind::Data, &current)
+            .unwrap();
+        job.save_checkpoint(&Checkpoint {
+            device_id: "USB001".to_string(),
+            target_version: 2,
+            completed: BTreeSet::from(["a.txt.enc".to_string()]),
+        })
+        .unwrap();
+        fs::write(data_dir.join("b.txt.enc.rekey"), b"partial").unwrap();
+
+        let report = job.run(&mut |_| {}).unwrap();
+        assert_eq!((report.resumed, report.rekeyed), (1, 2));
+        assert!(!data_dir.join("b.txt.enc.rekey").exists());
+        assert_eq!(fixture.decrypt("b.txt"), b"bravo");
+    }
+
+    #[test]
+    fn test_rekey_keeps_original_on_failure() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        // Without the old key version the file cannot be rewritten
+        fixture.store.retire_key_version("USB001", 1).unwrap();
+        let report = fixture.job().run(&mut |_| {}).unwrap();
+        assert!(!report.is_success());
+        assert_eq!(fixture.versions(), BTreeSet::from([1]));
+
+        let data_dir = sync::device_data_dir(fixture.device.path());
+        assert!(!data_dir.join("a.txt.enc.rekey").exists());
+    }
+
+    #[test]
+    fn test_stale_checkpoint_ignored() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        rotate_key(&fixture.store, "USB001").unwrap();
+        fixture
+            .job()
+            .save_checkpoint(&Checkpoint {
+                device_id: "USB001".to_string(),
+                target_version: 1,
+                completed: BTreeSet::from(["a.txt.enc".to_string()]),
+            })
+            .unwrap();
+
+        let report = fixture.job().run(&mut |_| {}).unwrap();
+        assert_eq!((report.resumed, report.rekeyed), (0, 2));
+    }
+}
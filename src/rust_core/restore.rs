@@ -0,0 +1,420 @@
+//! Restoring snapshots from a device
+//!
+//! `restore` reads a snapshot manifest (see [`crate::snapshot`]) and writes
+//! every file it lists into a destination directory. Files stored whole are
+//! decrypted with their source path as context, as sync wrote them, and
+//! files stored as chunks (see [`crate::dedup`]) are reassembled from the
+//! chunks the manifest names, in order.
+//!
+//! The manifest and every whole file are opened with the key version their
+//! header names, so a device does not have to be rekeyed after a rotation
+//! before it can be restored. Each file is written to a temporary file next
+//! to its destination, checked against the size and SHA-256 hash recorded
+//! in the manifest, and only then renamed into place; a file already at
+//! that path is replaced.
+
+use crate::dedup::{ChunkId, ChunkStore, Chunker};
+use crate::fingerprint::Fingerprint;
+use crate::header::{self, FileHeader, FILE_ID_LEN};
+use crate::keystore::{EncryptionKey, KeyStore};
+use crate::snapshot::{self, EntryKind, Manifest, ManifestEntry, SnapshotReader};
+use crate::sync::{self, SyncFailure};
+use crate::{AirGapError, Result};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Extension of files being restored
+const TEMP_EXTENSION: &str = "partial";
+
+/// Summary of a completed restore
+#[derive(Debug, Clone)]
+pub struct RestoreReport {
+    /// Device the snapshot was read from
+    pub device_id: String,
+    /// Snapshot that was restored
+    pub snapshot_id: String,
+    /// Files written to the destination
+    pub files_restored: u64,
+    /// Plaintext bytes written
+    pub bytes_restored: u64,
+    /// Files that could not be restored
+    pub failures: Vec<SyncFailure>,
+}
+
+impl RestoreReport {
+    /// Whether every file in the snapshot was restored
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Restores a device's snapshots into a directory
+pub struct RestoreJob<'a> {
+    store: &'a dyn KeyStore,
+    device_id: String,
+    mount_point: PathBuf,
+    trusted: Option<Fingerprint>,
+}
+
+impl<'a> RestoreJob<'a> {
+    /// Prepare to restore the data for `device_id` on `mount_point`
+    pub fn new(store: &'a dyn KeyStore, device_id: &str, mount_point: impl Into<PathBuf>) -> Self {
+        Self {
+            store,
+            device_id: device_id.to_string(),
+            mount_point: mount_point.into(),
+            trusted: None,
+        }
+    }
+
+    /// Only restore a snapshot signed by the key with this fingerprint
+    pub fn trust(mut self, signer: Fingerprint) -> Self {
+        self.trusted = Some(signer);
+        self
+    }
+
+    /// Restore `snapshot_id`, or the latest snapshot, into `destination`
+    pub fn run(&self, snapshot_id: Option<&str>, destination: &Path) -> Result<RestoreReport> {
+        if !self.mount_point.is_dir() {
+            return Err(AirGapError::DeviceNotFound(format!(
+                "{} (mount point {} is not available)",
+                self.device_id,
+                self.mount_point.display()
+            )));
+        }
+
+        let snapshot_id = match snapshot_id {
+            Some(snapshot_id) => snapshot_id.to_string(),
+            None => snapshot::list_snapshots(&self.mount_point)?
+                .pop()
+                .ok_or_else(|| {
+                    AirGapError::SyncError(format!("No snapshots on device {}", self.device_id))
+                })?,
+        };
+        let manifest = self.read_manifest(&snapshot_id)?;
+
+        let current = self.store.get_key(&self.device_id)?;
+        let has_chunks = manifest
+            .entries
+            .iter()
+            .any(|entry| entry.kind == EntryKind::File && !is_whole_file(entry));
+        let chunks = if has_chunks {
+            // Only read from, so the chunker's target size does not matter
+            Some(ChunkStore::open_with_archive(
+                &self.mount_point,
+                &current,
+                self.store,
+                &[],
+                Chunker::new(1 << 20),
+            )?)
+        } else {
+            None
+        };
+
+        let mut report = RestoreReport {
+            device_id: self.device_id.clone(),
+            snapshot_id: manifest.snapshot_id.clone(),
+            files_restored: 0,
+            bytes_restored: 0,
+            failures: Vec::new(),
+        };
+
+        fs::create_dir_all(destination)?;
+        for entry in &manifest.entries {
+            let result =
+                destination_path(destination, &entry.path).and_then(|target| match entry.kind {
+                    EntryKind::Directory => fs::create_dir_all(&target).map_err(Into::into),
+                    EntryKind::File => self.restore_file(entry, &target, chunks.as_ref()),
+                });
+            match result {
+                Ok(()) if entry.kind == EntryKind::File => {
+                    report.files_restored += 1;
+                    report.bytes_restored += entry.size;
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    log::warn!("Failed to restore {}: {e}", entry.path);
+                    report.failures.push(SyncFailure {
+                        path: PathBuf::from(&entry.path),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Read and verify a manifest with the key version its header names
+    fn read_manifest(&self, snapshot_id: &str) -> Result<Manifest> {
+        let path = snapshot::manifest_file(&self.mount_point, snapshot_id);
+        let file_header = FileHeader::read_from(&mut BufReader::new(File::open(path)?))?;
+        let key = self.key_for(&file_header)?;
+        let reader = SnapshotReader::new(&key);
+        let reader = match self.trusted {
+            Some(signer) => reader.trust(signer),
+            None => reader,
+        };
+        Ok(reader.read(&self.mount_point, snapshot_id)?)
+    }
+
+    /// Stored device key that wraps the data key of `file_header`
+    fn key_for(&self, file_header: &FileHeader) -> Result<EncryptionKey> {
+        match file_header.device_key() {
+            Some((device_id, version)) if device_id == self.device_id => {
+                Ok(self.store.get_key_version(device_id, version)?)
+            }
+            other => Err(AirGapError::SyncError(format!(
+                "Encrypted for device {}, not {}",
+                other.map_or("(none)", |(device_id, _)| device_id),
+                self.device_id
+            ))),
+        }
+    }
+
+    /// Write one file through a temporary file, checking it against the
+    /// manifest before renaming it to `target`
+    fn restore_file(
+        &self,
+        entry: &ManifestEntry,
+        target: &Path,
+        chunks: Option<&ChunkStore>,
+    ) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp = target.as_os_str().to_os_string();
+        temp.push(".");
+        temp.push(TEMP_EXTENSION);
+        let temp = PathBuf::from(temp);
+
+        let result = (|| -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            if is_whole_file(entry) {
+                self.decrypt_whole_file(entry, &mut writer)?;
+            } else {
+                let store = chunks.ok_or_else(|| {
+                    AirGapError::SyncError("File is stored as chunks".to_string())
+                })?;
+                for object in &entry.objects {
+                    let id = ChunkId::from_object(object).ok_or_else(|| {
+                        AirGapError::SyncError(format!("Unknown object {object}"))
+                    })?;
+                    writer.write_all(&store.read_chunk(&id)?)?;
+                }
+            }
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+
+            let size = file.metadata()?.len();
+            if size != entry.size || snapshot::hash_file(&temp)? != entry.content_hash {
+                return Err(AirGapError::SyncError(
+                    "Restored contents do not match the snapshot".to_string(),
+                ));
+            }
+            file.set_modified(SystemTime::from(entry.modified))?;
+            set_mode(&temp, entry.mode)?;
+            fs::rename(&temp, target)?;
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Decrypt the encrypted copy of a file stored whole
+    fn decrypt_whole_file<W: Write>(&self, entry: &ManifestEntry, writer: &mut W) -> Result<()> {
+        let relative = Path::new(&entry.path);
+        let data_dir = sync::device_data_dir(&self.mount_point);
+        let mut reader = BufReader::new(File::open(sync::encrypted_path(&data_dir, relative))?);
+        let file_header = FileHeader::read_from(&mut reader)?;
+        if entry.objects[0].as_bytes() != file_header.file_id {
+            return Err(AirGapError::SyncError(
+                "Encrypted copy was replaced by a later sync".to_string(),
+            ));
+        }
+        let key = self.key_for(&file_header)?;
+        header::decrypt_file(
+            &file_header.open_with_device_key(&key)?,
+            &file_header,
+            reader,
+            writer,
+            sync::file_aad(relative).as_bytes(),
+        )?;
+        Ok(())
+    }
+}
+
+/// Whether a file entry names one encrypted copy rather than chunks
+fn is_whole_file(entry: &ManifestEntry) -> bool {
+    matches!(entry.objects.as_slice(), [object] if object.as_bytes().len() == FILE_ID_LEN)
+}
+
+/// Where a manifest path is restored under `destination`
+///
+/// Only plain relative components are accepted, so a manifest cannot
+/// direct a file outside the destination.
+fn destination_path(destination: &Path, manifest_path: &str) -> Result<PathBuf> {
+    let relative = Path::new(manifest_path);
+    let plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if manifest_path.is_empty() || !plain {
+        return Err(AirGapError::SyncError(format!(
+            "Invalid path in snapshot: {manifest_path}"
+        )));
+    }
+    Ok(destination.join(relative))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if mode == 0 {
+        return Ok(());
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::keystore::{generate_key, rotate_key, MemoryKeyStore};
+    use crate::sync::SyncEngine;
+
+    struct Fixture {
+        source: tempfile::TempDir,
+        device: tempfile::TempDir,
+        store: MemoryKeyStore,
+    }
+
+    impl Fixture {
+        fn new(files: &[(&str, &[u8])]) -> Self {
+            let source = tempfile::tempdir().unwrap();
+            let device = tempfile::tempdir().unwrap();
+            for (name, contents) in files {
+                let path = source.path().join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            let store = MemoryKeyStore::new();
+            store
+                .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+                .unwrap();
+            Self {
+                source,
+                device,
+                store,
+            }
+        }
+
+        fn sync(&self, dedup: bool) -> String {
+            let mut config: Config = toml::from_str(&format!(
+                "[general]\n[source]\npath = {:?}\n[[device]]\nid = \"USB001\"\nname = \"USB\"\nmount_point = {:?}\n[policy]\n[security]\nkey_store = \"vault\"\n[notifications]\n[advanced]\n",
+                self.source.path(),
+                self.device.path()
+            ))
+            .unwrap();
+            config.policy.chunk_size_mb = 1;
+            config.advanced.experimental_dedup = dedup;
+            let key = self.store.get_key("USB001").unwrap();
+            let report = SyncEngine::new(&config, "USB001", &key)
+                .unwrap()
+                .with_key_store(&self.store)
+                .run()
+                .unwrap();
+            assert!(report.is_success());
+            report.snapshot_id
+        }
+
+        fn restore(&self, snapshot_id: Option<&str>) -> (RestoreReport, tempfile::TempDir) {
+            let destination = tempfile::tempdir().unwrap();
+            let report = RestoreJob::new(&self.store, "USB001", self.device.path())
+                .run(snapshot_id, destination.path())
+                .unwrap();
+            (report, destination)
+        }
+    }
+
+    #[test]
+    fn test_restore_whole_files() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha"), ("docs/b.txt", b"bravo")]);
+        let first = fixture.sync(false);
+        fs::write(fixture.source.path().join("a.txt"), b"changed").unwrap();
+        let second = fixture.sync(false);
+        // Older key versions are looked up from the headers
+        rotate_key(&fixture.store, "USB001").unwrap();
+
+        let (report, restored) = fixture.restore(None);
+        assert!(report.is_success());
+        assert_eq!(report.snapshot_id, second);
+        assert_eq!((report.files_restored, report.bytes_restored), (2, 12));
+        assert_eq!(fs::read(restored.path().join("a.txt")).unwrap(), b"changed");
+        assert_eq!(
+            fs::read(restored.path().join("docs/b.txt")).unwrap(),
+            b"bravo"
+        );
+
+        // The later sync replaced the copy of a.txt the first snapshot lists
+        let (report, restored) = fixture.restore(Some(&first));
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].path, Path::new("a.txt"));
+        assert!(!restored.path().join("a.txt").exists());
+        assert_eq!(
+            fs::read(restored.path().join("docs/b.txt")).unwrap(),
+            b"bravo"
+        );
+    }
+
+    #[test]
+    fn test_restore_chunked_files() {
+        let big: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let fixture = Fixture::new(&[("a.txt", b"alpha"), ("big.bin", &big), ("empty", b"")]);
+        fixture.sync(true);
+
+        let (report, restored) = fixture.restore(None);
+        assert!(report.is_success());
+        assert_eq!(report.files_restored, 3);
+        assert_eq!(fs::read(restored.path().join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(restored.path().join("big.bin")).unwrap(), big);
+        assert!(fs::read(restored.path().join("empty")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_checks_contents() {
+        let fixture = Fixture::new(&[("a.txt", b"alpha")]);
+        fixture.sync(false);
+        let data_dir = sync::device_data_dir(fixture.device.path());
+        let encrypted = sync::encrypted_path(&data_dir, Path::new("a.txt"));
+        let mut file = fs::read(&encrypted).unwrap();
+        *file.last_mut().unwrap() ^= 1;
+        fs::write(&encrypted, file).unwrap();
+
+        // Nothing is left behind for a file that fails authentication
+        let (report, restored) = fixture.restore(None);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(fs::read_dir(restored.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_destination_path() {
+        let destination = Path::new("/restore");
+        assert_eq!(
+            destination_path(destination, "docs/a.txt").unwrap(),
+            Path::new("/restore/docs/a.txt")
+        );
+        for path in ["", "../a.txt", "docs/../../a.txt", "/etc/passwd", "./a"] {
+            assert!(destination_path(destination, path).is_err(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,466 @@
+//! Shamir secret sharing of device keys
+//!
+//! [`split_key`] turns a symmetric device key into N shares, any M of which
+//! rebuild it with [`combine_shares`]; fewer than M reveal nothing about the
+//! key. The shared secret is the key's backup record body (see
+//! [`crate::backup`]), split byte by byte with Shamir's scheme over GF(2^8),
+//! so a combined key comes back with its metadata.
+//!
+//! Each share is framed, checksummed and written out like a backup, as
+//! words or as a QR-ready payload string:
+//!
+//! ```text
+//! kind         u8    (2 = key share)
+//! split id     8 random bytes, the same in every share of one split
+//! threshold    u8    (M)
+//! count        u8    (N)
+//! index        u8    (x coordinate, 1-N)
+//! key version  u32
+//! key id       8 bytes (see crate::fingerprint)
+//! value        one byte per byte of the shared record body
+//! ```
+//!
+//! The checksum catches a mistyped share on its own. When combining, any
+//! shares beyond the threshold must lie on the same polynomial, and the
+//! rebuilt key's ID must match the one every share carries, so a wrong or
+//! corrupted share is reported instead of yielding a bad key.
+
+use crate::backup::{self, BackupError, SHARE_RECORD};
+use crate::fingerprint::{Fingerprint, KEY_ID_LEN};
+use crate::keystore::EncryptionKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::Write as _;
+use zeroize::Zeroizing;
+
+/// Prefix of a share's QR payload string
+pub const SHARE_PAYLOAD_PREFIX: &str = "AGSS1:";
+
+/// Length of the random split ID
+const SPLIT_ID_LEN: usize = 8;
+
+/// Share bytes before the value
+const HEADER_LEN: usize = 1 + SPLIT_ID_LEN + 1 + 1 + 1 + 4 + KEY_ID_LEN;
+
+/// One custodian's share of a device key
+#[derive(Clone)]
+pub struct KeyShare {
+    split_id: [u8; SPLIT_ID_LEN],
+    threshold: u8,
+    count: u8,
+    index: u8,
+    key_version: u32,
+    key_id: [u8; KEY_ID_LEN],
+    value: Zeroizing<Vec<u8>>,
+}
+
+impl KeyShare {
+    /// Position of this share, from 1
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Shares needed to rebuild the key
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Shares the key was split into
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    /// `KeyMetadata.version` of the shared key
+    pub fn key_version(&self) -> u32 {
+        self.key_version
+    }
+
+    /// Key ID of the shared key
+    pub fn key_id(&self) -> [u8; KEY_ID_LEN] {
+        self.key_id
+    }
+
+    /// Share as words, separated by spaces
+    pub fn to_mnemonic(&self) -> Result<Zeroizing<String>, BackupError> {
+        Ok(backup::record_words(&backup::seal(&self.body())?))
+    }
+
+    /// Share as a QR-ready payload string
+    pub fn to_payload(&self) -> Result<Zeroizing<String>, BackupError> {
+        let record = backup::seal(&self.body())?;
+        Ok(Zeroizing::new(format!(
+            "{SHARE_PAYLOAD_PREFIX}{}",
+            hex::encode_upper(&*record)
+        )))
+    }
+
+    /// Read a share from words, a payload string or a whole share sheet
+    pub fn parse(input: &str) -> Result<Self, BackupError> {
+        let record = match backup::find_payload(input, SHARE_PAYLOAD_PREFIX) {
+            Some(payload) => backup::payload_record(payload, SHARE_PAYLOAD_PREFIX)?,
+            None => backup::words_record(input)?,
+        };
+        Self::from_body(backup::open(&record)?)
+    }
+
+    fn body(&self) -> Zeroizing<Vec<u8>> {
+        let mut body = Zeroizing::new(Vec::with_capacity(HEADER_LEN + self.value.len()));
+        body.push(SHARE_RECORD);
+        body.extend_from_slice(&self.split_id);
+        body.push(self.threshold);
+        body.push(self.count);
+        body.push(self.index);
+        body.extend_from_slice(&self.key_version.to_be_bytes());
+        body.extend_from_slice(&self.key_id);
+        body.extend_from_slice(&self.value);
+        body
+    }
+
+    fn from_body(body: &[u8]) -> Result<Self, BackupError> {
+        if body.first() != Some(&SHARE_RECORD) {
+            return Err(BackupError::InvalidFormat(
+                "not a key share; restore backups with `keys restore`".to_string(),
+            ));
+        }
+        if body.len() <= HEADER_LEN {
+            return Err(BackupError::InvalidFormat("truncated share".to_string()));
+        }
+
+        let mut split_id = [0u8; SPLIT_ID_LEN];
+        split_id.copy_from_slice(&body[1..1 + SPLIT_ID_LEN]);
+        let rest = &body[1 + SPLIT_ID_LEN..];
+        let (threshold, count, index) = (rest[0], rest[1], rest[2]);
+        if threshold < 2 || threshold > count || index == 0 || index > count {
+            return Err(BackupError::InvalidFormat(
+                "share has an invalid threshold or index".to_string(),
+            ));
+        }
+        let key_version = u32::from_be_bytes(rest[3..7].try_into().expect("4 bytes"));
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&rest[7..7 + KEY_ID_LEN]);
+
+        Ok(Self {
+            split_id,
+            threshold,
+            count,
+            index,
+            key_version,
+            key_id,
+            value: Zeroizing::new(body[HEADER_LEN..].to_vec()),
+        })
+    }
+}
+
+/// Split a symmetric device key into `count` shares, any `threshold` of
+/// which rebuild it
+pub fn split_key(
+    key: &EncryptionKey,
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<KeyShare>, BackupError> {
+    if threshold < 2 || threshold > count {
+        return Err(BackupError::InvalidShares(format!(
+            "threshold must be between 2 and the number of shares ({count})"
+        )));
+    }
+
+    let secret = backup::key_body(key)?;
+    let rng = SystemRandom::new();
+    let mut split_id = [0u8; SPLIT_ID_LEN];
+    rng.fill(&mut split_id)
+        .map_err(|_| BackupError::RandomFailure)?;
+    // Coefficients 1..threshold of the polynomial for each secret byte
+    let degree = usize::from(threshold) - 1;
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * degree]);
+    rng.fill(&mut coefficients)
+        .map_err(|_| BackupError::RandomFailure)?;
+
+    let key_id = Fingerprint::of_secret_key(&key.key_material).key_id();
+    let shares = (1..=count)
+        .map(|x| {
+            let value = secret
+                .iter()
+                .zip(coefficients.chunks(degree))
+                .map(|(&constant, higher)| {
+                    // Horner's rule, highest coefficient first
+                    let y = higher.iter().rev().fold(0, |acc, &c| gf_mul(acc, x) ^ c);
+                    gf_mul(y, x) ^ constant
+                })
+                .collect();
+            KeyShare {
+                split_id,
+                threshold,
+                count,
+                index: x,
+                key_version: key.metadata.version,
+                key_id,
+                value: Zeroizing::new(value),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Every share must fit a framed record
+    backup::seal(&shares[0].body())?;
+    Ok(shares)
+}
+
+/// Rebuild a key from at least its threshold of shares
+pub fn combine_shares(shares: &[KeyShare]) -> Result<EncryptionKey, BackupError> {
+    let first = shares
+        .first()
+        .ok_or_else(|| BackupError::InvalidShares("no shares given".to_string()))?;
+    for (i, share) in shares.iter().enumerate() {
+        if share.split_id != first.split_id
+            || share.threshold != first.threshold
+            || share.count != first.count
+            || share.key_version != first.key_version
+            || share.key_id != first.key_id
+            || share.value.len() != first.value.len()
+        {
+            return Err(BackupError::InvalidShares(format!(
+                "share {} belongs to a different split than share {}",
+                share.index, first.index
+            )));
+        }
+        if shares[..i].iter().any(|other| other.index == share.index) {
+            return Err(BackupError::InvalidShares(format!(
+                "share {} was given twice",
+                share.index
+            )));
+        }
+    }
+    let threshold = usize::from(first.threshold);
+    if shares.len() < threshold {
+        return Err(BackupError::InvalidShares(format!(
+            "{threshold} shares are needed, {} given",
+            shares.len()
+        )));
+    }
+
+    let (base, extra) = shares.split_at(threshold);
+    if extra
+        .iter()
+        .any(|share| *interpolate(base, share.index) != *share.value)
+    {
+        return Err(BackupError::WrongShares);
+    }
+
+    let key = backup::key_from_body(&interpolate(base, 0)).map_err(|_| BackupError::WrongShares)?;
+    if Fingerprint::of_secret_key(&key.key_material).key_id() != first.key_id
+        || key.metadata.version != first.key_version
+    {
+        return Err(BackupError::WrongShares);
+    }
+    Ok(key)
+}
+
+/// Printable sheet for one custodian's share
+pub fn share_sheet(
+    key: &EncryptionKey,
+    share: &KeyShare,
+) -> Result<Zeroizing<String>, BackupError> {
+    let mnemonic = share.to_mnemonic()?;
+    let payload = share.to_payload()?;
+    let fingerprint = Fingerprint::of_secret_key(&key.key_material);
+
+    let mut sheet = Zeroizing::new(String::new());
+    // Writing to a String cannot fail
+    let title = format!(
+        "AirGapSync key share {} of {} ({} needed)",
+        share.index, share.count, share.threshold
+    );
+    let _ = writeln!(sheet, "{title}");
+    let _ = writeln!(sheet, "{}", "=".repeat(title.len()));
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "Device:             {}", key.metadata.device_id);
+    let _ = writeln!(sheet, "Key version:        {}", share.key_version);
+    let _ = writeln!(sheet, "Key ID:             {}", fingerprint.key_id_hex());
+    let _ = writeln!(
+        sheet,
+        "Verification code:  {}",
+        fingerprint.verification_code()
+    );
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "Share words:");
+    for line in mnemonic.split(' ').collect::<Vec<_>>().chunks(8) {
+        let _ = writeln!(sheet, "  {}", line.join(" "));
+    }
+    let _ = writeln!(sheet);
+    let _ = writeln!(sheet, "QR payload:");
+    let _ = writeln!(sheet, "{}", payload.as_str());
+    let _ = writeln!(sheet);
+    let _ = writeln!(
+        sheet,
+        "Combine {} shares with `airgapsync keys combine` to restore the key.",
+        share.threshold
+    );
+    Ok(sheet)
+}
+
+/// Evaluate at `x` the polynomial through `shares`, byte by byte
+fn interpolate(shares: &[KeyShare], x: u8) -> Zeroizing<Vec<u8>> {
+    let mut out = Zeroizing::new(vec![0u8; shares[0].value.len()]);
+    for share in shares {
+        // Lagrange basis polynomial of this share, evaluated at x
+        let (mut numerator, mut denominator) = (1, 1);
+        for other in shares.iter().filter(|other| other.index != share.index) {
+            numerator = gf_mul(numerator, x ^ other.index);
+            denominator = gf_mul(denominator, share.index ^ other.index);
+        }
+        let basis = gf_mul(numerator, gf_inv(denominator));
+        for (out, &y) in out.iter_mut().zip(share.value.iter()) {
+            *out ^= gf_mul(basis, y);
+        }
+    }
+    out
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, without
+/// data-dependent branches
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = a >> 7;
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(carry));
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(2^8), as a^254
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::generate_key;
+
+    #[test]
+    fn test_field_arithmetic() {
+        // FIPS 197 section 4.2 example
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_any_threshold_subset_rebuilds_key() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let shares = split_key(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        for a in 0..5 {
+            for b in a + 1..5 {
+                for c in b + 1..5 {
+                    let subset = [shares[c].clone(), shares[a].clone(), shares[b].clone()];
+                    let combined = combine_shares(&subset).unwrap();
+                    assert_eq!(combined.key_material, key.key_material);
+                    assert_eq!(combined.metadata.device_id, "USB001");
+                    assert_eq!(combined.metadata.version, key.metadata.version);
+                }
+            }
+        }
+        assert!(combine_shares(&shares).is_ok());
+
+        // Fewer than the threshold cannot be combined
+        assert!(matches!(
+            combine_shares(&shares[..2]),
+            Err(BackupError::InvalidShares(_))
+        ));
+    }
+
+    #[test]
+    fn test_share_encoding() {
+        let key = generate_key("ChaCha20", "USB001").unwrap();
+        let shares = split_key(&key, 2, 3).unwrap();
+        let key_id = Fingerprint::of_secret_key(&key.key_material).key_id();
+
+        let from_words = KeyShare::parse(&shares[0].to_mnemonic().unwrap()).unwrap();
+        let from_payload = KeyShare::parse(&shares[2].to_payload().unwrap()).unwrap();
+        let sheet = share_sheet(&key, &shares[1]).unwrap();
+        assert!(sheet.starts_with("AirGapSync key share 2 of 3 (2 needed)"));
+        let from_sheet = KeyShare::parse(&sheet).unwrap();
+
+        for share in [&from_words, &from_payload, &from_sheet] {
+            assert_eq!(share.key_id(), key_id);
+            assert_eq!(share.key_version(), 1);
+            assert_eq!(share.threshold(), 2);
+            assert_eq!(share.count(), 3);
+        }
+        assert_eq!(from_sheet.index(), 2);
+        let combined = combine_shares(&[from_words, from_payload]).unwrap();
+        assert_eq!(combined.key_material, key.key_material);
+
+        // Shares and backups are not interchangeable
+        assert!(backup::parse(&shares[0].to_mnemonic().unwrap()).is_err());
+        assert!(KeyShare::parse(&backup::to_mnemonic(&key).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_wrong_shares_detected() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let shares = split_key(&key, 2, 3).unwrap();
+
+        // A share altered after its checksum was computed
+        let mut corrupted = shares[1].clone();
+        corrupted.value[5] ^= 1;
+        assert!(matches!(
+            combine_shares(&[shares[0].clone(), corrupted.clone()]),
+            Err(BackupError::WrongShares)
+        ));
+        // An extra share that does not fit the others
+        assert!(matches!(
+            combine_shares(&[shares[0].clone(), shares[2].clone(), corrupted]),
+            Err(BackupError::WrongShares)
+        ));
+
+        // A mistyped share fails its own checksum
+        let mut words: Vec<String> = shares[0]
+            .to_mnemonic()
+            .unwrap()
+            .split(' ')
+            .map(str::to_string)
+            .collect();
+        words[10] = if words[10] == "zoo" { "abandon" } else { "zoo" }.to_string();
+        assert!(KeyShare::parse(&words.join(" ")).is_err());
+
+        // Shares from another split of the same key are refused
+        let other = split_key(&key, 2, 3).unwrap();
+        assert!(matches!(
+            combine_shares(&[shares[0].clone(), other[1].clone()]),
+            Err(BackupError::InvalidShares(_))
+        ));
+        assert!(matches!(
+            combine_shares(&[shares[0].clone(), shares[0].clone()]),
+            Err(BackupError::InvalidShares(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_limits() {
+        let key = generate_key("AES-128", "USB001").unwrap();
+        assert!(split_key(&key, 1, 3).is_err());
+        assert!(split_key(&key, 4, 3).is_err());
+        assert_eq!(split_key(&key, 255, 255).unwrap().len(), 255);
+
+        let pair = generate_key("X25519", "partner").unwrap();
+        assert!(matches!(
+            split_key(&pair, 2, 3),
+            Err(BackupError::UnsupportedAlgorithm(_))
+        ));
+    }
+}
@@ -0,0 +1,891 @@
+//! Snapshot manifests
+//!
+//! Every sync run records a snapshot: a manifest listing each synced path
+//! with its metadata, content hash and the storage objects holding its
+//! data. Manifests live under `AirGapSync/snapshots/` on the device as
+//! `<snapshot id>.manifest`. They are encrypted like data files, with a
+//! [`FileHeader`] followed by a segmented stream, and the snapshot ID as
+//! payload context so one manifest cannot be passed off as another.
+//!
+//! The decrypted manifest is:
+//!
+//! ```text
+//! magic        "AGSM"
+//! version      u8
+//! snapshot id  u8 length || UTF-8
+//! created      i64 seconds || u32 nanoseconds since the Unix epoch
+//! host         u8 length || UTF-8
+//! config hash  32 bytes (see [`config_hash`])
+//! device id    u8 length || UTF-8
+//! key version  u32
+//! key id       8 bytes
+//! entry count  u32
+//! each entry, sorted by path:
+//!   path       u16 length || UTF-8, components joined with `/`
+//!   kind       u8      (1 = file, 2 = directory)
+//!   size       u64
+//!   modified   i64 seconds || u32 nanoseconds
+//!   mode       u32     (Unix permission bits, 0 elsewhere)
+//!   hash       32 bytes (SHA-256 of the contents, zero for directories)
+//!   objects    u16 count, each u8 length || object ID
+//! signer       u16 length || SubjectPublicKeyInfo (DER), empty if unsigned
+//! signature    u16 length || signature over every byte before `signer`
+//! ```
+//!
+//! All integers are big endian. The key version and ID name the device key
+//! in use when the snapshot was taken. A manifest signed with an RSA, ECDSA
+//! or Ed25519 key pair carries the signer's public key; readers always
+//! check the signature, and can require a particular signer with
+//! [`SnapshotReader::trust`].
+
+use crate::config::Config;
+use crate::crypto::{CryptoError, NonceGenerator};
+use crate::envelope::Recipient;
+use crate::fingerprint::{Fingerprint, KEY_ID_LEN};
+use crate::header::{self, FileHeader};
+use crate::keys::{AsymmetricKey, KeyError};
+use crate::keystore::EncryptionKey;
+use crate::sync::DEVICE_ROOT;
+use chrono::{DateTime, Utc};
+use ring::digest;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Magic bytes at the start of every decrypted manifest
+pub const MAGIC: &[u8; 4] = b"AGSM";
+
+/// Current manifest format version
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Directory under the device root that holds snapshot manifests
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+/// File extension of a manifest
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Length of content and configuration hashes
+pub const HASH_LEN: usize = 32;
+
+/// Segment size of the encrypted manifest stream
+const SEGMENT_SIZE: usize = 1 << 20;
+
+/// Snapshot errors
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    /// The manifest is malformed
+    #[error("Invalid manifest: {0}")]
+    InvalidFormat(String),
+
+    /// The manifest was written by an unsupported format version
+    #[error("Unsupported manifest format version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The manifest signature does not verify
+    #[error("Manifest signature does not verify")]
+    BadSignature,
+
+    /// The manifest is unsigned, or signed by a key that is not trusted
+    #[error("Manifest is not signed by {0}")]
+    UntrustedSigner(Fingerprint),
+
+    /// Encryption or decryption failed
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+
+    /// A signing or device key could not be used
+    #[error(transparent)]
+    Key(#[from] KeyError),
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// What a manifest entry describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Regular file
+    File,
+    /// Directory
+    Directory,
+}
+
+/// Identifier of a stored object holding (part of) a file's data
+///
+/// Whole encrypted files are named by their header's file ID, and chunks
+/// stored by [`crate::dedup`] by their chunk ID.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectId(Vec<u8>);
+
+impl ObjectId {
+    /// Object ID from its raw bytes
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    /// Raw ID bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+/// One path recorded in a manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the source root, components joined with `/`
+    pub path: String,
+    /// File or directory
+    pub kind: EntryKind,
+    /// Size in bytes (0 for directories)
+    pub size: u64,
+    /// Last modification time
+    pub modified: DateTime<Utc>,
+    /// Unix permission bits (0 where unavailable)
+    pub mode: u32,
+    /// SHA-256 of the contents (zero for directories)
+    pub content_hash: [u8; HASH_LEN],
+    /// Stored objects holding the contents, in order
+    pub objects: Vec<ObjectId>,
+}
+
+impl ManifestEntry {
+    /// Entry for `relative` with kind, size, time and mode from `metadata`
+    ///
+    /// The content hash and objects are left empty for the caller to fill.
+    pub fn from_metadata(relative: &Path, metadata: &fs::Metadata) -> Self {
+        Self {
+            path: manifest_path(relative),
+            kind: if metadata.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            },
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata
+                .modified()
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_default(),
+            mode: file_mode(metadata),
+            content_hash: [0u8; HASH_LEN],
+            objects: Vec::new(),
+        }
+    }
+}
+
+/// Contents of one snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// Unique, chronologically sortable snapshot ID
+    pub snapshot_id: String,
+    /// When the snapshot was taken
+    pub created_at: DateTime<Utc>,
+    /// Host the source was read on
+    pub host: String,
+    /// Hash of the configuration used (see [`config_hash`])
+    pub config_hash: [u8; HASH_LEN],
+    /// Device the snapshot was written to
+    pub device_id: String,
+    /// Device key version in use
+    pub key_version: u32,
+    /// Key ID of that device key
+    pub key_id: [u8; KEY_ID_LEN],
+    /// Recorded paths
+    pub entries: Vec<ManifestEntry>,
+    /// Fingerprint of the key that signed the manifest, set when read
+    pub signer: Option<Fingerprint>,
+}
+
+impl Manifest {
+    /// Empty manifest for a snapshot taken now with `config` and `key`
+    pub fn new(config: &Config, key: &EncryptionKey) -> Result<Self, SnapshotError> {
+        let created_at = Utc::now();
+        let random = NonceGenerator::new().generate(4)?;
+        Ok(Self {
+            snapshot_id: format!(
+                "{}-{}",
+                created_at.format("%Y%m%dT%H%M%S%6fZ"),
+                hex::encode(random)
+            ),
+            created_at,
+            host: host_name(),
+            config_hash: config_hash(config),
+            device_id: key.metadata.device_id.clone(),
+            key_version: key.metadata.version,
+            key_id: key.fingerprint()?.key_id(),
+            entries: Vec::new(),
+            signer: None,
+        })
+    }
+
+    /// Entry for a `/`-joined source-relative path
+    pub fn entry(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    /// Encode the manifest up to the signer field
+    fn encode(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut out = Vec::with_capacity(128 + self.entries.len() * 96);
+        out.extend_from_slice(MAGIC);
+        out.push(FORMAT_VERSION);
+        push_short(&mut out, self.snapshot_id.as_bytes(), "snapshot ID")?;
+        push_time(&mut out, &self.created_at);
+        push_short(&mut out, self.host.as_bytes(), "host name")?;
+        out.extend_from_slice(&self.config_hash);
+        push_short(&mut out, self.device_id.as_bytes(), "device ID")?;
+        out.extend_from_slice(&self.key_version.to_be_bytes());
+        out.extend_from_slice(&self.key_id);
+
+        let mut entries: Vec<&ManifestEntry> = self.entries.iter().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let count = u32::try_from(entries.len())
+            .map_err(|_| SnapshotError::InvalidFormat("too many entries".to_string()))?;
+        out.extend_from_slice(&count.to_be_bytes());
+        for entry in entries {
+            push_long(&mut out, entry.path.as_bytes(), "path")?;
+            out.push(match entry.kind {
+                EntryKind::File => 1,
+                EntryKind::Directory => 2,
+            });
+            out.extend_from_slice(&entry.size.to_be_bytes());
+            push_time(&mut out, &entry.modified);
+            out.extend_from_slice(&entry.mode.to_be_bytes());
+            out.extend_from_slice(&entry.content_hash);
+            let objects = u16::try_from(entry.objects.len()).map_err(|_| {
+                SnapshotError::InvalidFormat(format!("too many objects for {}", entry.path))
+            })?;
+            out.extend_from_slice(&objects.to_be_bytes());
+            for object in &entry.objects {
+                push_short(&mut out, object.as_bytes(), "object ID")?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Decode a manifest, checking its signature if it has one
+    fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut decoder = Decoder { bytes };
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::InvalidFormat(
+                "not a snapshot manifest".to_string(),
+            ));
+        }
+        let version = decoder.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let snapshot_id = decoder.string_short("snapshot ID")?;
+        let created_at = decoder.time()?;
+        let host = decoder.string_short("host name")?;
+        let config_hash = decoder.hash()?;
+        let device_id = decoder.string_short("device ID")?;
+        let key_version = decoder.u32()?;
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(decoder.take(KEY_ID_LEN)?);
+
+        let count = decoder.u32()?;
+        let mut entries: Vec<ManifestEntry> = Vec::new();
+        for _ in 0..count {
+            let path = decoder.string_long("path")?;
+            if entries.last().is_some_and(|last| last.path >= path) {
+                return Err(SnapshotError::InvalidFormat(format!(
+                    "entries out of order at {path}"
+                )));
+            }
+            let kind = match decoder.u8()? {
+                1 => EntryKind::File,
+                2 => EntryKind::Directory,
+                other => {
+                    return Err(SnapshotError::InvalidFormat(format!(
+                        "unknown entry kind {other}"
+                    )))
+                }
+            };
+            let size = decoder.u64()?;
+            let modified = decoder.time()?;
+            let mode = decoder.u32()?;
+            let content_hash = decoder.hash()?;
+            let objects = (0..decoder.u16()?)
+                .map(|_| Ok(ObjectId::new(decoder.short()?)))
+                .collect::<Result<_, SnapshotError>>()?;
+            entries.push(ManifestEntry {
+                path,
+                kind,
+                size,
+                modified,
+                mode,
+                content_hash,
+                objects,
+            });
+        }
+
+        let signed_len = bytes.len() - decoder.bytes.len();
+        let signer_der = decoder.long()?;
+        let signature = decoder.long()?;
+        if !decoder.bytes.is_empty() {
+            return Err(SnapshotError::InvalidFormat(
+                "trailing bytes after signature".to_string(),
+            ));
+        }
+        let signer = if signer_der.is_empty() {
+            None
+        } else {
+            let key = AsymmetricKey::from_public_der(signer_der)?;
+            key.verify(&bytes[..signed_len], signature)
+                .map_err(|_| SnapshotError::BadSignature)?;
+            Some(key.fingerprint())
+        };
+
+        Ok(Self {
+            snapshot_id,
+            created_at,
+            host,
+            config_hash,
+            device_id,
+            key_version,
+            key_id,
+            entries,
+            signer,
+        })
+    }
+}
+
+/// Writes encrypted, optionally signed manifests onto a device
+pub struct SnapshotWriter<'a> {
+    key: &'a EncryptionKey,
+    recipients: &'a [Recipient],
+    signer: Option<&'a AsymmetricKey>,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    /// Writer encrypting to the device key and `recipients`
+    pub fn new(key: &'a EncryptionKey, recipients: &'a [Recipient]) -> Self {
+        Self {
+            key,
+            recipients,
+            signer: None,
+        }
+    }
+
+    /// Sign manifests with a signing key pair
+    pub fn signed_by(mut self, signer: &'a AsymmetricKey) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Write `manifest` under the device's snapshot directory
+    ///
+    /// The manifest is written to a temporary file and renamed into place,
+    /// so an interrupted write never leaves a partial manifest. Returns the
+    /// manifest's path.
+    pub fn write(&self, mount_point: &Path, manifest: &Manifest) -> Result<PathBuf, SnapshotError> {
+        let path = manifest_file(mount_point, &manifest.snapshot_id);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temp = path.with_extension("tmp");
+        let result = (|| -> Result<(), SnapshotError> {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            self.write_to(manifest, &mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&temp, &path)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result.map(|_| path)
+    }
+
+    /// Encrypt `manifest` into `writer`
+    pub fn write_to<W: Write>(&self, manifest: &Manifest, writer: W) -> Result<(), SnapshotError> {
+        let mut body = manifest.encode()?;
+        match self.signer {
+            Some(signer) => {
+                let signature = signer.sign(&body)?;
+                push_long(&mut body, &signer.public_key_der(), "signer public key")?;
+                push_long(&mut body, &signature, "signature")?;
+            }
+            None => body.extend_from_slice(&[0u8; 4]),
+        }
+
+        let (file_header, data_key) = FileHeader::seal(self.key, self.recipients)?;
+        header::encrypt_file(
+            &data_key,
+            &file_header,
+            &mut &body[..],
+            writer,
+            SEGMENT_SIZE,
+            snapshot_aad(&manifest.snapshot_id).as_bytes(),
+        )?;
+        Ok(())
+    }
+}
+
+/// Reads and verifies manifests with a device key
+pub struct SnapshotReader<'a> {
+    key: &'a EncryptionKey,
+    trusted: Option<Fingerprint>,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Reader decrypting with the device key
+    ///
+    /// The key must be the version that wraps the manifest; see
+    /// [`crate::rekey`] for moving manifests to the current version.
+    pub fn new(key: &'a EncryptionKey) -> Self {
+        Self { key, trusted: None }
+    }
+
+    /// Only accept manifests signed by the key with this fingerprint
+    pub fn trust(mut self, signer: Fingerprint) -> Self {
+        self.trusted = Some(signer);
+        self
+    }
+
+    /// Read the manifest with the given ID from a device
+    pub fn read(&self, mount_point: &Path, snapshot_id: &str) -> Result<Manifest, SnapshotError> {
+        let file = File::open(manifest_file(mount_point, snapshot_id))?;
+        self.read_from(BufReader::new(file), snapshot_id)
+    }
+
+    /// Read the most recent manifest on a device, if there is one
+    pub fn latest(&self, mount_point: &Path) -> Result<Option<Manifest>, SnapshotError> {
+        match list_snapshots(mount_point)?.last() {
+            Some(snapshot_id) => self.read(mount_point, snapshot_id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypt and verify a manifest read from `reader`
+    pub fn read_from<R: Read>(
+        &self,
+        mut reader: R,
+        snapshot_id: &str,
+    ) -> Result<Manifest, SnapshotError> {
+        let file_header = FileHeader::read_from(&mut reader)?;
+        let data_key = file_header.open_with_device_key(self.key)?;
+        let mut body = Vec::new();
+        header::decrypt_file(
+            &data_key,
+            &file_header,
+            reader,
+            &mut body,
+            snapshot_aad(snapshot_id).as_bytes(),
+        )?;
+
+        let manifest = Manifest::decode(&body)?;
+        if manifest.snapshot_id != snapshot_id {
+            return Err(SnapshotError::InvalidFormat(format!(
+                "manifest {snapshot_id} holds snapshot {}",
+                manifest.snapshot_id
+            )));
+        }
+        if let Some(trusted) = self.trusted {
+            if manifest.signer != Some(trusted) {
+                return Err(SnapshotError::UntrustedSigner(trusted));
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// IDs of the snapshots on a device, oldest first
+pub fn list_snapshots(mount_point: &Path) -> Result<Vec<String>, SnapshotError> {
+    let dir = snapshot_dir(mount_point);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(MANIFEST_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+            ids.push(id.to_string());
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Directory holding manifests under a device mount point
+pub fn snapshot_dir(mount_point: &Path) -> PathBuf {
+    mount_point.join(DEVICE_ROOT).join(SNAPSHOT_DIR)
+}
+
+/// Location of the manifest with the given ID
+pub fn manifest_file(mount_point: &Path, snapshot_id: &str) -> PathBuf {
+    snapshot_dir(mount_point).join(format!("{snapshot_id}.{MANIFEST_EXTENSION}"))
+}
+
+/// Additional authenticated data binding an encrypted manifest to its ID
+pub fn snapshot_aad(snapshot_id: &str) -> String {
+    format!("snapshot:{snapshot_id}")
+}
+
+/// Manifest form of a source-relative path: components joined with `/`
+pub fn manifest_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// SHA-256 of the configuration, as TOML, without `advanced.last_sync`
+///
+/// Identifies the settings a snapshot was taken with; the last sync time is
+/// left out since every run changes it.
+pub fn config_hash(config: &Config) -> [u8; HASH_LEN] {
+    let mut config = config.clone();
+    config.advanced.last_sync = None;
+    let toml = toml::to_string(&config).unwrap_or_default();
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, toml.as_bytes()).as_ref());
+    hash
+}
+
+/// SHA-256 of a file's contents
+pub fn hash_file(path: &Path) -> io::Result<[u8; HASH_LEN]> {
+    let mut reader = HashingReader::new(BufReader::new(File::open(path)?));
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+/// Reader that hashes everything read through it with SHA-256
+pub struct HashingReader<R: Read> {
+    inner: R,
+    context: digest::Context,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Wrap `inner`
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            context: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    /// Hash of the bytes read so far
+    pub fn finish(self) -> [u8; HASH_LEN] {
+        let mut hash = [0u8; HASH_LEN];
+        hash.copy_from_slice(self.context.finish().as_ref());
+        hash
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.context.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Name of this host, or `unknown`
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(|| {
+            let output = std::process::Command::new("hostname").output().ok()?;
+            let name = String::from_utf8(output.stdout).ok()?;
+            Some(name.trim().to_string()).filter(|name| !name.is_empty())
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0
+}
+
+/// Append `bytes` with a u8 length prefix
+fn push_short(out: &mut Vec<u8>, bytes: &[u8], what: &str) -> Result<(), SnapshotError> {
+    if bytes.len() > u8::MAX as usize {
+        return Err(SnapshotError::InvalidFormat(format!("{what} too long")));
+    }
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Append `bytes` with a u16 length prefix
+fn push_long(out: &mut Vec<u8>, bytes: &[u8], what: &str) -> Result<(), SnapshotError> {
+    if bytes.len() > u16::MAX as usize {
+        return Err(SnapshotError::InvalidFormat(format!("{what} too long")));
+    }
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn push_time(out: &mut Vec<u8>, time: &DateTime<Utc>) {
+    out.extend_from_slice(&time.timestamp().to_be_bytes());
+    out.extend_from_slice(&time.timestamp_subsec_nanos().to_be_bytes());
+}
+
+/// Cursor over a decrypted manifest
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::InvalidFormat(
+                "truncated manifest".to_string(),
+            ));
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn hash(&mut self) -> Result<[u8; HASH_LEN], SnapshotError> {
+        self.array()
+    }
+
+    fn time(&mut self) -> Result<DateTime<Utc>, SnapshotError> {
+        let seconds = i64::from_be_bytes(self.array()?);
+        let nanos = self.u32()?;
+        DateTime::from_timestamp(seconds, nanos)
+            .ok_or_else(|| SnapshotError::InvalidFormat("timestamp out of range".to_string()))
+    }
+
+    fn short(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn long(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn string_short(&mut self, what: &str) -> Result<String, SnapshotError> {
+        let bytes = self.short()?;
+        utf8(bytes, what)
+    }
+
+    fn string_long(&mut self, what: &str) -> Result<String, SnapshotError> {
+        let bytes = self.long()?;
+        utf8(bytes, what)
+    }
+}
+
+fn utf8(bytes: &[u8], what: &str) -> Result<String, SnapshotError> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| SnapshotError::InvalidFormat(format!("{what} is not UTF-8")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::AsymmetricAlgorithm;
+    use crate::keystore::generate_key;
+
+    fn manifest(key: &EncryptionKey) -> Manifest {
+        let modified = DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap();
+        let file = |path: &str, hash: u8| ManifestEntry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            size: 5,
+            modified,
+            mode: 0o644,
+            content_hash: [hash; HASH_LEN],
+            objects: vec![ObjectId::new([hash; 16])],
+        };
+        Manifest {
+            snapshot_id: "20261017T120000Z-0a0b0c0d".to_string(),
+            created_at: modified,
+            host: "workstation".to_string(),
+            config_hash: [7; HASH_LEN],
+            device_id: key.metadata.device_id.clone(),
+            key_version: key.metadata.version,
+            key_id: key.fingerprint().unwrap().key_id(),
+            entries: vec![
+                file("docs/b.txt", 2),
+                ManifestEntry {
+                    path: "docs".to_string(),
+                    kind: EntryKind::Directory,
+                    size: 0,
+                    modified,
+                    mode: 0o755,
+                    content_hash: [0; HASH_LEN],
+                    objects: vec![],
+                },
+                file("a.txt", 1),
+            ],
+            signer: None,
+        }
+    }
+
+    fn seal(writer: &SnapshotWriter<'_>, manifest: &Manifest) -> Vec<u8> {
+        let mut out = Vec::new();
+        writer.write_to(manifest, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let original = manifest(&key);
+        let encrypted = seal(&SnapshotWriter::new(&key, &[]), &original);
+
+        let read = SnapshotReader::new(&key)
+            .read_from(&encrypted[..], &original.snapshot_id)
+            .unwrap();
+        let mut expected = original.clone();
+        expected.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(read, expected);
+        assert_eq!(
+            read.entry("docs/b.txt").unwrap().content_hash,
+            [2; HASH_LEN]
+        );
+
+        // Bound to its ID and readable only with the device key
+        assert!(SnapshotReader::new(&key)
+            .read_from(&encrypted[..], "20261017T120000Z-ffffffff")
+            .is_err());
+        let other = generate_key("AES-256", "USB001").unwrap();
+        assert!(SnapshotReader::new(&other)
+            .read_from(&encrypted[..], &original.snapshot_id)
+            .is_err());
+    }
+
+    #[test]
+    fn test_signed_manifest() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let signer = AsymmetricKey::generate(AsymmetricAlgorithm::Ed25519).unwrap();
+        let original = manifest(&key);
+        let signed = seal(
+            &SnapshotWriter::new(&key, &[]).signed_by(&signer),
+            &original,
+        );
+
+        let read = SnapshotReader::new(&key)
+            .trust(signer.fingerprint())
+            .read_from(&signed[..], &original.snapshot_id)
+            .unwrap();
+        assert_eq!(read.signer, Some(signer.fingerprint()));
+
+        let stranger = AsymmetricKey::generate(AsymmetricAlgorithm::Ed25519).unwrap();
+        let result = SnapshotReader::new(&key)
+            .trust(stranger.fingerprint())
+            .read_from(&signed[..], &original.snapshot_id);
+        assert!(matches!(result, Err(SnapshotError::UntrustedSigner(_))));
+
+        // An unsigned manifest is not accepted when a signer is required
+        let unsigned = seal(&SnapshotWriter::new(&key, &[]), &original);
+        let result = SnapshotReader::new(&key)
+            .trust(signer.fingerprint())
+            .read_from(&unsigned[..], &original.snapshot_id);
+        assert!(matches!(result, Err(SnapshotError::UntrustedSigner(_))));
+
+        // Any change to the signed part breaks the signature
+        let mut body = original.encode().unwrap();
+        let signature = signer.sign(&body).unwrap();
+        push_long(&mut body, &signer.public_key_der(), "signer").unwrap();
+        push_long(&mut body, &signature, "signature").unwrap();
+        assert!(Manifest::decode(&body).is_ok());
+        body[MAGIC.len() + 2] ^= 1;
+        assert!(matches!(
+            Manifest::decode(&body),
+            Err(SnapshotError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn test_snapshots_on_device() {
+        let device = tempfile::tempdir().unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let reader = SnapshotReader::new(&key);
+        assert!(reader.latest(device.path()).unwrap().is_none());
+
+        let writer = SnapshotWriter::new(&key, &[]);
+        let mut first = manifest(&key);
+        first.snapshot_id = "20261017T120000Z-00000001".to_string();
+        let mut second = manifest(&key);
+        second.snapshot_id = "20261018T120000Z-00000000".to_string();
+        for manifest in [&second, &first] {
+            let path = writer.write(device.path(), manifest).unwrap();
+            assert!(path.is_file());
+        }
+
+        assert_eq!(
+            list_snapshots(device.path()).unwrap(),
+            [first.snapshot_id.clone(), second.snapshot_id.clone()]
+        );
+        let latest = reader.latest(device.path()).unwrap().unwrap();
+        assert_eq!(latest.snapshot_id, second.snapshot_id);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed() {
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let mut body = manifest(&key).encode().unwrap();
+        body.extend_from_slice(&[0u8; 4]);
+        assert!(Manifest::decode(&body).is_ok());
+
+        assert!(Manifest::decode(&body[..body.len() - 1]).is_err());
+        let mut version = body.clone();
+        version[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            Manifest::decode(&version),
+            Err(SnapshotError::UnsupportedVersion(_))
+        ));
+
+        // Entries must be unique and in path order
+        let mut duplicated = manifest(&key);
+        duplicated.entries[0].path = "a.txt".to_string();
+        let mut body = duplicated.encode().unwrap();
+        body.extend_from_slice(&[0u8; 4]);
+        assert!(Manifest::decode(&body).is_err());
+    }
+}
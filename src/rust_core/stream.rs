@@ -0,0 +1,509 @@
+//! Streaming authenticated encryption
+//!
+//! Large files are encrypted as a sequence of fixed-size segments using the
+//! STREAM construction (Hoang, Reyhanitabar, Rogaway and Vizár, 2015), so
+//! memory use is bounded by the segment size rather than the file size.
+//!
+//! ```text
+//! header:  nonce prefix (7 bytes) || segment size (u32, big endian)
+//! segment: ciphertext || tag, sealed with
+//!          nonce = nonce prefix || counter (u32, big endian) || last flag
+//!          aad   = header || caller additional data
+//! ```
+//!
+//! Every segment except the last holds exactly `segment size` bytes of
+//! plaintext; the last holds the remainder (possibly none) and is sealed with
+//! the last flag set. The counter detects reordered or dropped segments, the
+//! last flag detects truncation at a segment boundary, and the random nonce
+//! prefix and header AAD stop segments being spliced between streams.
+
+use crate::crypto::{Algorithm, CryptoError, CryptoKey, NonceGenerator};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305};
+use std::io::{self, Read, Write};
+
+/// Length of the random per-stream nonce prefix
+pub const NONCE_PREFIX_LEN: usize = 7;
+
+/// Length of the stream header
+pub const HEADER_LEN: usize = NONCE_PREFIX_LEN + 4;
+
+/// Largest accepted segment size (1 GiB)
+pub const MAX_SEGMENT_SIZE: usize = 1 << 30;
+
+/// Last-segment flag values in the final nonce byte
+const NOT_LAST: u8 = 0;
+const LAST: u8 = 1;
+
+/// Per-stream sealing state shared by the encryptor and decryptor
+struct SegmentCipher {
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    aad: Vec<u8>,
+    tag_size: usize,
+}
+
+impl SegmentCipher {
+    fn new(key: &CryptoKey, header: &[u8; HEADER_LEN], aad: &[u8]) -> Result<Self, CryptoError> {
+        let algorithm = match key.algorithm() {
+            Algorithm::Aes256Gcm => &AES_256_GCM,
+            Algorithm::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        };
+        let unbound =
+            UnboundKey::new(algorithm, key.key()).map_err(|_| CryptoError::InvalidKeyLength)?;
+
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        nonce_prefix.copy_from_slice(&header[..NONCE_PREFIX_LEN]);
+
+        let mut segment_aad = Vec::with_capacity(HEADER_LEN + aad.len());
+        segment_aad.extend_from_slice(header);
+        segment_aad.extend_from_slice(aad);
+
+        Ok(Self {
+            key: LessSafeKey::new(unbound),
+            nonce_prefix,
+            counter: 0,
+            aad: segment_aad,
+            tag_size: key.algorithm().tag_size(),
+        })
+    }
+
+    /// Nonce for the current segment
+    fn nonce(&self, last: bool) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = if last { LAST } else { NOT_LAST };
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Move to the next segment, refusing to reuse a nonce
+    fn advance(&mut self) -> Result<(), CryptoError> {
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| CryptoError::InvalidStream("too many segments".to_string()))?;
+        Ok(())
+    }
+
+    fn seal(&mut self, segment: &mut Vec<u8>, last: bool) -> Result<(), CryptoError> {
+        self.key
+            .seal_in_place_append_tag(self.nonce(last), Aad::from(&self.aad), segment)
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+        self.advance()
+    }
+
+    fn open(&mut self, segment: &mut Vec<u8>, last: bool) -> Result<(), CryptoError> {
+        let len = self
+            .key
+            .open_in_place(self.nonce(last), Aad::from(&self.aad), segment)
+            .map_err(|_| CryptoError::DecryptionFailed)?
+            .len();
+        segment.truncate(len);
+        self.advance()
+    }
+}
+
+/// Encrypts a byte stream into segments as it is written
+///
+/// Call [`StreamEncryptor::finish`] once all data has been written; without
+/// it the stream is missing its last segment and will not decrypt.
+pub struct StreamEncryptor<W: Write> {
+    inner: W,
+    cipher: SegmentCipher,
+    segment_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    /// Start a stream, writing its header to `inner`
+    pub fn new(
+        key: &CryptoKey,
+        mut inner: W,
+        segment_size: usize,
+        aad: &[u8],
+    ) -> Result<Self, CryptoError> {
+        check_segment_size(segment_size)?;
+
+        let prefix = NonceGenerator::new().generate(NONCE_PREFIX_LEN)?;
+        let mut header = [0u8; HEADER_LEN];
+        header[..NONCE_PREFIX_LEN].copy_from_slice(&prefix);
+        header[NONCE_PREFIX_LEN..].copy_from_slice(&(segment_size as u32).to_be_bytes());
+        inner.write_all(&header)?;
+
+        let cipher = SegmentCipher::new(key, &header, aad)?;
+        Ok(Self {
+            inner,
+            cipher,
+            segment_size,
+            buffer: Vec::with_capacity(segment_size + key.algorithm().tag_size()),
+        })
+    }
+
+    /// Seal the final segment and return the underlying writer
+    pub fn finish(mut self) -> Result<W, CryptoError> {
+        self.cipher.seal(&mut self.buffer, true)?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Seal and write the buffered, full segment as a non-final segment
+    fn flush_segment(&mut self) -> Result<(), CryptoError> {
+        self.cipher.seal(&mut self.buffer, false)?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        // A full segment is only sealed once more data arrives, because
+        // until then it may still turn out to be the last one
+        if self.buffer.len() == self.segment_size {
+            self.flush_segment().map_err(to_io_error)?;
+        }
+        let take = data.len().min(self.segment_size - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts and authenticates a segmented stream as it is read
+///
+/// Plaintext is only released one verified segment at a time. A read error
+/// of kind `InvalidData` means the stream failed authentication; data
+/// returned before that point must be discarded by the caller.
+pub struct StreamDecryptor<R: Read> {
+    inner: R,
+    cipher: SegmentCipher,
+    segment_size: usize,
+    /// Verified plaintext of the current segment
+    plaintext: Vec<u8>,
+    position: usize,
+    /// Byte read ahead to tell whether the previous segment was the last
+    lookahead: Option<u8>,
+    finished: bool,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Read the stream header from `inner`
+    pub fn new(key: &CryptoKey, mut inner: R, aad: &[u8]) -> Result<Self, CryptoError> {
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => CryptoError::DecryptionFailed,
+            _ => CryptoError::Io(e),
+        })?;
+
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&header[NONCE_PREFIX_LEN..]);
+        let segment_size = u32::from_be_bytes(size) as usize;
+        check_segment_size(segment_size)?;
+
+        let cipher = SegmentCipher::new(key, &header, aad)?;
+        Ok(Self {
+            inner,
+            cipher,
+            segment_size,
+            plaintext: Vec::new(),
+            position: 0,
+            lookahead: None,
+            finished: false,
+        })
+    }
+
+    /// Plaintext segment size recorded in the stream header
+    pub fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    /// Read, authenticate and buffer the next segment
+    fn next_segment(&mut self) -> Result<(), CryptoError> {
+        let full = self.segment_size + self.cipher.tag_size;
+        let mut segment = std::mem::take(&mut self.plaintext);
+        segment.clear();
+        segment.extend(self.lookahead.take());
+
+        // Fill a whole ciphertext segment or stop at end of input. The buffer
+        // only grows as data arrives, since the segment size comes from the
+        // not yet authenticated header
+        let remaining = (full - segment.len()) as u64;
+        (&mut self.inner)
+            .take(remaining)
+            .read_to_end(&mut segment)?;
+        let filled = segment.len();
+
+        // A full segment is the last one only if nothing follows it
+        let last = if filled < full {
+            true
+        } else {
+            let mut byte = [0u8; 1];
+            let n = loop {
+                match self.inner.read(&mut byte) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    other => break other?,
+                }
+            };
+            if n == 1 {
+                self.lookahead = Some(byte[0]);
+            }
+            n == 0
+        };
+
+        self.cipher.open(&mut segment, last)?;
+        self.plaintext = segment;
+        self.position = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.finished || out.is_empty() {
+                return Ok(0);
+            }
+            self.next_segment().map_err(to_io_error)?;
+        }
+
+        let n = out.len().min(self.plaintext.len() - self.position);
+        out[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Drop for StreamDecryptor<R> {
+    fn drop(&mut self) {
+        use zeroize::Zeroize;
+        self.plaintext.zeroize();
+    }
+}
+
+/// Encrypt everything from `reader` into `writer`
+///
+/// Returns the number of plaintext bytes consumed.
+pub fn encrypt_stream<R: Read, W: Write>(
+    key: &CryptoKey,
+    reader: &mut R,
+    writer: W,
+    segment_size: usize,
+    aad: &[u8],
+) -> Result<u64, CryptoError> {
+    let mut encryptor = StreamEncryptor::new(key, writer, segment_size, aad)?;
+    let bytes = io::copy(reader, &mut encryptor).map_err(from_io_error)?;
+    encryptor.finish()?;
+    Ok(bytes)
+}
+
+/// Decrypt and authenticate everything from `reader` into `writer`
+///
+/// Returns the number of plaintext bytes written. On error, `writer` may
+/// already hold plaintext from earlier segments and must be discarded.
+pub fn decrypt_stream<R: Read, W: Write>(
+    key: &CryptoKey,
+    reader: R,
+    writer: &mut W,
+    aad: &[u8],
+) -> Result<u64, CryptoError> {
+    let mut decryptor = StreamDecryptor::new(key, reader, aad)?;
+    io::copy(&mut decryptor, writer).map_err(from_io_error)
+}
+
+/// Size of the encrypted stream for `plaintext_len` bytes of input
+pub fn encrypted_len(plaintext_len: u64, segment_size: usize, algorithm: Algorithm) -> u64 {
+    let segment_size = segment_size as u64;
+    let segments = plaintext_len.div_ceil(segment_size).max(1);
+    HEADER_LEN as u64 + plaintext_len + segments * algorithm.tag_size() as u64
+}
+
+fn check_segment_size(segment_size: usize) -> Result<(), CryptoError> {
+    if segment_size == 0 || segment_size > MAX_SEGMENT_SIZE {
+        return Err(CryptoError::InvalidStream(format!(
+            "segment size must be between 1 and {MAX_SEGMENT_SIZE} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Carry a `CryptoError` through an `io::Read`/`io::Write` boundary
+fn to_io_error(e: CryptoError) -> io::Error {
+    match e {
+        CryptoError::Io(e) => e,
+        other => io::Error::new(io::ErrorKind::InvalidData, other),
+    }
+}
+
+/// Recover a `CryptoError` carried by [`to_io_error`]
+fn from_io_error(e: io::Error) -> CryptoError {
+    if e.get_ref().is_some_and(|inner| inner.is::<CryptoError>()) {
+        if let Some(inner) = e.into_inner() {
+            if let Ok(crypto) = inner.downcast::<CryptoError>() {
+                return *crypto;
+            }
+        }
+        return CryptoError::DecryptionFailed;
+    }
+    CryptoError::Io(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT: usize = 64;
+
+    fn seal(key: &CryptoKey, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encrypt_stream(key, &mut &plaintext[..], &mut out, SEGMENT, aad).unwrap();
+        out
+    }
+
+    fn open(key: &CryptoKey, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut out = Vec::new();
+        decrypt_stream(key, ciphertext, &mut out, aad)?;
+        Ok(out)
+    }
+
+    /// Byte range of segment `index` within an encrypted stream
+    fn segment_range(index: usize) -> std::ops::Range<usize> {
+        let start = HEADER_LEN + index * (SEGMENT + 16);
+        start..start + SEGMENT + 16
+    }
+
+    #[test]
+    fn test_round_trip_both_algorithms() {
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::ChaCha20Poly1305] {
+            let key = CryptoKey::generate(algorithm).unwrap();
+            // Empty, partial, exactly one segment, exact multiple, remainder
+            for len in [0, 1, SEGMENT - 1, SEGMENT, 3 * SEGMENT, 3 * SEGMENT + 5] {
+                let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let ciphertext = seal(&key, &plaintext, b"aad");
+                assert_eq!(
+                    ciphertext.len() as u64,
+                    encrypted_len(len as u64, SEGMENT, algorithm)
+                );
+                assert_eq!(open(&key, &ciphertext, b"aad").unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_writes_and_reads() {
+        let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let plaintext: Vec<u8> = (0..500).map(|i| (i * 7) as u8).collect();
+
+        let mut encryptor = StreamEncryptor::new(&key, Vec::new(), SEGMENT, b"").unwrap();
+        for byte in &plaintext {
+            encryptor.write_all(std::slice::from_ref(byte)).unwrap();
+        }
+        let ciphertext = encryptor.finish().unwrap();
+
+        let mut decryptor = StreamDecryptor::new(&key, &ciphertext[..], b"").unwrap();
+        assert_eq!(decryptor.segment_size(), SEGMENT);
+        let mut out = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            let n = decryptor.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, plaintext);
+    }
+
+    #[test]
+    fn test_truncation_detected() {
+        let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let ciphertext = seal(&key, &[7u8; 3 * SEGMENT + 5], b"");
+
+        // Dropping the final segment leaves a stream ending on a full,
+        // non-final segment
+        let cut = segment_range(2).end;
+        assert!(matches!(
+            open(&key, &ciphertext[..cut], b""),
+            Err(CryptoError::DecryptionFailed)
+        ));
+
+        // Cutting inside a segment
+        assert!(open(&key, &ciphertext[..ciphertext.len() - 1], b"").is_err());
+
+        // Header only
+        assert!(open(&key, &ciphertext[..HEADER_LEN], b"").is_err());
+        assert!(open(&key, &ciphertext[..3], b"").is_err());
+    }
+
+    #[test]
+    fn test_reordering_detected() {
+        let key = CryptoKey::generate(Algorithm::ChaCha20Poly1305).unwrap();
+        let mut ciphertext = seal(&key, &[1u8; 3 * SEGMENT + 5], b"");
+
+        let first = ciphertext[segment_range(0)].to_vec();
+        let second = ciphertext[segment_range(1)].to_vec();
+        ciphertext[segment_range(0)].copy_from_slice(&second);
+        ciphertext[segment_range(1)].copy_from_slice(&first);
+
+        assert!(matches!(
+            open(&key, &ciphertext, b""),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_splicing_detected() {
+        let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let plaintext = [2u8; 2 * SEGMENT + 1];
+        let a = seal(&key, &plaintext, b"");
+        let mut b = seal(&key, &plaintext, b"");
+
+        // Same key and position, but a different stream
+        b[segment_range(1)].copy_from_slice(&a[segment_range(1)]);
+        assert!(open(&key, &b, b"").is_err());
+    }
+
+    #[test]
+    fn test_aad_and_header_bound() {
+        let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        let ciphertext = seal(&key, b"hello", b"file:a.txt");
+
+        assert!(open(&key, &ciphertext, b"file:b.txt").is_err());
+
+        let mut tampered = ciphertext.clone();
+        tampered[NONCE_PREFIX_LEN + 3] ^= 1;
+        assert!(open(&key, &tampered, b"file:a.txt").is_err());
+    }
+
+    #[test]
+    fn test_invalid_segment_size() {
+        let key = CryptoKey::generate(Algorithm::Aes256Gcm).unwrap();
+        assert!(matches!(
+            StreamEncryptor::new(&key, Vec::new(), 0, b""),
+            Err(CryptoError::InvalidStream(_))
+        ));
+
+        let mut header = [0u8; HEADER_LEN];
+        header[NONCE_PREFIX_LEN..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            StreamDecryptor::new(&key, &header[..], b""),
+            Err(CryptoError::InvalidStream(_))
+        ));
+
+        // A header claiming the largest size over a short body just fails
+        let size = MAX_SEGMENT_SIZE as u32;
+        header[NONCE_PREFIX_LEN..].copy_from_slice(&size.to_be_bytes());
+        let mut forged = header.to_vec();
+        forged.extend_from_slice(&[0u8; 32]);
+        let mut decryptor = StreamDecryptor::new(&key, &forged[..], b"").unwrap();
+        assert!(decryptor.read_to_end(&mut Vec::new()).is_err());
+    }
+}
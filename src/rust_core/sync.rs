@@ -0,0 +1,1030 @@
+//! One-way encrypted sync engine
+//!
+//! This module walks the configured source directory (see
+//! [`crate::walker`]) and writes an encrypted copy of every included regular
+//! file onto a device mount point. Files are encrypted as streams (see
+//! [`crate::stream`]) in segments of `policy.chunk_size_mb`, so memory use
+//! does not grow with file size. Each file gets its own data key, which its
+//! [`FileHeader`] stores wrapped under the device key and to each of the
+//! device's configured recipients. Each run ends by writing a snapshot
+//! manifest of everything it synced (see [`crate::snapshot`]).
+//!
+//! Runs are incremental: the source is diffed against the device's latest
+//! snapshot (see [`crate::diff`]) and only added or modified files are
+//! encrypted again. With `advanced.experimental_dedup` set, files are split
+//! into content-defined chunks that are stored once per device (see
+//! [`crate::dedup`]) instead of being encrypted whole.
+//!
+//! After the new manifest is written, snapshots outside the retention
+//! policy are deleted, along with any chunks no remaining snapshot uses.
+
+use crate::compress::Compression;
+use crate::config::{Config, DeviceConfig};
+use crate::dedup::{self, ChunkId, ChunkStore, Chunker};
+use crate::diff::{ChangeKind, SnapshotDiff};
+use crate::envelope::Recipient;
+use crate::header::{self, FileHeader, SlotKind};
+use crate::keys::AsymmetricKey;
+use crate::keystore::{EncryptionKey, KeyStore};
+use crate::snapshot::{
+    self, EntryKind, HashingReader, Manifest, ManifestEntry, ObjectId, SnapshotReader,
+    SnapshotWriter,
+};
+use crate::walker::SourceWalker;
+use crate::{AirGapError, Result};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Directory on the device that holds all AirGapSync data
+pub const DEVICE_ROOT: &str = "AirGapSync";
+
+/// Directory under the device root that holds encrypted file data
+pub const DATA_DIR: &str = "data";
+
+/// File extension appended to every encrypted file
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
+/// Extension appended to an encrypted file while it is being written
+const TEMP_EXTENSION: &str = "tmp";
+
+/// A file that could not be synced
+#[derive(Debug, Clone)]
+pub struct SyncFailure {
+    /// Path relative to the source root
+    pub path: PathBuf,
+    /// Human-readable reason
+    pub reason: String,
+}
+
+/// Summary of a completed sync run
+#[derive(Debug, Clone)]
+pub struct SyncReport {
+    /// Device the data was written to
+    pub device_id: String,
+    /// When the run started
+    pub started_at: DateTime<Utc>,
+    /// When the run finished
+    pub completed_at: DateTime<Utc>,
+    /// Number of files encrypted and written
+    pub files_synced: u64,
+    /// Number of files skipped as unchanged since the previous snapshot
+    pub files_unchanged: u64,
+    /// Number of files in the previous snapshot no longer in the source
+    pub files_deleted: u64,
+    /// Total plaintext bytes read from the source
+    pub bytes_read: u64,
+    /// Total ciphertext bytes written to the device
+    pub bytes_written: u64,
+    /// Files that failed to sync
+    pub failures: Vec<SyncFailure>,
+    /// Snapshot recorded by the run
+    pub snapshot_id: String,
+    /// Number of old snapshots deleted under the retention policy
+    pub snapshots_pruned: u64,
+    /// Number of chunks deleted because no snapshot uses them any more
+    pub chunks_pruned: u64,
+}
+
+impl SyncReport {
+    /// Whether every file was synced without error
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Sync engine for a single device
+pub struct SyncEngine<'a> {
+    config: &'a Config,
+    device: &'a DeviceConfig,
+    key: EncryptionKey,
+    recipients: Vec<Recipient>,
+    signing_key: Option<AsymmetricKey>,
+    key_store: Option<&'a dyn KeyStore>,
+}
+
+impl<'a> SyncEngine<'a> {
+    /// Create a sync engine for the device with the given ID
+    ///
+    /// Fails if a configured recipient's public key cannot be loaded, or
+    /// does not match the device's `post_quantum` setting.
+    pub fn new(config: &'a Config, device_id: &str, key: &EncryptionKey) -> Result<Self> {
+        let device = config
+            .device
+            .iter()
+            .find(|d| d.id == device_id)
+            .ok_or_else(|| AirGapError::DeviceNotFound(device_id.to_string()))?;
+
+        Ok(Self {
+            config,
+            device,
+            key: key.clone(),
+            recipients: device.load_recipients()?,
+            signing_key: None,
+            key_store: None,
+        })
+    }
+
+    /// Sign snapshot manifests with a signing key pair
+    pub fn with_signing_key(mut self, key: AsymmetricKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Fetch archived versions of the device key from `store`
+    ///
+    /// Lets a chunk index written before the key was rotated be opened, and
+    /// sealed under the current version when the run commits.
+    pub fn with_key_store(mut self, store: &'a dyn KeyStore) -> Self {
+        self.key_store = Some(store);
+        self
+    }
+
+    /// Directory on the device where encrypted files are written
+    pub fn data_dir(&self) -> PathBuf {
+        device_data_dir(&self.device.mount_point)
+    }
+
+    /// Compare the source directory with the device's latest snapshot
+    ///
+    /// Reads the source and the device but writes nothing, so the result
+    /// doubles as a dry run of [`SyncEngine::run`].
+    pub fn diff(&self) -> Result<SnapshotDiff> {
+        let source_root = &self.config.source.path;
+        if !source_root.is_dir() {
+            return Err(AirGapError::SyncError(format!(
+                "Source directory does not exist: {}",
+                source_root.display()
+            )));
+        }
+
+        if !self.device.mount_point.is_dir() {
+            return Err(AirGapError::DeviceNotFound(format!(
+                "{} (mount point {} is not available)",
+                self.device.id,
+                self.device.mount_point.display()
+            )));
+        }
+
+        let walker = SourceWalker::new(&self.config.source)?;
+        let previous = self.previous_snapshot();
+        Ok(SnapshotDiff::compute(&walker, previous.as_ref()))
+    }
+
+    /// Sync the source directory onto the device
+    ///
+    /// Only files added or modified since the latest snapshot are encrypted
+    /// and written, along with any unchanged file whose encrypted copy has
+    /// gone missing. Encrypted copies of deleted files are left in place.
+    pub fn run(&self) -> Result<SyncReport> {
+        let started_at = Utc::now();
+        let diff = self.diff()?;
+
+        let data_dir = self.data_dir();
+        std::fs::create_dir_all(&data_dir)?;
+        let mut manifest = Manifest::new(self.config, &self.key)?;
+        let mut chunks = if self.config.advanced.experimental_dedup {
+            let chunker = Chunker::new(self.config.policy.chunk_size_bytes());
+            let compression = Compression::from_policy_level(self.config.policy.compression_level);
+            let store = match self.key_store {
+                Some(key_store) => ChunkStore::open_with_archive(
+                    &self.device.mount_point,
+                    &self.key,
+                    key_store,
+                    &self.recipients,
+                    chunker,
+                )?,
+                None => ChunkStore::open(
+                    &self.device.mount_point,
+                    &self.key,
+                    &self.recipients,
+                    chunker,
+                )?,
+            };
+            Some(store.with_compression(compression))
+        } else {
+            None
+        };
+
+        let mut report = SyncReport {
+            device_id: self.device.id.clone(),
+            started_at,
+            completed_at: started_at,
+            files_synced: 0,
+            files_unchanged: 0,
+            files_deleted: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            failures: diff.failures,
+            snapshot_id: manifest.snapshot_id.clone(),
+            snapshots_pruned: 0,
+            chunks_pruned: 0,
+        };
+
+        for change in diff.changes {
+            let (Some(source), Some(mut record)) = (change.source, change.current) else {
+                if change.previous.is_some_and(|p| p.kind == EntryKind::File) {
+                    report.files_deleted += 1;
+                }
+                continue;
+            };
+            if record.kind == EntryKind::Directory {
+                manifest.entries.push(record);
+                continue;
+            }
+
+            let relative = PathBuf::from(&change.path);
+            if change.kind == ChangeKind::Unchanged
+                && self.is_stored(&record, &relative, &data_dir, chunks.as_ref())
+            {
+                report.files_unchanged += 1;
+                manifest.entries.push(record);
+                continue;
+            }
+
+            let synced = match &mut chunks {
+                Some(store) => self.store_chunks(&source, store),
+                None => self.sync_file(&source, &relative, &data_dir),
+            };
+            match synced {
+                Ok(synced) => {
+                    log::debug!("Synced {} ({})", relative.display(), change.kind);
+                    report.files_synced += 1;
+                    report.bytes_read += synced.bytes_read;
+                    report.bytes_written += synced.bytes_written;
+
+                    record.size = synced.bytes_read;
+                    record.content_hash = synced.content_hash;
+                    record.objects = synced.objects;
+                    manifest.entries.push(record);
+                }
+                Err(e) => {
+                    log::warn!("Failed to sync {}: {e}", relative.display());
+                    report.failures.push(SyncFailure {
+                        path: relative,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        if let Some(store) = &mut chunks {
+            store.commit(&manifest)?;
+        }
+        let mut writer = SnapshotWriter::new(&self.key, &self.recipients);
+        if let Some(signing_key) = &self.signing_key {
+            writer = writer.signed_by(signing_key);
+        }
+        writer.write(&self.device.mount_point, &manifest)?;
+        log::debug!("Recorded snapshot {}", manifest.snapshot_id);
+
+        // The new snapshot is safely recorded, so a failure here only
+        // leaves old data around until the next run
+        match self.prune_snapshots(&manifest, chunks.as_mut()) {
+            Ok((snapshots, chunks)) => {
+                report.snapshots_pruned = snapshots;
+                report.chunks_pruned = chunks;
+            }
+            Err(e) => log::warn!("Failed to prune old snapshots: {e}"),
+        }
+
+        report.completed_at = Utc::now();
+        Ok(report)
+    }
+
+    /// Latest snapshot on the device to diff against
+    ///
+    /// A snapshot that cannot be read, or that was taken under another key
+    /// version, is ignored so that every file is written again.
+    fn previous_snapshot(&self) -> Option<Manifest> {
+        match self.snapshot_reader().latest(&self.device.mount_point) {
+            Ok(Some(manifest)) if manifest.key_version != self.key.metadata.version => {
+                log::info!(
+                    "Snapshot {} uses key version {}, syncing every file",
+                    manifest.snapshot_id,
+                    manifest.key_version
+                );
+                None
+            }
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log::warn!("Cannot read the latest snapshot, syncing every file: {e}");
+                None
+            }
+        }
+    }
+
+    /// Delete snapshots outside the retention policy, then unused chunks
+    ///
+    /// A snapshot is deleted once it is older than `policy.retain_days` and
+    /// not among the newest `policy.retain_snapshots` (at least the one just
+    /// written, `current`). Snapshots that cannot be read and verified with
+    /// the current key are kept, since their chunk references cannot be
+    /// released. Returns the number of snapshots and chunks deleted.
+    fn prune_snapshots(
+        &self,
+        current: &Manifest,
+        chunks: Option<&mut ChunkStore>,
+    ) -> Result<(u64, u64)> {
+        let mount_point = &self.device.mount_point;
+        let keep = (self.config.policy.retain_snapshots as usize).max(1);
+        let cutoff =
+            current.created_at - chrono::Duration::days(self.config.policy.retain_days as i64);
+        let reader = self.snapshot_reader();
+
+        let mut released = Vec::new();
+        for snapshot_id in snapshot::list_snapshots(mount_point)?
+            .iter()
+            .rev()
+            .skip(keep)
+        {
+            if *snapshot_id == current.snapshot_id {
+                continue;
+            }
+            match reader.read(mount_point, snapshot_id) {
+                Ok(manifest) if manifest.created_at < cutoff => released.push(manifest),
+                Ok(_) => {}
+                Err(e) => log::info!("Keeping snapshot {snapshot_id}: {e}"),
+            }
+        }
+
+        // Manifests go first: if the run stops before the index is saved,
+        // reference counts are left too high, never too low
+        for manifest in &released {
+            std::fs::remove_file(snapshot::manifest_file(mount_point, &manifest.snapshot_id))?;
+            log::debug!("Deleted snapshot {}", manifest.snapshot_id);
+        }
+        let chunks_pruned = match chunks {
+            Some(store) => store.collect_garbage(&released)?,
+            None => 0,
+        };
+        Ok((released.len() as u64, chunks_pruned))
+    }
+
+    /// Reader for this device's snapshots, trusting only the configured
+    /// signing key if there is one
+    fn snapshot_reader(&self) -> SnapshotReader<'_> {
+        let reader = SnapshotReader::new(&self.key);
+        match &self.signing_key {
+            Some(signing_key) => reader.trust(signing_key.fingerprint()),
+            None => reader,
+        }
+    }
+
+    /// Encrypt a single file and write it under the data directory
+    ///
+    /// The file is written to a temporary file and renamed over the previous
+    /// copy, so a failed or interrupted write keeps that copy intact.
+    fn sync_file(&self, source: &Path, relative: &Path, data_dir: &Path) -> Result<SyncedFile> {
+        let destination = encrypted_path(data_dir, relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temp = destination.as_os_str().to_os_string();
+        temp.push(".");
+        temp.push(TEMP_EXTENSION);
+        let temp = PathBuf::from(temp);
+
+        let result = self
+            .write_encrypted(source, relative, &temp)
+            .and_then(|synced| {
+                std::fs::rename(&temp, &destination)?;
+                Ok(synced)
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Encrypt `source` into `destination` and flush it to disk
+    fn write_encrypted(
+        &self,
+        source: &Path,
+        relative: &Path,
+        destination: &Path,
+    ) -> Result<SyncedFile> {
+        let mut reader = HashingReader::new(BufReader::new(File::open(source)?));
+        let aad = file_aad(relative);
+        let mut writer = BufWriter::new(File::create(destination)?);
+
+        let compression = Compression::from_policy_level(self.config.policy.compression_level);
+        let (compression, mut plaintext) = compression.for_stream(&mut reader)?;
+        let (header, data_key) =
+            FileHeader::seal_compressed(&self.key, &self.recipients, compression)?;
+        let read = header::encrypt_file(
+            &data_key,
+            &header,
+            &mut plaintext,
+            &mut writer,
+            self.config.policy.chunk_size_bytes(),
+            aad.as_bytes(),
+        )?;
+        drop(plaintext);
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        let written = file.metadata()?.len();
+
+        Ok(SyncedFile {
+            bytes_read: read,
+            bytes_written: written,
+            content_hash: reader.finish(),
+            objects: vec![ObjectId::new(header.file_id)],
+        })
+    }
+
+    /// Split a single file into chunks and store the new ones
+    fn store_chunks(&self, source: &Path, store: &mut ChunkStore) -> Result<SyncedFile> {
+        let mut reader = HashingReader::new(BufReader::new(File::open(source)?));
+        let stored = store.store(&mut reader)?;
+
+        Ok(SyncedFile {
+            bytes_read: stored.bytes_read,
+            bytes_written: stored.bytes_written,
+            content_hash: reader.finish(),
+            objects: stored.chunks.into_iter().map(ObjectId::from).collect(),
+        })
+    }
+
+    /// Whether an unchanged file's stored objects can be carried forward
+    ///
+    /// They must be in the form this run writes: chunks in the index when
+    /// deduplicating, otherwise the encrypted copy whose header they name.
+    fn is_stored(
+        &self,
+        record: &ManifestEntry,
+        relative: &Path,
+        data_dir: &Path,
+        chunks: Option<&ChunkStore>,
+    ) -> bool {
+        match chunks {
+            Some(store) => record.objects.iter().all(|object| {
+                ChunkId::from_object(object).is_some_and(|id| store.index().contains(&id))
+            }),
+            None => {
+                let Ok(file) = File::open(encrypted_path(data_dir, relative)) else {
+                    return false;
+                };
+                FileHeader::read_from(&mut BufReader::new(file))
+                    .is_ok_and(|header| record.objects == [ObjectId::new(header.file_id)])
+            }
+        }
+    }
+}
+
+/// Outcome of encrypting one file
+struct SyncedFile {
+    bytes_read: u64,
+    bytes_written: u64,
+    content_hash: [u8; snapshot::HASH_LEN],
+    objects: Vec<ObjectId>,
+}
+
+/// Directory holding encrypted file data under a device mount point
+pub fn device_data_dir(mount_point: &Path) -> PathBuf {
+    mount_point.join(DEVICE_ROOT).join(DATA_DIR)
+}
+
+/// Key versions referenced by the encrypted files on a device
+///
+/// Reads the header of every encrypted file, snapshot manifest, chunk and
+/// chunk index on the device and returns, per device ID whose key wraps a
+/// data key in those headers, the set of key versions used. A device that
+/// has never been synced yields an empty map. Unreadable headers are an
+/// error, since the versions they would reference are unknown.
+pub fn key_versions_in_use(mount_point: &Path) -> Result<BTreeMap<String, BTreeSet<u32>>> {
+    let mut in_use: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+    let locations = [
+        (device_data_dir(mount_point), ENCRYPTED_EXTENSION),
+        (
+            snapshot::snapshot_dir(mount_point),
+            snapshot::MANIFEST_EXTENSION,
+        ),
+        (dedup::chunk_dir(mount_point), dedup::CHUNK_EXTENSION),
+        (dedup::chunk_dir(mount_point), dedup::INDEX_EXTENSION),
+    ];
+
+    for (dir, extension) in &locations {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(dir) {
+            let entry = entry.map_err(|e| AirGapError::SyncError(e.to_string()))?;
+            let matches = entry.path().extension().and_then(|e| e.to_str()) == Some(*extension);
+            if !entry.file_type().is_file() || !matches {
+                continue;
+            }
+
+            let header = FileHeader::read_from(&mut BufReader::new(File::open(entry.path())?))
+                .map_err(|e| AirGapError::SyncError(format!("{}: {e}", entry.path().display())))?;
+            for slot in header.slots {
+                if let SlotKind::Device {
+                    device_id,
+                    key_version,
+                    ..
+                } = slot.kind
+                {
+                    in_use.entry(device_id).or_default().insert(key_version);
+                }
+            }
+        }
+    }
+
+    Ok(in_use)
+}
+
+/// Location of the encrypted copy of `relative` under `data_dir`
+pub fn encrypted_path(data_dir: &Path, relative: &Path) -> PathBuf {
+    let mut name = relative.as_os_str().to_os_string();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    data_dir.join(name)
+}
+
+/// Source-relative path of an encrypted file under `data_dir`
+///
+/// Inverse of [`encrypted_path`]; returns `None` for paths outside the data
+/// directory or without the encrypted extension.
+pub fn source_relative_path(data_dir: &Path, encrypted: &Path) -> Option<PathBuf> {
+    let relative = encrypted.strip_prefix(data_dir).ok()?;
+    let name = relative.to_str()?;
+    let stem = name.strip_suffix(&format!(".{ENCRYPTED_EXTENSION}"))?;
+    (!stem.is_empty()).then(|| PathBuf::from(stem))
+}
+
+/// Additional authenticated data of an encrypted file on a device, from
+/// its location under `AirGapSync/data`
+///
+/// Returns `None` for files outside a data directory. If the path passes
+/// through more than one, the outermost is the device's.
+pub fn data_file_aad(encrypted: &Path) -> Option<String> {
+    let data_root = Path::new(DEVICE_ROOT).join(DATA_DIR);
+    let data_dir = encrypted
+        .ancestors()
+        .skip(1)
+        .filter(|dir| dir.ends_with(&data_root))
+        .last()?;
+    source_relative_path(data_dir, encrypted).map(|relative| file_aad(&relative))
+}
+
+/// Additional authenticated data binding ciphertext to its source path
+///
+/// Components are joined with `/` so the same tree produces the same AAD
+/// regardless of the host platform.
+pub fn file_aad(relative: &Path) -> String {
+    let components: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    format!("file:{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::keystore::generate_key;
+
+    fn test_config(source: &Path, mount_point: &Path) -> Config {
+        Config {
+            general: GeneralConfig::default(),
+            source: SourceConfig {
+                path: source.to_path_buf(),
+                exclude: vec![],
+                follow_symlinks: false,
+                include_hidden: false,
+            },
+            device: vec![DeviceConfig {
+                id: "USB001".to_string(),
+                name: "Test USB".to_string(),
+                mount_point: mount_point.to_path_buf(),
+                encryption: EncryptionConfig::default(),
+                recipients: vec![],
+            }],
+            policy: PolicyConfig::default(),
+            security: SecurityConfig::default(),
+            schedule: None,
+            notifications: NotificationConfig::default(),
+            advanced: AdvancedConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_sync_encrypts_tree() {
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("docs")).unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+        std::fs::write(source.path().join("docs/b.txt"), b"bravo").unwrap();
+
+        let config = test_config(source.path(), device.path());
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        let report = engine.run().unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(report.files_synced, 2);
+        assert_eq!(report.bytes_read, 10);
+
+        let encrypted = encrypted_path(&engine.data_dir(), Path::new("docs/b.txt"));
+        let ciphertext = std::fs::read(encrypted).unwrap();
+        assert_eq!(report.bytes_written, 2 * ciphertext.len() as u64);
+
+        let mut reader = &ciphertext[..];
+        let file_header = FileHeader::read_from(&mut reader).unwrap();
+        let data_key = file_header.open_with_device_key(&key).unwrap();
+
+        let aad = file_aad(Path::new("docs/b.txt"));
+        let mut plaintext = Vec::new();
+        header::decrypt_file(
+            &data_key,
+            &file_header,
+            reader,
+            &mut plaintext,
+            aad.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(plaintext, b"bravo");
+
+        // The run recorded a snapshot of what it wrote
+        let manifest = crate::snapshot::SnapshotReader::new(&key)
+            .read(device.path(), &report.snapshot_id)
+            .unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "docs", "docs/b.txt"]);
+        let entry = manifest.entry("docs/b.txt").unwrap();
+        assert_eq!(entry.size, 5);
+        assert_eq!(entry.objects, [ObjectId::new(file_header.file_id)]);
+        assert_eq!(
+            entry.content_hash,
+            crate::snapshot::hash_file(&source.path().join("docs/b.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sync_skips_excluded() {
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("cache")).unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+        std::fs::write(source.path().join("cache/b.txt"), b"bravo").unwrap();
+        std::fs::write(source.path().join(".secret"), b"charlie").unwrap();
+
+        let mut config = test_config(source.path(), device.path());
+        config.source.exclude = vec!["cache/".to_string()];
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        let report = engine.run().unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(report.files_synced, 1);
+        assert!(encrypted_path(&engine.data_dir(), Path::new("a.txt")).exists());
+        assert!(!engine.data_dir().join("cache").exists());
+    }
+
+    #[test]
+    fn test_sync_writes_only_changes() {
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+        std::fs::write(source.path().join("b.txt"), b"bravo").unwrap();
+        std::fs::write(source.path().join("c.txt"), b"charlie").unwrap();
+
+        let config = test_config(source.path(), device.path());
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        assert_eq!(engine.run().unwrap().files_synced, 3);
+
+        std::fs::write(source.path().join("b.txt"), b"BRAVO!").unwrap();
+        std::fs::remove_file(source.path().join("c.txt")).unwrap();
+        std::fs::write(source.path().join("d.txt"), b"delta").unwrap();
+        let a_copy = encrypted_path(&engine.data_dir(), Path::new("a.txt"));
+        let a_before = std::fs::read(&a_copy).unwrap();
+
+        let diff = engine.diff().unwrap();
+        assert_eq!(diff.count(ChangeKind::Added), 1);
+        assert_eq!(diff.count(ChangeKind::Modified), 1);
+        assert_eq!(diff.count(ChangeKind::Deleted), 1);
+
+        let report = engine.run().unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.files_synced, 2);
+        assert_eq!(report.files_unchanged, 1);
+        assert_eq!(report.files_deleted, 1);
+        assert_eq!(report.bytes_read, 11);
+        assert_eq!(std::fs::read(&a_copy).unwrap(), a_before);
+
+        // The new snapshot still lists the unchanged file
+        let manifest = crate::snapshot::SnapshotReader::new(&key)
+            .read(device.path(), &report.snapshot_id)
+            .unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt", "d.txt"]);
+
+        // A missing encrypted copy is written again even if unchanged
+        std::fs::remove_file(&a_copy).unwrap();
+        let report = engine.run().unwrap();
+        assert_eq!(report.files_synced, 1);
+        assert!(a_copy.exists());
+    }
+
+    #[test]
+    fn test_failed_write_keeps_previous_copy() {
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+
+        let config = test_config(source.path(), device.path());
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        engine.run().unwrap();
+        let copy = encrypted_path(&engine.data_dir(), Path::new("a.txt"));
+        let before = std::fs::read(&copy).unwrap();
+
+        // A directory in the way of the temporary file makes the write fail
+        std::fs::write(source.path().join("a.txt"), b"ALPHA").unwrap();
+        let mut temp = copy.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::create_dir(&temp).unwrap();
+        let report = engine.run().unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(std::fs::read(&copy).unwrap(), before);
+
+        std::fs::remove_dir(&temp).unwrap();
+        let report = engine.run().unwrap();
+        assert!(report.is_success());
+        assert_ne!(std::fs::read(&copy).unwrap(), before);
+        assert!(!Path::new(&temp).exists());
+    }
+
+    #[test]
+    fn test_sync_deduplicates_chunks() {
+        use crate::dedup::{ChunkId, ChunkStore, Chunker};
+
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"same contents").unwrap();
+        std::fs::write(source.path().join("copy.txt"), b"same contents").unwrap();
+        std::fs::write(source.path().join("b.txt"), b"other contents").unwrap();
+
+        let mut config = test_config(source.path(), device.path());
+        config.advanced.experimental_dedup = true;
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        let report = engine.run().unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.files_synced, 3);
+        assert!(!encrypted_path(&engine.data_dir(), Path::new("a.txt")).exists());
+
+        let manifest = crate::snapshot::SnapshotReader::new(&key)
+            .read(device.path(), &report.snapshot_id)
+            .unwrap();
+        let objects = &manifest.entry("a.txt").unwrap().objects;
+        assert_eq!(objects, &manifest.entry("copy.txt").unwrap().objects);
+        let id = ChunkId::from_object(&objects[0]).unwrap();
+
+        let store = ChunkStore::open(device.path(), &key, &[], Chunker::new(1 << 20)).unwrap();
+        assert_eq!(store.index().len(), 2);
+        assert_eq!(store.index().get(&id).unwrap().references, 2);
+        assert_eq!(&store.read_chunk(&id).unwrap()[..], b"same contents");
+        // One chunk per distinct contents; "other contents" is a byte longer
+        let chunk_len = std::fs::metadata(store.chunk_path(&id)).unwrap().len();
+        assert_eq!(report.bytes_written, 2 * chunk_len + 1);
+
+        // Unchanged files keep their chunks, which the new snapshot also
+        // references
+        let report = engine.run().unwrap();
+        assert_eq!((report.files_synced, report.files_unchanged), (0, 3));
+        let store = ChunkStore::open(device.path(), &key, &[], Chunker::new(1 << 20)).unwrap();
+        assert_eq!(store.index().get(&id).unwrap().references, 4);
+        assert_eq!(
+            key_versions_in_use(device.path()).unwrap()["USB001"],
+            BTreeSet::from([1])
+        );
+    }
+
+    #[test]
+    fn test_sync_prunes_old_snapshots() {
+        use crate::dedup::{ChunkId, ChunkStore, Chunker};
+
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        let mut config = test_config(source.path(), device.path());
+        config.advanced.experimental_dedup = true;
+        config.policy.retain_snapshots = 2;
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+
+        std::fs::write(source.path().join("a.txt"), b"first").unwrap();
+        let first = engine.run().unwrap();
+        let manifest = SnapshotReader::new(&key)
+            .read(device.path(), &first.snapshot_id)
+            .unwrap();
+        let first_chunk =
+            ChunkId::from_object(&manifest.entry("a.txt").unwrap().objects[0]).unwrap();
+        for contents in ["second", "third"] {
+            std::fs::write(source.path().join("a.txt"), contents).unwrap();
+            let report = engine.run().unwrap();
+            assert_eq!(report.snapshots_pruned, 0);
+        }
+
+        // Snapshots within retain_days are kept whatever their number
+        assert_eq!(snapshot::list_snapshots(device.path()).unwrap().len(), 3);
+
+        config.policy.retain_days = 0;
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        let report = engine.run().unwrap();
+        // The first two snapshots go, with the chunks only they used
+        assert_eq!((report.snapshots_pruned, report.chunks_pruned), (2, 2));
+        let snapshots = snapshot::list_snapshots(device.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(!snapshots.contains(&first.snapshot_id));
+
+        let store = ChunkStore::open(device.path(), &key, &[], Chunker::new(1 << 20)).unwrap();
+        assert!(!store.index().contains(&first_chunk));
+        assert!(!store.chunk_path(&first_chunk).exists());
+        assert_eq!(store.index().len(), 1);
+    }
+
+    #[test]
+    fn test_sync_compresses_before_encryption() {
+        use crate::compress::Compression;
+
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        let text = b"the same line, over and over again\n".repeat(4096);
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise: Vec<u8> = (0..text.len())
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        std::fs::write(source.path().join("text.txt"), &text).unwrap();
+        std::fs::write(source.path().join("noise.bin"), &noise).unwrap();
+
+        let mut config = test_config(source.path(), device.path());
+        config.policy.compression_level = 9;
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        let report = engine.run().unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.bytes_read, 2 * text.len() as u64);
+
+        // Each object records its own codec, so it reads back from the
+        // header alone
+        let open = |relative: &str| {
+            let ciphertext =
+                std::fs::read(encrypted_path(&engine.data_dir(), Path::new(relative))).unwrap();
+            let mut reader = &ciphertext[..];
+            let file_header = FileHeader::read_from(&mut reader).unwrap();
+            let data_key = file_header.open_with_device_key(&key).unwrap();
+            let mut plaintext = Vec::new();
+            header::decrypt_file(
+                &data_key,
+                &file_header,
+                reader,
+                &mut plaintext,
+                file_aad(Path::new(relative)).as_bytes(),
+            )
+            .unwrap();
+            (file_header.compression, ciphertext.len(), plaintext)
+        };
+
+        let (compression, len, plaintext) = open("text.txt");
+        assert_eq!(compression, Compression::Zstd { level: 19 });
+        assert!(len < text.len() / 10);
+        assert_eq!(plaintext, text);
+
+        let (compression, len, plaintext) = open("noise.bin");
+        assert_eq!(compression, Compression::None);
+        assert!(len > noise.len());
+        assert_eq!(plaintext, noise);
+    }
+
+    #[test]
+    fn test_sync_wraps_to_recipients() {
+        use crate::keys::{AsymmetricAlgorithm, AsymmetricKey};
+
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+
+        let officer = AsymmetricKey::generate(AsymmetricAlgorithm::EcdsaP384).unwrap();
+        let pem_path = source.path().join("officer.pem");
+        std::fs::write(&pem_path, officer.public_key_pem()).unwrap();
+
+        let mut config = test_config(source.path(), device.path());
+        config.device[0].recipients.push(RecipientConfig {
+            name: "officer".to_string(),
+            public_key: pem_path,
+            ml_kem_public_key: None,
+        });
+        let key = generate_key("AES-256", "USB001").unwrap();
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        assert!(engine.run().unwrap().is_success());
+
+        // The officer's private key opens the file without the device key
+        let ciphertext =
+            std::fs::read(encrypted_path(&engine.data_dir(), Path::new("a.txt"))).unwrap();
+        let mut reader = &ciphertext[..];
+        let file_header = FileHeader::read_from(&mut reader).unwrap();
+        let data_key = file_header.open_with_private_key(&officer).unwrap();
+        let mut plaintext = Vec::new();
+        header::decrypt_file(
+            &data_key,
+            &file_header,
+            reader,
+            &mut plaintext,
+            file_aad(Path::new("a.txt")).as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(plaintext, b"alpha");
+
+        // A recipient whose key cannot be read stops the sync up front
+        config.device[0].recipients[0].public_key = source.path().join("missing.pem");
+        assert!(SyncEngine::new(&config, "USB001", &key).is_err());
+    }
+
+    #[test]
+    fn test_source_relative_path() {
+        let data_dir = Path::new("/mnt/usb/AirGapSync/data");
+        let relative = Path::new("docs/b.txt");
+        let encrypted = encrypted_path(data_dir, relative);
+        assert_eq!(
+            source_relative_path(data_dir, &encrypted).as_deref(),
+            Some(relative)
+        );
+        assert_eq!(
+            source_relative_path(data_dir, Path::new("/mnt/usb/AirGapSync/data/x.tmp")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_data_file_aad() {
+        let data_dir = Path::new("/mnt/usb/AirGapSync/data");
+        let encrypted = encrypted_path(data_dir, Path::new("docs/AirGapSync/data/b.txt"));
+        assert_eq!(
+            data_file_aad(&encrypted).as_deref(),
+            Some("file:docs/AirGapSync/data/b.txt")
+        );
+        assert_eq!(data_file_aad(Path::new("/tmp/b.txt.enc")), None);
+    }
+
+    #[test]
+    fn test_key_versions_in_use() {
+        let source = tempfile::tempdir().unwrap();
+        let device = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+        let config = test_config(source.path(), device.path());
+
+        assert!(key_versions_in_use(device.path()).unwrap().is_empty());
+
+        let mut key = generate_key("AES-256", "USB001").unwrap();
+        SyncEngine::new(&config, "USB001", &key)
+            .unwrap()
+            .run()
+            .unwrap();
+        std::fs::write(source.path().join("b.txt"), b"bravo").unwrap();
+        key.metadata.version = 2;
+        SyncEngine::new(&config, "USB001", &key)
+            .unwrap()
+            .run()
+            .unwrap();
+
+        // The second run rewrote every file under version 2, but the first
+        // run's snapshot manifest still uses version 1
+        let in_use = key_versions_in_use(device.path()).unwrap();
+        assert_eq!(in_use["USB001"], BTreeSet::from([1, 2]));
+
+        let stray = device_data_dir(device.path()).join("stray.enc");
+        std::fs::write(stray, b"not a header").unwrap();
+        assert!(key_versions_in_use(device.path()).is_err());
+    }
+
+    #[test]
+    fn test_sync_unknown_device() {
+        let source = tempfile::tempdir().unwrap();
+        let config = test_config(source.path(), source.path());
+        let key = generate_key("AES-256", "USB001").unwrap();
+
+        let result = SyncEngine::new(&config, "NOPE", &key);
+        assert!(matches!(result, Err(AirGapError::DeviceNotFound(_))));
+    }
+
+    #[test]
+    fn test_sync_missing_mount_point() {
+        let source = tempfile::tempdir().unwrap();
+        let config = test_config(source.path(), Path::new("/nonexistent/mount"));
+        let key = generate_key("AES-256", "USB001").unwrap();
+
+        let engine = SyncEngine::new(&config, "USB001", &key).unwrap();
+        assert!(matches!(engine.run(), Err(AirGapError::DeviceNotFound(_))));
+    }
+}
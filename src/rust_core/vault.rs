@@ -0,0 +1,523 @@
+//! Passphrase-protected key vault
+//!
+//! A vault is a single file (by default `~/.airgapsync/keys.vault`) that
+//! holds every stored key, encrypted under a key derived from a passphrase.
+//! The file records its format version, cipher and key derivation
+//! parameters in a plaintext header that is authenticated as AAD.
+//!
+//! ```text
+//! {
+//!   "format": "airgapsync-vault",
+//!   "version": 1,
+//!   "algorithm": "aes256-gcm",
+//!   "kdf": { "algorithm": "argon2", "memory_kib": 65536, "time_cost": 3, "parallelism": 4 },
+//!   "salt": "<base64>",
+//!   "payload": "<base64 nonce || ciphertext || tag>"
+//! }
+//! ```
+
+use crate::config::{EncryptionAlgorithm, EncryptionConfig};
+use crate::crypto::{self, CryptoKey, KdfParams};
+use crate::keystore::{
+    airgapsync_home, create_private_dir, validate_entry_id, write_private_file, EncryptionKey,
+    KeyData, KeyStore, KeyStoreError,
+};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use zeroize::Zeroize;
+
+/// Format identifier written into every vault header
+const VAULT_FORMAT: &str = "airgapsync-vault";
+
+/// Current vault format version
+pub const VAULT_VERSION: u32 = 1;
+
+/// Derive the vault key from a passphrase
+fn derive_key(
+    kdf: &KdfParams,
+    passphrase: &[u8],
+    salt: &[u8],
+    algorithm: EncryptionAlgorithm,
+) -> Result<CryptoKey, KeyStoreError> {
+    CryptoKey::derive(passphrase, salt, kdf, algorithm.into())
+        .map_err(|e| KeyStoreError::Backend(e.to_string()))
+}
+
+/// Plaintext vault header, authenticated as AAD
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultHeader {
+    format: String,
+    version: u32,
+    algorithm: EncryptionAlgorithm,
+    kdf: KdfParams,
+    salt: String, // Base64 encoded
+}
+
+impl VaultHeader {
+    /// Canonical bytes bound to the payload as additional data
+    fn aad(&self) -> Result<Vec<u8>, KeyStoreError> {
+        serde_json::to_vec(self).map_err(|e| KeyStoreError::EncodingError(e.to_string()))
+    }
+
+    fn salt(&self) -> Result<Vec<u8>, KeyStoreError> {
+        general_purpose::STANDARD
+            .decode(&self.salt)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))
+    }
+}
+
+/// On-disk vault file
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    #[serde(flatten)]
+    header: VaultHeader,
+    payload: String, // Base64 encoded
+}
+
+/// Decrypted vault contents held while unlocked
+struct UnlockedVault {
+    header: VaultHeader,
+    key: CryptoKey,
+    entries: BTreeMap<String, EncryptionKey>,
+}
+
+/// Key store backed by a passphrase-protected vault file
+///
+/// The vault starts locked. `unlock` derives the vault key once and keeps
+/// it, together with the decrypted entries, until `lock` is called, so a
+/// long-running process only needs the passphrase a single time.
+pub struct VaultKeyStore {
+    path: PathBuf,
+    /// Settings used when a new vault is created
+    config: EncryptionConfig,
+    state: Mutex<Option<UnlockedVault>>,
+}
+
+impl VaultKeyStore {
+    /// Open the vault at `path` in the locked state
+    ///
+    /// The file does not need to exist yet; unlocking a missing vault
+    /// creates an empty one protected by the given passphrase.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        config: &EncryptionConfig,
+    ) -> Result<Self, KeyStoreError> {
+        Ok(Self {
+            path: path.into(),
+            config: config.clone(),
+            state: Mutex::new(None),
+        })
+    }
+
+    /// Default vault location (`~/.airgapsync/keys.vault`)
+    pub fn default_path() -> Result<PathBuf, KeyStoreError> {
+        Ok(airgapsync_home()?.join("keys.vault"))
+    }
+
+    /// Path of the vault file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the vault file has been created
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    /// Re-encrypt the vault under a new passphrase
+    pub fn change_passphrase(&self, new_passphrase: &[u8]) -> Result<(), KeyStoreError> {
+        let mut state = self.state();
+        let vault = state.as_mut().ok_or(KeyStoreError::Locked)?;
+
+        // Only switch to the new header and key once they are on disk
+        let (header, key) = self.new_header(new_passphrase)?;
+        let updated = UnlockedVault {
+            header,
+            key,
+            entries: std::mem::take(&mut vault.entries),
+        };
+        let result = self.write(&updated);
+        vault.entries = updated.entries;
+        if result.is_ok() {
+            vault.header = updated.header;
+            vault.key = updated.key;
+        }
+        result
+    }
+
+    fn state(&self) -> MutexGuard<'_, Option<UnlockedVault>> {
+        // A panic while holding the lock cannot leave the state half-updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Build a header with a fresh salt and derive its key
+    fn new_header(&self, passphrase: &[u8]) -> Result<(VaultHeader, CryptoKey), KeyStoreError> {
+        let kdf = KdfParams::from_config(&self.config);
+        let salt = crypto::generate_salt().map_err(|e| KeyStoreError::Backend(e.to_string()))?;
+        let key = derive_key(&kdf, passphrase, &salt, self.config.algorithm)?;
+
+        let header = VaultHeader {
+            format: VAULT_FORMAT.to_string(),
+            version: VAULT_VERSION,
+            algorithm: self.config.algorithm,
+            kdf,
+            salt: general_purpose::STANDARD.encode(&salt),
+        };
+        Ok((header, key))
+    }
+
+    /// Read and decrypt the vault file
+    fn read(&self, passphrase: &[u8]) -> Result<UnlockedVault, KeyStoreError> {
+        let contents = fs::read(&self.path)?;
+        let file: VaultFile = serde_json::from_slice(&contents)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+
+        if file.header.format != VAULT_FORMAT {
+            return Err(KeyStoreError::InvalidKeyFormat);
+        }
+        if file.header.version != VAULT_VERSION {
+            return Err(KeyStoreError::Backend(format!(
+                "Unsupported vault version: {}",
+                file.header.version
+            )));
+        }
+
+        file.header
+            .kdf
+            .check_limits()
+            .map_err(KeyStoreError::Backend)?;
+        let salt = file.header.salt()?;
+        let key = derive_key(&file.header.kdf, passphrase, &salt, file.header.algorithm)?;
+
+        let payload = general_purpose::STANDARD
+            .decode(&file.payload)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+        let mut plaintext = crypto::decrypt(&key, &payload, &file.header.aad()?)
+            .map_err(|_| KeyStoreError::DecryptionFailed)?;
+
+        let decoded: Result<BTreeMap<String, KeyData>, _> = serde_json::from_slice(&plaintext);
+        plaintext.zeroize();
+        let decoded = decoded.map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+
+        let mut entries = BTreeMap::new();
+        for (device_id, key_data) in &decoded {
+            entries.insert(device_id.clone(), key_data.to_key()?);
+        }
+
+        Ok(UnlockedVault {
+            header: file.header,
+            key,
+            entries,
+        })
+    }
+
+    /// Encrypt and atomically rewrite the vault file
+    fn write(&self, vault: &UnlockedVault) -> Result<(), KeyStoreError> {
+        let encoded: BTreeMap<&String, KeyData> = vault
+            .entries
+            .iter()
+            .map(|(device_id, key)| (device_id, KeyData::from_key(key)))
+            .collect();
+        let mut plaintext = serde_json::to_vec(&encoded)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+        drop(encoded);
+
+        let sealed = crypto::encrypt(&vault.key, &plaintext, &vault.header.aad()?);
+        plaintext.zeroize();
+        let sealed = sealed.map_err(|e| KeyStoreError::Backend(e.to_string()))?;
+
+        let file = VaultFile {
+            header: vault.header.clone(),
+            payload: general_purpose::STANDARD.encode(&sealed),
+        };
+        let contents = serde_json::to_vec_pretty(&file)
+            .map_err(|e| KeyStoreError::EncodingError(e.to_string()))?;
+
+        if let Some(parent) = self.path.parent() {
+            create_private_dir(parent)?;
+        }
+        write_private_file(&self.path, &contents)
+    }
+
+    /// Run `f` against the unlocked vault contents
+    fn with_unlocked<T>(
+        &self,
+        f: impl FnOnce(&mut UnlockedVault) -> Result<T, KeyStoreError>,
+    ) -> Result<T, KeyStoreError> {
+        let mut state = self.state();
+        let vault = state.as_mut().ok_or(KeyStoreError::Locked)?;
+        f(vault)
+    }
+}
+
+impl KeyStore for VaultKeyStore {
+    fn store_key(&self, device_id: &str, key: &EncryptionKey) -> Result<(), KeyStoreError> {
+        validate_entry_id(device_id)?;
+        self.with_unlocked(|vault| {
+            let previous = vault.entries.insert(device_id.to_string(), key.clone());
+            let result = self.write(vault);
+            if result.is_err() {
+                // Keep memory consistent with what is on disk
+                match previous {
+                    Some(previous) => vault.entries.insert(device_id.to_string(), previous),
+                    None => vault.entries.remove(device_id),
+                };
+            }
+            result
+        })
+    }
+
+    fn get_key(&self, device_id: &str) -> Result<EncryptionKey, KeyStoreError> {
+        self.with_unlocked(|vault| {
+            vault
+                .entries
+                .get(device_id)
+                .cloned()
+                .ok_or(KeyStoreError::KeyNotFound)
+        })
+    }
+
+    fn key_exists(&self, device_id: &str) -> bool {
+        self.with_unlocked(|vault| Ok(vault.entries.contains_key(device_id)))
+            .unwrap_or(false)
+    }
+
+    fn delete_key(&self, device_id: &str) -> Result<(), KeyStoreError> {
+        self.with_unlocked(|vault| {
+            let removed = vault
+                .entries
+                .remove(device_id)
+                .ok_or(KeyStoreError::KeyNotFound)?;
+            let result = self.write(vault);
+            if result.is_err() {
+                vault.entries.insert(device_id.to_string(), removed);
+            }
+            result
+        })
+    }
+
+    fn list_entries(&self) -> Result<Vec<String>, KeyStoreError> {
+        self.with_unlocked(|vault| Ok(vault.entries.keys().cloned().collect()))
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state().is_none()
+    }
+
+    fn unlock(&self, passphrase: &[u8]) -> Result<(), KeyStoreError> {
+        let mut state = self.state();
+        if state.is_some() {
+            return Ok(());
+        }
+
+        let vault = if self.exists() {
+            self.read(passphrase)?
+        } else {
+            let (header, key) = self.new_header(passphrase)?;
+            let vault = UnlockedVault {
+                header,
+                key,
+                entries: BTreeMap::new(),
+            };
+            self.write(&vault)?;
+            vault
+        };
+
+        *state = Some(vault);
+        Ok(())
+    }
+
+    fn lock(&self) {
+        // Dropping the state zeroizes the vault key and every cached entry
+        *self.state() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::{generate_key, rotate_key};
+
+    fn test_config() -> EncryptionConfig {
+        EncryptionConfig {
+            iterations: 1_000,
+            ..EncryptionConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_vault_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+        let key = generate_key("AES-256", "USB001").unwrap();
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        assert!(vault.is_locked());
+        assert!(matches!(
+            vault.store_key("USB001", &key),
+            Err(KeyStoreError::Locked)
+        ));
+
+        vault.unlock(b"correct horse").unwrap();
+        assert!(vault.exists());
+        vault.store_key("USB001", &key).unwrap();
+        rotate_key(&vault, "USB001").unwrap();
+        vault.lock();
+        assert!(vault.is_locked());
+        assert!(!vault.key_exists("USB001"));
+
+        // A fresh process only needs the passphrase
+        let reopened = VaultKeyStore::open(&path, &test_config()).unwrap();
+        reopened.unlock(b"correct horse").unwrap();
+        assert_eq!(reopened.list_devices().unwrap(), vec!["USB001".to_string()]);
+        assert_eq!(reopened.get_key("USB001").unwrap().metadata.version, 2);
+    }
+
+    #[test]
+    fn test_vault_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        vault.unlock(b"right").unwrap();
+
+        let other = VaultKeyStore::open(&path, &test_config()).unwrap();
+        assert!(matches!(
+            other.unlock(b"wrong"),
+            Err(KeyStoreError::DecryptionFailed)
+        ));
+        assert!(other.is_locked());
+    }
+
+    #[test]
+    fn test_vault_header_records_kdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        vault.unlock(b"pass").unwrap();
+
+        let file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file["format"], VAULT_FORMAT);
+        assert_eq!(file["version"], VAULT_VERSION);
+        assert_eq!(file["kdf"]["algorithm"], "pbkdf2");
+        assert_eq!(file["kdf"]["iterations"], 1_000);
+    }
+
+    #[test]
+    fn test_vault_argon2() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+        let config = EncryptionConfig {
+            key_derivation: crate::config::KeyDerivation::Argon2,
+            argon2_memory_kib: 1024,
+            argon2_time_cost: 2,
+            argon2_parallelism: 1,
+            ..EncryptionConfig::default()
+        };
+
+        let vault = VaultKeyStore::open(&path, &config).unwrap();
+        vault.unlock(b"pass").unwrap();
+        let key = generate_key("AES-256", "USB001").unwrap();
+        vault.store_key("USB001", &key).unwrap();
+
+        let file: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file["kdf"]["algorithm"], "argon2");
+        assert_eq!(file["kdf"]["memory_kib"], 1024);
+
+        // Parameters come from the header, not the opening configuration
+        let reopened = VaultKeyStore::open(&path, &test_config()).unwrap();
+        reopened.unlock(b"pass").unwrap();
+        assert_eq!(
+            reopened.get_key("USB001").unwrap().key_material,
+            key.key_material
+        );
+    }
+
+    #[test]
+    fn test_vault_header_tampering_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        vault.unlock(b"pass").unwrap();
+        vault
+            .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+            .unwrap();
+
+        // Any header change invalidates the payload
+        let mut file: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let original = file.clone();
+        file["algorithm"] = serde_json::json!("chacha20-poly1305");
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+
+        let reopened = VaultKeyStore::open(&path, &test_config()).unwrap();
+        assert!(reopened.unlock(b"pass").is_err());
+
+        // Costs beyond the configured maxima are refused before deriving
+        let mut file = original;
+        file["kdf"] = serde_json::json!({
+            "algorithm": "argon2",
+            "memory_kib": u32::MAX,
+            "time_cost": 3,
+            "parallelism": 4,
+        });
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        assert!(matches!(
+            reopened.unlock(b"pass"),
+            Err(KeyStoreError::Backend(message)) if message.contains("memory cost")
+        ));
+    }
+
+    #[test]
+    fn test_vault_change_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        vault.unlock(b"old").unwrap();
+        vault
+            .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+            .unwrap();
+        vault.change_passphrase(b"new").unwrap();
+
+        let reopened = VaultKeyStore::open(&path, &test_config()).unwrap();
+        assert!(reopened.unlock(b"old").is_err());
+        reopened.unlock(b"new").unwrap();
+        assert!(reopened.key_exists("USB001"));
+    }
+
+    #[test]
+    fn test_vault_change_passphrase_failure_keeps_old_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.vault");
+
+        let vault = VaultKeyStore::open(&path, &test_config()).unwrap();
+        vault.unlock(b"old").unwrap();
+        vault
+            .store_key("USB001", &generate_key("AES-256", "USB001").unwrap())
+            .unwrap();
+
+        // A non-empty directory in the vault's place makes the write fail
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("blocker"), b"").unwrap();
+        assert!(vault.change_passphrase(b"new").is_err());
+
+        // Later writes still use the old passphrase and keep every entry
+        fs::remove_dir_all(&path).unwrap();
+        vault
+            .store_key("USB002", &generate_key("AES-256", "USB002").unwrap())
+            .unwrap();
+        let reopened = VaultKeyStore::open(&path, &test_config()).unwrap();
+        assert!(reopened.unlock(b"new").is_err());
+        reopened.unlock(b"old").unwrap();
+        assert!(reopened.key_exists("USB001"));
+        assert!(reopened.key_exists("USB002"));
+    }
+}
@@ -0,0 +1,615 @@
+//! Source tree walker
+//!
+//! Walks the configured source directory depth first, visiting the entries
+//! of each directory in file name order, so the same tree always yields the
+//! same sequence. A path is left out when:
+//!
+//! - it matches a pattern in `source.exclude`, which is read like a
+//!   gitignore file at the source root
+//! - it matches a pattern in an [`IGNORE_FILE`] in its directory or any
+//!   ancestor; deeper files take precedence, and every ignore file takes
+//!   precedence over `source.exclude`
+//! - its name starts with `.` and `include_hidden` is off
+//! - it is a symbolic link and `follow_symlinks` is off, or a followed link
+//!   leads back to one of its own ancestors
+//!
+//! Patterns use gitignore syntax: `!` re-includes a path excluded by an
+//! earlier pattern, a leading or inner `/` anchors a pattern to the
+//! directory of the file it comes from, a trailing `/` matches directories
+//! only, and `**` matches any number of directories. As in git, excluded
+//! directories are not descended into, so nothing beneath one can be
+//! re-included.
+
+use crate::config::SourceConfig;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use walkdir::WalkDir;
+
+/// Name of the per-directory ignore file
+pub const IGNORE_FILE: &str = ".airgapignore";
+
+/// Source walker errors
+#[derive(Debug, Error)]
+pub enum WalkError {
+    /// A `source.exclude` pattern could not be parsed
+    #[error("Invalid exclude pattern {pattern:?}: {reason}")]
+    InvalidPattern {
+        /// The pattern as written
+        pattern: String,
+        /// Parser error
+        reason: String,
+    },
+
+    /// An ignore file could not be read, or has invalid patterns
+    #[error("{}: {reason}", path.display())]
+    IgnoreFile {
+        /// Source-relative path of the ignore file
+        path: PathBuf,
+        /// Read or parse error
+        reason: String,
+    },
+
+    /// A path under the source directory could not be read
+    #[error("{}: {source}", path.display())]
+    Io {
+        /// Source-relative path
+        path: PathBuf,
+        /// Underlying error
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl WalkError {
+    /// Source-relative path the error refers to, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            WalkError::InvalidPattern { .. } => None,
+            WalkError::IgnoreFile { path, .. } | WalkError::Io { path, .. } => Some(path),
+        }
+    }
+}
+
+/// Why a path was left out of the walk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExcludeReason {
+    /// Matched an exclude pattern
+    Pattern {
+        /// The pattern as written
+        pattern: String,
+        /// Source-relative ignore file it came from, `None` for `source.exclude`
+        ignore_file: Option<PathBuf>,
+    },
+    /// Hidden, and `include_hidden` is off
+    Hidden,
+    /// Symbolic link, and `follow_symlinks` is off
+    Symlink,
+    /// Symbolic link leading back to an ancestor directory
+    SymlinkLoop {
+        /// Source-relative ancestor the link resolves to
+        ancestor: PathBuf,
+    },
+    /// Symbolic link whose target does not exist
+    BrokenSymlink,
+    /// Neither a regular file nor a directory
+    Special,
+}
+
+impl fmt::Display for ExcludeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExcludeReason::Pattern {
+                pattern,
+                ignore_file: Some(file),
+            } => write!(f, "matches {pattern:?} in {}", file.display()),
+            ExcludeReason::Pattern {
+                pattern,
+                ignore_file: None,
+            } => write!(f, "matches {pattern:?} in source.exclude"),
+            ExcludeReason::Hidden => f.write_str("hidden (include_hidden is off)"),
+            ExcludeReason::Symlink => f.write_str("symbolic link (follow_symlinks is off)"),
+            ExcludeReason::SymlinkLoop { ancestor } if ancestor.as_os_str().is_empty() => {
+                f.write_str("symbolic link loop back to the source root")
+            }
+            ExcludeReason::SymlinkLoop { ancestor } => {
+                write!(f, "symbolic link loop back to {}", ancestor.display())
+            }
+            ExcludeReason::BrokenSymlink => f.write_str("broken symbolic link"),
+            ExcludeReason::Special => f.write_str("not a regular file or directory"),
+        }
+    }
+}
+
+/// A file or directory included in the walk
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// Path on disk, under the source directory
+    pub path: PathBuf,
+    /// Path relative to the source root
+    pub relative: PathBuf,
+    /// Whether the entry is a directory (after following links, if enabled)
+    pub is_dir: bool,
+}
+
+/// One step of a walk
+#[derive(Debug, Clone)]
+pub enum WalkEvent {
+    /// A path to sync
+    Entry(WalkEntry),
+    /// A path left out, and why; nothing beneath an excluded directory is
+    /// visited
+    Excluded {
+        /// Path relative to the source root
+        relative: PathBuf,
+        /// Why it was left out
+        reason: ExcludeReason,
+    },
+}
+
+/// Walker over a configured source directory
+#[derive(Debug)]
+pub struct SourceWalker {
+    root: PathBuf,
+    exclude: Gitignore,
+    follow_symlinks: bool,
+    include_hidden: bool,
+}
+
+impl SourceWalker {
+    /// Create a walker for the source configuration
+    ///
+    /// Fails if a `source.exclude` pattern is invalid.
+    pub fn new(source: &SourceConfig) -> Result<Self, WalkError> {
+        let mut builder = GitignoreBuilder::new(&source.path);
+        for pattern in &source.exclude {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| WalkError::InvalidPattern {
+                    pattern: pattern.clone(),
+                    reason: e.to_string(),
+                })?;
+        }
+        let exclude = builder.build().map_err(|e| WalkError::InvalidPattern {
+            pattern: source.exclude.join(", "),
+            reason: e.to_string(),
+        })?;
+
+        Ok(Self {
+            root: source.path.clone(),
+            exclude,
+            follow_symlinks: source.follow_symlinks,
+            include_hidden: source.include_hidden,
+        })
+    }
+
+    /// Start a walk of the source directory
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            walker: self,
+            entries: WalkDir::new(&self.root)
+                .follow_links(self.follow_symlinks)
+                .sort_by_file_name()
+                .into_iter(),
+            ignore_files: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+/// Patterns from an ignore file found during a walk
+struct IgnoreLayer {
+    /// Walk depth of the directory holding the file
+    depth: usize,
+    /// Source-relative path of the file
+    relative: PathBuf,
+    matcher: Gitignore,
+}
+
+/// Iterator over a source directory, created by [`SourceWalker::walk`]
+pub struct Walk<'a> {
+    walker: &'a SourceWalker,
+    entries: walkdir::IntoIter,
+    /// Ignore files in effect for the current directory, outermost first
+    ignore_files: Vec<IgnoreLayer>,
+    /// Events waiting to be returned
+    pending: VecDeque<Result<WalkEvent, WalkError>>,
+}
+
+impl Walk<'_> {
+    /// Classify the next entry from the underlying directory walk
+    fn visit(&mut self, entry: walkdir::DirEntry) -> Option<Result<WalkEvent, WalkError>> {
+        let depth = entry.depth();
+        while self.ignore_files.last().is_some_and(|l| l.depth >= depth) {
+            self.ignore_files.pop();
+        }
+
+        let file_type = entry.file_type();
+        if depth == 0 {
+            // The source root itself is not reported, only its contents
+            if file_type.is_dir() {
+                self.load_ignore_file(entry.path(), depth);
+            }
+            return None;
+        }
+
+        let relative = self.walker.relative(entry.path());
+        let reason = if !self.walker.include_hidden && is_hidden(&entry) {
+            Some(ExcludeReason::Hidden)
+        } else if let Some(reason) = self.matched(entry.path(), file_type.is_dir()) {
+            Some(reason)
+        } else if file_type.is_symlink() {
+            Some(ExcludeReason::Symlink)
+        } else if !file_type.is_file() && !file_type.is_dir() {
+            Some(ExcludeReason::Special)
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            if file_type.is_dir() {
+                self.entries.skip_current_dir();
+            }
+            return Some(Ok(WalkEvent::Excluded { relative, reason }));
+        }
+
+        if file_type.is_dir() {
+            self.load_ignore_file(entry.path(), depth);
+        }
+        Some(Ok(WalkEvent::Entry(WalkEntry {
+            path: entry.into_path(),
+            relative,
+            is_dir: file_type.is_dir(),
+        })))
+    }
+
+    /// The exclude pattern that decides `path`, if it is excluded
+    ///
+    /// Ignore files are consulted from the deepest up, then
+    /// `source.exclude`; the first one with a matching pattern decides,
+    /// whether that pattern excludes or re-includes.
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<ExcludeReason> {
+        let layers = self
+            .ignore_files
+            .iter()
+            .rev()
+            .map(|l| (&l.matcher, Some(&l.relative)))
+            .chain(std::iter::once((&self.walker.exclude, None)));
+
+        for (matcher, ignore_file) in layers {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(glob) => {
+                    return Some(ExcludeReason::Pattern {
+                        pattern: glob.original().to_string(),
+                        ignore_file: ignore_file.cloned(),
+                    })
+                }
+                Match::Whitelist(_) => return None,
+                Match::None => {}
+            }
+        }
+        None
+    }
+
+    /// Load the ignore file in `dir`, if it has one
+    fn load_ignore_file(&mut self, dir: &Path, depth: usize) {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return;
+        }
+
+        let relative = self.walker.relative(&path);
+        let (matcher, error) = Gitignore::new(&path);
+        if let Some(error) = error {
+            // Valid lines still apply, as they would in git
+            self.pending.push_back(Err(WalkError::IgnoreFile {
+                path: relative.clone(),
+                reason: error.to_string(),
+            }));
+        }
+        self.ignore_files.push(IgnoreLayer {
+            depth,
+            relative,
+            matcher,
+        });
+    }
+
+    /// Turn a directory walk error into an event
+    fn failed(&self, error: walkdir::Error) -> Result<WalkEvent, WalkError> {
+        let path = error
+            .path()
+            .map(|p| self.walker.relative(p))
+            .unwrap_or_default();
+
+        if let Some(ancestor) = error.loop_ancestor() {
+            return Ok(WalkEvent::Excluded {
+                relative: path,
+                reason: ExcludeReason::SymlinkLoop {
+                    ancestor: self.walker.relative(ancestor),
+                },
+            });
+        }
+
+        let is_broken_link = error.io_error().map(|e| e.kind())
+            == Some(std::io::ErrorKind::NotFound)
+            && error
+                .path()
+                .and_then(|p| p.symlink_metadata().ok())
+                .is_some_and(|m| m.file_type().is_symlink());
+        if is_broken_link {
+            return Ok(WalkEvent::Excluded {
+                relative: path,
+                reason: ExcludeReason::BrokenSymlink,
+            });
+        }
+
+        Err(WalkError::Io {
+            path,
+            source: error.into(),
+        })
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<WalkEvent, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            let event = match self.entries.next()? {
+                Ok(entry) => self.visit(entry),
+                Err(error) => Some(self.failed(error)),
+            };
+            // Ahead of any error from the ignore file the entry just loaded
+            if let Some(event) = event {
+                self.pending.push_front(event);
+            }
+        }
+    }
+}
+
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(root: &Path, exclude: &[&str]) -> SourceConfig {
+        SourceConfig {
+            path: root.to_path_buf(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            follow_symlinks: false,
+            include_hidden: false,
+        }
+    }
+
+    fn touch(root: &Path, relative: &str) {
+        let path = root.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, relative).unwrap();
+    }
+
+    /// Included files and excluded paths, as `/`-joined relative paths
+    fn walk(config: &SourceConfig) -> (Vec<String>, Vec<(String, ExcludeReason)>) {
+        let walker = SourceWalker::new(config).unwrap();
+        let mut files = Vec::new();
+        let mut excluded = Vec::new();
+        for event in walker.walk() {
+            match event.unwrap() {
+                WalkEvent::Entry(entry) if !entry.is_dir => {
+                    files.push(entry.relative.to_string_lossy().replace('\\', "/"))
+                }
+                WalkEvent::Entry(_) => {}
+                WalkEvent::Excluded { relative, reason } => {
+                    excluded.push((relative.to_string_lossy().replace('\\', "/"), reason))
+                }
+            }
+        }
+        (files, excluded)
+    }
+
+    #[test]
+    fn test_gitignore_semantics() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "build/out.o",
+            "src/build/gen.rs",
+            "src/main.rs",
+            "logs/a.log",
+            "logs/keep.log",
+            "deep/a/b/cache/x",
+            "notes.tmp",
+            "target",
+        ] {
+            touch(dir.path(), file);
+        }
+        let config = source(
+            dir.path(),
+            &[
+                // Anchored: only the top-level build directory
+                "/build",
+                "*.log",
+                "!keep.log",
+                "**/cache/",
+                // Directory-only: the file named target stays
+                "target/",
+                "*.tmp",
+            ],
+        );
+
+        let (files, excluded) = walk(&config);
+        assert_eq!(
+            files,
+            ["logs/keep.log", "src/build/gen.rs", "src/main.rs", "target"]
+        );
+
+        let paths: Vec<&str> = excluded.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["build", "deep/a/b/cache", "logs/a.log", "notes.tmp"]
+        );
+        assert_eq!(
+            excluded[0].1,
+            ExcludeReason::Pattern {
+                pattern: "/build".to_string(),
+                ignore_file: None,
+            }
+        );
+        assert_eq!(
+            excluded[2].1.to_string(),
+            "matches \"*.log\" in source.exclude"
+        );
+    }
+
+    #[test]
+    fn test_nested_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "a.txt",
+            "b.txt",
+            "docs/a.txt",
+            "docs/b.txt",
+            "docs/sub/c.txt",
+        ] {
+            touch(dir.path(), file);
+        }
+        std::fs::write(dir.path().join(IGNORE_FILE), "b.txt\n").unwrap();
+        // Deeper files override shallower ones and `source.exclude`
+        std::fs::write(dir.path().join("docs").join(IGNORE_FILE), "!b.txt\n/sub/\n").unwrap();
+
+        let (files, excluded) = walk(&source(dir.path(), &["a.txt", "sub"]));
+        assert_eq!(files, ["docs/b.txt"]);
+        assert_eq!(
+            excluded,
+            [
+                (".airgapignore".to_string(), ExcludeReason::Hidden),
+                (
+                    "a.txt".to_string(),
+                    ExcludeReason::Pattern {
+                        pattern: "a.txt".to_string(),
+                        ignore_file: None,
+                    }
+                ),
+                (
+                    "b.txt".to_string(),
+                    ExcludeReason::Pattern {
+                        pattern: "b.txt".to_string(),
+                        ignore_file: Some(PathBuf::from(IGNORE_FILE)),
+                    }
+                ),
+                ("docs/.airgapignore".to_string(), ExcludeReason::Hidden),
+                (
+                    "docs/a.txt".to_string(),
+                    ExcludeReason::Pattern {
+                        pattern: "a.txt".to_string(),
+                        ignore_file: None,
+                    }
+                ),
+                (
+                    "docs/sub".to_string(),
+                    ExcludeReason::Pattern {
+                        pattern: "/sub/".to_string(),
+                        ignore_file: Some(Path::new("docs").join(IGNORE_FILE)),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hidden_files() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), ".env");
+        touch(dir.path(), ".git/config");
+        touch(dir.path(), "a.txt");
+
+        let mut config = source(dir.path(), &[]);
+        let (files, excluded) = walk(&config);
+        assert_eq!(files, ["a.txt"]);
+        assert_eq!(
+            excluded,
+            [
+                (".env".to_string(), ExcludeReason::Hidden),
+                (".git".to_string(), ExcludeReason::Hidden),
+            ]
+        );
+
+        config.include_hidden = true;
+        config.exclude = vec![".git/".to_string()];
+        assert_eq!(walk(&config).0, [".env", "a.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "real/a.txt");
+        symlink("real", dir.path().join("link")).unwrap();
+        symlink("..", dir.path().join("real/up")).unwrap();
+        symlink("missing", dir.path().join("dangling")).unwrap();
+
+        let mut config = source(dir.path(), &[]);
+        let (files, excluded) = walk(&config);
+        assert_eq!(files, ["real/a.txt"]);
+        assert_eq!(
+            excluded,
+            [
+                ("dangling".to_string(), ExcludeReason::Symlink),
+                ("link".to_string(), ExcludeReason::Symlink),
+                ("real/up".to_string(), ExcludeReason::Symlink),
+            ]
+        );
+
+        // Followed links are walked, and loops are reported instead of
+        // descended into
+        config.follow_symlinks = true;
+        let (files, excluded) = walk(&config);
+        assert_eq!(files, ["link/a.txt", "real/a.txt"]);
+        assert_eq!(
+            excluded,
+            [
+                ("dangling".to_string(), ExcludeReason::BrokenSymlink),
+                (
+                    "link/up".to_string(),
+                    ExcludeReason::SymlinkLoop {
+                        ancestor: PathBuf::new()
+                    }
+                ),
+                (
+                    "real/up".to_string(),
+                    ExcludeReason::SymlinkLoop {
+                        ancestor: PathBuf::new()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            excluded[1].1.to_string(),
+            "symbolic link loop back to the source root"
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = SourceWalker::new(&source(dir.path(), &["a/**b/[z-a]"])).unwrap_err();
+        assert!(
+            matches!(err, WalkError::InvalidPattern { pattern, .. } if pattern == "a/**b/[z-a]")
+        );
+    }
+}
@@ -43,7 +43,9 @@ fn test_configuration_system() {
                 algorithm: EncryptionAlgorithm::Aes256Gcm,
                 key_derivation: KeyDerivation::Pbkdf2,
                 iterations: 100_000,
+                ..EncryptionConfig::default()
             },
+            recipients: vec![],
         }],
         policy: PolicyConfig::default(),
         security: SecurityConfig::default(),
@@ -218,6 +220,7 @@ fn test_config_validation() {
         name: "Device 1".to_string(),
         mount_point: PathBuf::from("/mnt/usb1"),
         encryption: EncryptionConfig::default(),
+        recipients: vec![],
     });
 
     config.device.push(DeviceConfig {
@@ -225,7 +228,84 @@ fn test_config_validation() {
         name: "Device 2".to_string(),
         mount_point: PathBuf::from("/mnt/usb2"),
         encryption: EncryptionConfig::default(),
+        recipients: vec![],
     });
 
     assert!(config.validate().is_err());
 }
+
+/// Run the CLI with `config` and an isolated home directory
+fn airgapsync(home: &std::path::Path, config: &std::path::Path, args: &[&str]) -> String {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_airgapsync"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("HOME", home)
+        .env("AIRGAPSYNC_PASSPHRASE", "integration test passphrase")
+        .output()
+        .expect("Failed to run airgapsync");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(
+        output.status.success(),
+        "airgapsync {args:?} failed:\n{stdout}\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+#[test]
+fn test_cli_sync_and_restore() {
+    let home = tempfile::tempdir().unwrap();
+    let source = tempfile::tempdir().unwrap();
+    let device = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(source.path().join("docs")).unwrap();
+    std::fs::write(source.path().join("a.txt"), b"alpha").unwrap();
+    std::fs::write(source.path().join("docs/b.txt"), b"bravo").unwrap();
+
+    for dedup in [false, true] {
+        let config = home.path().join(format!("config-{dedup}.toml"));
+        std::fs::write(
+            &config,
+            format!(
+                "[general]\n[source]\npath = {:?}\n[[device]]\nid = \"USB001\"\nname = \"USB\"\nmount_point = {:?}\n[policy]\n[security]\nkey_store = \"vault\"\nkey_store_path = {:?}\n[notifications]\n[advanced]\nexperimental_dedup = {dedup}\n",
+                source.path(),
+                device.path(),
+                home.path().join("keys.vault"),
+            ),
+        )
+        .unwrap();
+        if !dedup {
+            airgapsync(home.path(), &config, &["keygen", "USB001"]);
+        }
+        airgapsync(home.path(), &config, &["sync", "USB001"]);
+
+        let restored = home.path().join(format!("restored-{dedup}"));
+        let output = airgapsync(
+            home.path(),
+            &config,
+            &["restore", "USB001", restored.to_str().unwrap()],
+        );
+        assert!(output.contains("Files restored: 2"), "{output}");
+        assert_eq!(std::fs::read(restored.join("a.txt")).unwrap(), b"alpha");
+        assert_eq!(
+            std::fs::read(restored.join("docs/b.txt")).unwrap(),
+            b"bravo"
+        );
+
+        // A synced copy decrypts on its own, bound to its path on the device
+        if !dedup {
+            let encrypted = device.path().join("AirGapSync/data/docs/b.txt.enc");
+            let decrypted = home.path().join("b.txt");
+            airgapsync(
+                home.path(),
+                &config,
+                &[
+                    "decrypt",
+                    encrypted.to_str().unwrap(),
+                    decrypted.to_str().unwrap(),
+                ],
+            );
+            assert_eq!(std::fs::read(decrypted).unwrap(), b"bravo");
+        }
+    }
+}